@@ -0,0 +1,288 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::error::Error;
+use crate::extent::Extent;
+use crate::fs::FileSystem;
+use crate::inode::Inode;
+use crate::io::{Read, ReadWriteSeek, Seek, SeekFrom};
+use crate::super_block::SuperBlock;
+
+// inode.block里直接块的个数，以及一级、二级、三级间接块的下标
+pub const DIRECT_BLOCKS: usize = 12;
+pub const IND_BLOCK: usize = 12;
+pub const DIND_BLOCK: usize = 13;
+pub const TIND_BLOCK: usize = 14;
+
+// ext2/ext3的块映射：inode.block前12个是直接块，后面三个分别指向一级、二级、三级间接块
+// 间接块里是一个u32数组，0表示空洞
+pub struct BlockMap<'a, 'b, IO: ReadWriteSeek> {
+  pub fs: &'a FileSystem<IO>,
+  pub ino: u64,
+  pub inode: &'b mut Inode,
+}
+
+impl<'a, 'b, IO: ReadWriteSeek> BlockMap<'a, 'b, IO> {
+  pub fn new(fs: &'a FileSystem<IO>, ino: u64, inode: &'b mut Inode) -> Self {
+    Self { fs, ino, inode }
+  }
+
+  // 块映射能表示的逻辑块数
+  pub fn max_blocks(block_size: u64) -> u64 {
+    let p = block_size / 4;
+    (DIRECT_BLOCKS as u64 + p + p * p + p * p * p).min(u32::MAX as u64 + 1)
+  }
+
+  // 逻辑块在块映射里的位置：inode.block里的下标和每一级间接块里的下标
+  fn locate(&self, lblock: u64) -> Result<(usize, Vec<usize>), Error<IO::Error>> {
+    let p = self.fs.super_block.borrow().get_block_size() / 4;
+    let mut l = lblock;
+    if l < DIRECT_BLOCKS as u64 {
+      return Ok((l as usize, Vec::new()));
+    }
+    l -= DIRECT_BLOCKS as u64;
+    for level in 1..=3u32 {
+      let span = p.pow(level);
+      if l < span {
+        let offsets = (0..level).rev().map(|k| (l / p.pow(k) % p) as usize).collect();
+        return Ok((IND_BLOCK + level as usize - 1, offsets));
+      }
+      l -= span;
+    }
+    error!("BlockMap::locate: lblock {} out of range", lblock);
+    Err(Error::InvalidInput)
+  }
+
+  // 把extent里的逻辑块映射到对应的物理块，缺少的间接块会被分配
+  // 调用者负责数据块的块数，间接块的块数在这里更新，调用者负责把inode写回disk
+  pub fn insert(&mut self, extent: Extent) -> Result<(), Error<IO::Error>> {
+    trace!("BlockMap::insert ino: {}, extent: {:?}", self.ino, extent);
+    if extent.get_block_loc() + extent.get_len() as u64 > u32::MAX as u64 + 1 {
+      error!("BlockMap::insert: pblock out of range");
+      return Err(Error::InvalidInput);
+    }
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let mut lblock = extent.block as u64;
+    let end = extent.get_end_block();
+    while lblock < end {
+      let pblock = extent.map_block(lblock).unwrap() as u32;
+      let (slot, offsets) = self.locate(lblock)?;
+      if offsets.is_empty() {
+        self.inode.block[slot] = pblock;
+        lblock += 1;
+        continue;
+      }
+      // 找到存放数据块指针的那个间接块，一次写入其中尽量多的指针
+      let mut ptr = self.inode.block[slot];
+      if ptr == 0 {
+        ptr = self.alloc_indirect_block(extent.get_block_loc())?;
+        self.inode.block[slot] = ptr;
+      }
+      for &offset in &offsets[..offsets.len() - 1] {
+        let mut ptrs = self.load_ptrs(ptr)?;
+        if ptrs[offset] == 0 {
+          ptrs[offset] = self.alloc_indirect_block(extent.get_block_loc())?;
+          self.store_ptrs(ptr, &ptrs)?;
+        }
+        ptr = ptrs[offset];
+      }
+      let mut ptrs = self.load_ptrs(ptr)?;
+      let first = *offsets.last().unwrap();
+      let count = ((block_size / 4) as usize - first).min((end - lblock) as usize);
+      for (i, slot) in ptrs[first..first + count].iter_mut().enumerate() {
+        *slot = pblock + i as u32;
+      }
+      self.store_ptrs(ptr, &ptrs)?;
+      lblock += count as u64;
+    }
+    Ok(())
+  }
+
+  // 删除[lblock, lblock + len)范围内的映射并释放对应的数据块，变空的间接块也会被释放
+  // 返回释放的数据块数，调用者负责把inode写回disk
+  pub fn remove(&mut self, lblock: u64, len: u64) -> Result<u64, Error<IO::Error>> {
+    trace!("BlockMap::remove ino: {}, lblock: {}, len: {}", self.ino, lblock, len);
+    let (start, end) = (lblock, lblock.saturating_add(len));
+    let p = self.fs.super_block.borrow().get_block_size() / 4;
+    let mut removed = Vec::new();
+    for i in 0..DIRECT_BLOCKS {
+      if (start..end).contains(&(i as u64)) && self.inode.block[i] != 0 {
+        removed.push(self.inode.block[i]);
+        self.inode.block[i] = 0;
+      }
+    }
+    let mut base = DIRECT_BLOCKS as u64;
+    for level in 1..=3u32 {
+      let span = p.pow(level);
+      let slot = IND_BLOCK + level as usize - 1;
+      let pblock = self.inode.block[slot];
+      if pblock != 0
+        && start < base + span
+        && end > base
+        && self.remove_indirect(pblock, level, base, start, end, &mut removed)?
+      {
+        self.release_blocks(pblock as u64, 1)?;
+        self.inode.block[slot] = 0;
+      }
+      base += span;
+    }
+
+    // 连续的数据块一起释放
+    removed.sort_unstable();
+    let mut i = 0;
+    while i < removed.len() {
+      let mut count = 1;
+      while i + count < removed.len() && removed[i + count] == removed[i] + count as u32 {
+        count += 1;
+      }
+      self.release_blocks(removed[i] as u64, count as u64)?;
+      i += count;
+    }
+    Ok(removed.len() as u64)
+  }
+
+  // 删除一个level级间接块下[start, end)的映射，返回这个间接块是否已经全空
+  fn remove_indirect(
+    &mut self,
+    pblock: u32,
+    level: u32,
+    base: u64,
+    start: u64,
+    end: u64,
+    removed: &mut Vec<u32>,
+  ) -> Result<bool, Error<IO::Error>> {
+    let mut ptrs = self.load_ptrs(pblock)?;
+    let span = (self.fs.super_block.borrow().get_block_size() / 4).pow(level - 1);
+    let mut modified = false;
+    for (i, ptr) in ptrs.iter_mut().enumerate() {
+      let child_start = base + i as u64 * span;
+      if *ptr == 0 || child_start >= end || child_start + span <= start {
+        continue;
+      }
+      if level == 1 {
+        removed.push(*ptr);
+      } else if self.remove_indirect(*ptr, level - 1, child_start, start, end, removed)? {
+        // 下一级间接块已经全空
+        self.release_blocks(*ptr as u64, 1)?;
+      } else {
+        continue;
+      }
+      *ptr = 0;
+      modified = true;
+    }
+    if ptrs.iter().all(|&p| p == 0) {
+      return Ok(true);
+    }
+    if modified {
+      self.store_ptrs(pblock, &ptrs)?;
+    }
+    Ok(false)
+  }
+
+  fn load_ptrs(&self, pblock: u32) -> Result<Vec<u32>, Error<IO::Error>> {
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let mut disk = self.fs.disk.borrow_mut();
+    read_ptrs(&mut *disk, block_size, pblock)
+  }
+
+  fn store_ptrs(&self, pblock: u32, ptrs: &[u32]) -> Result<(), Error<IO::Error>> {
+    let data: Vec<u8> = ptrs.iter().flat_map(|p| p.to_le_bytes()).collect();
+    self.fs.write_block(pblock as u64, &data)
+  }
+
+  // 在goal附近分配一个清零的间接块并更新inode的块数
+  fn alloc_indirect_block(&mut self, goal: u64) -> Result<u32, Error<IO::Error>> {
+    let pblock = self.fs.alloc_block(goal)?;
+    if pblock > u32::MAX as u64 {
+      self.fs.free_blocks(pblock, 1)?;
+      error!("BlockMap::alloc_indirect_block: pblock {} out of range", pblock);
+      return Err(Error::NotEnoughSpace);
+    }
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    self.fs.write_block(pblock, &vec![0u8; block_size as usize])?;
+    let blocks_count = self.inode.get_blocks_count() + block_size / Inode::INODE_BLOCK_SIZE as u64;
+    self.inode.set_blocks_count(blocks_count);
+    Ok(pblock as u32)
+  }
+
+  // 释放属于这个inode的块并更新inode的块数
+  fn release_blocks(&mut self, start: u64, count: u64) -> Result<(), Error<IO::Error>> {
+    self.fs.free_blocks(start, count)?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let blocks_count = self.inode.get_blocks_count() - count * block_size / Inode::INODE_BLOCK_SIZE as u64;
+    self.inode.set_blocks_count(blocks_count);
+    Ok(())
+  }
+}
+
+// 把inode的块映射读成按逻辑块号排序的extent，连续的块合并成一个extent
+pub fn read_block_map<R: Read + Seek>(
+  inode: &Inode,
+  reader: &mut R,
+  super_block: &SuperBlock,
+) -> Result<Vec<Extent>, Error<R::Error>> {
+  let block_size = super_block.get_block_size();
+  let p = block_size / 4;
+  let mut extents = Vec::new();
+  for (i, &pblock) in inode.block[..DIRECT_BLOCKS].iter().enumerate() {
+    push_block(&mut extents, i as u64, pblock);
+  }
+  let mut base = DIRECT_BLOCKS as u64;
+  for level in 1..=3u32 {
+    let pblock = inode.block[IND_BLOCK + level as usize - 1];
+    if pblock != 0 {
+      read_indirect(reader, block_size, pblock, level, base, &mut extents)?;
+    }
+    base += p.pow(level);
+  }
+  Ok(extents)
+}
+
+// 递归读取一个level级间接块，base是它负责的第一个逻辑块号
+fn read_indirect<R: Read + Seek>(
+  reader: &mut R,
+  block_size: u64,
+  pblock: u32,
+  level: u32,
+  base: u64,
+  extents: &mut Vec<Extent>,
+) -> Result<(), Error<R::Error>> {
+  let ptrs = read_ptrs(reader, block_size, pblock)?;
+  let span = (block_size / 4).pow(level - 1);
+  for (i, &ptr) in ptrs.iter().enumerate() {
+    let lblock = base + i as u64 * span;
+    if ptr == 0 || lblock > u32::MAX as u64 {
+      continue;
+    }
+    if level == 1 {
+      push_block(extents, lblock, ptr);
+    } else {
+      read_indirect(reader, block_size, ptr, level - 1, lblock, extents)?;
+    }
+  }
+  Ok(())
+}
+
+fn read_ptrs<R: Read + Seek>(reader: &mut R, block_size: u64, pblock: u32) -> Result<Vec<u32>, Error<R::Error>> {
+  let mut data = vec![0u8; block_size as usize];
+  reader.seek(SeekFrom::Start(pblock as u64 * block_size))?;
+  reader.read_exact(&mut data)?;
+  Ok(
+    data
+      .chunks_exact(4)
+      .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+      .collect(),
+  )
+}
+
+// 加入一个映射，能接在最后一个extent后面时直接合并
+fn push_block(extents: &mut Vec<Extent>, lblock: u64, pblock: u32) {
+  if pblock == 0 {
+    return;
+  }
+  let extent = Extent::new(lblock as u32, 1, pblock as u64);
+  match extents.last_mut() {
+    Some(last) if last.can_merge(&extent) => last.set_len(last.get_len() + 1),
+    _ => extents.push(extent),
+  }
+}
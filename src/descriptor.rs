@@ -2,7 +2,10 @@ use bitflags::bitflags;
 
 use crate::io::{Read, Write};
 use crate::super_block::SuperBlock;
-use crate::utils::{combine_u32, combine_u64, crc::crc32c};
+use crate::utils::{
+  combine_u32, combine_u64,
+  crc::{crc16, crc32c},
+};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -44,9 +47,10 @@ bitflags! {
 }
 
 impl BlockGroupDescriptor {
-  pub fn deserialize<R: Read>(reader: &mut R) -> Result<Self, R::Error> {
+  // 磁盘上的descriptor有desc_size字节：32字节的descriptor没有_hi字段，读出来是0
+  pub fn deserialize<R: Read>(reader: &mut R, desc_size: usize) -> Result<Self, R::Error> {
     let mut buffer = [0u8; core::mem::size_of::<Self>()];
-    reader.read_exact(&mut buffer)?;
+    reader.read_exact(&mut buffer[..desc_size])?;
     let bgd: BlockGroupDescriptor = unsafe {
       let ptr = buffer.as_ptr() as *const Self;
       ptr.read_unaligned()
@@ -54,9 +58,9 @@ impl BlockGroupDescriptor {
     Ok(bgd)
   }
 
-  pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), W::Error> {
-    let self_bytes =
-      unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>()) };
+  // 只写入desc_size字节，32字节的descriptor不写_hi字段
+  pub fn serialize<W: Write>(&self, writer: &mut W, desc_size: usize) -> Result<(), W::Error> {
+    let self_bytes = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, desc_size) };
     writer.write_all(self_bytes)?;
     Ok(())
   }
@@ -77,20 +81,24 @@ impl BlockGroupDescriptor {
     BGFlags::from_bits_truncate(self.flags)
   }
 
+  pub fn set_flags(&mut self, flags: BGFlags) {
+    self.flags = flags.bits();
+  }
+
   pub fn get_free_inodes_count(&self) -> u32 {
-    combine_u32(self.free_inodes_count_lo as u16, self.free_inodes_count_hi as u16)
+    combine_u32(self.free_inodes_count_lo, self.free_inodes_count_hi)
   }
 
   pub fn get_free_blocks_count(&self) -> u32 {
-    combine_u32(self.free_blocks_count_lo as u16, self.free_blocks_count_hi as u16)
+    combine_u32(self.free_blocks_count_lo, self.free_blocks_count_hi)
   }
 
   pub fn get_used_dirs_count(&self) -> u32 {
-    combine_u32(self.used_dirs_count_lo as u16, self.used_dirs_count_hi as u16)
+    combine_u32(self.used_dirs_count_lo, self.used_dirs_count_hi)
   }
 
   pub fn get_itable_unused(&self) -> u32 {
-    combine_u32(self.itable_unused_lo as u16, self.itable_unused_hi as u16)
+    combine_u32(self.itable_unused_lo, self.itable_unused_hi)
   }
 
   pub fn set_inode_bitmap_csum(&mut self, super_block: &SuperBlock, bitmap: &[u8]) {
@@ -99,8 +107,8 @@ impl BlockGroupDescriptor {
     }
     let inodes_per_group = super_block.inodes_per_group;
     let uuid = super_block.uuid;
-    let mut csum = crc32c(!0 as u32, &uuid, uuid.len() as u32);
-    csum = crc32c(csum, bitmap, (inodes_per_group + 7) / 8);
+    let mut csum = crc32c(!0, &uuid, uuid.len() as u32);
+    csum = crc32c(csum, bitmap, inodes_per_group.div_ceil(8));
 
     let csum_lo = (csum & 0xFFFF).to_le();
     let csum_hi = (csum >> 16).to_le();
@@ -114,8 +122,8 @@ impl BlockGroupDescriptor {
     }
     let blocks_per_group = super_block.blocks_per_group;
     let uuid = super_block.uuid;
-    let mut csum = crc32c(!0 as u32, &uuid, uuid.len() as u32);
-    csum = crc32c(csum, bitmap, (blocks_per_group / 8) as u32);
+    let mut csum = crc32c(!0, &uuid, uuid.len() as u32);
+    csum = crc32c(csum, bitmap, blocks_per_group / 8);
 
     let csum_lo = (csum & 0xFFFF).to_le();
     let csum_hi = (csum >> 16).to_le();
@@ -143,19 +151,29 @@ impl BlockGroupDescriptor {
     self.itable_unused_hi = (count >> 16) as u16;
   }
 
+  // 开启metadata_csum时是crc32c的低16位，只开启gdt_csum时是crc16，都没有开启时没有校验和
   pub fn compute_checksum(&mut self, bgd_id: u32, super_block: &SuperBlock) -> u16 {
     let original_csum = self.checksum;
     self.checksum = 0;
-    // 计算
-    let mut csum = crc32c(!0, &super_block.uuid, super_block.uuid.len() as u32);
-    csum = crc32c(csum, &bgd_id.to_le_bytes(), 4);
-    let self_bytes =
-      unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>()) };
-    assert_eq!(self_bytes.len(), super_block.get_desc_size() as usize);
-    csum = crc32c(csum, self_bytes, self_bytes.len() as u32);
+    let desc_size = super_block.get_desc_size() as usize;
+    let self_bytes = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, desc_size) };
+    let csum = if super_block.has_feature_ro_compat_metadata_csum() {
+      let mut csum = crc32c(!0, &super_block.uuid, super_block.uuid.len() as u32);
+      csum = crc32c(csum, &bgd_id.to_le_bytes(), 4);
+      csum = crc32c(csum, self_bytes, self_bytes.len() as u32);
+      (csum & 0xFFFF) as u16
+    } else if super_block.has_feature_ro_compat_gdt_csum() {
+      // checksum字段本身不参与计算
+      let offset = core::mem::offset_of!(Self, checksum);
+      let mut csum = crc16(!0, &super_block.uuid);
+      csum = crc16(csum, &bgd_id.to_le_bytes());
+      csum = crc16(csum, &self_bytes[..offset]);
+      crc16(csum, &self_bytes[offset + 2..])
+    } else {
+      0
+    };
 
     self.checksum = original_csum;
-    let csum = (csum & 0xFFFF) as u16;
     csum
   }
 
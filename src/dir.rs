@@ -3,9 +3,10 @@ use crate::error::Error;
 use crate::extent::Extent;
 use crate::file::File;
 use crate::fs::FileSystem;
+use crate::htree::{dx_hash, DxEntry, DxFrame, DxNode, DxRootInfo, DxTail};
 use crate::inode::{Inode, InodeFilePerm, InodeFileType, InodeFlags};
 use crate::io::{ReadWriteSeek, SeekFrom};
-use crate::utils::split_path;
+use crate::utils::split_parent;
 
 pub struct Dir<'a, IO: ReadWriteSeek> {
   pub ino: u64,
@@ -13,13 +14,21 @@ pub struct Dir<'a, IO: ReadWriteSeek> {
   pub fs: &'a FileSystem<IO>,
 }
 
+// entry所在的物理块、块里的所有entry和entry在块里的下标
+type DirBlockEntry = (u64, Vec<DirEntryData>, usize);
+
+// dx_root里的散列算法、name的hash和从root到最底层的索引节点
+type DxPath = (u8, u32, Vec<DxFrame>);
+
+// 按块遍历目录，跳过inode为0的空闲entry
 pub struct DirIter<'a, IO: ReadWriteSeek> {
   pub dir_ino: u64,
   pub dir_inode: Inode,
   pub fs: &'a FileSystem<IO>,
-  pub extent_idx: usize,
-  pub extent_offset: u64,
-  pub tail_entry: Option<DirEntryData>,
+  pub blocks: Option<Vec<u64>>,   // 目录占用的物理块，第一次调用next时读取
+  pub block_idx: usize,           // 下一个要读取的块
+  pub entries: Vec<DirEntryData>, // 当前块里的entry
+  pub entry_idx: usize,           // 下一个要返回的entry
 }
 
 impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
@@ -32,9 +41,10 @@ impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
       dir_ino: self.ino,
       dir_inode: self.inode,
       fs: self.fs,
-      extent_idx: 0,
-      extent_offset: 0,
-      tail_entry: None,
+      blocks: None,
+      block_idx: 0,
+      entries: Vec::new(),
+      entry_idx: 0,
     }
   }
 
@@ -50,122 +60,115 @@ impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
       name,
       file_type
     );
+    self.insert_entry(ino, name, file_type)?;
+
+    // 更新link count
+    if let Some(file_type) = file_type {
+      if file_type == DirEntryFileType::DIR {
+        trace!("Dir::add_dir_entry_and_sync: increment parent dir link count if new entry is a dir");
+        self.inode.links_count += 1;
+        self.fs.write_inode(self.ino, &mut self.inode)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  // 新inode的extra_isize，128字节的inode没有扩展部分
+  fn new_extra_isize(&self) -> u16 {
+    let super_block = self.fs.super_block.borrow();
+    let max = super_block.get_inode_size() as u16 - Inode::GOOD_OLD_INODE_SIZE;
+    super_block.want_extra_isize.min(max)
+  }
+
+  // 开启extents时新inode使用extent tree，否则使用ext2/ext3的块映射
+  fn init_new_inode_blocks(&self, inode: &mut Inode, extents: &[Extent]) {
+    if self.fs.super_block.borrow().has_feature_incompat_extents() {
+      inode.set_flags(inode.get_flags() | InodeFlags::EXTENTS_FL);
+      inode.init_extent_tree(extents.to_vec());
+    } else {
+      inode.init_block_map(extents);
+    }
+  }
+
+  // 在目录里插入一个entry，不修改链接数
+  // 有htree索引时插入到hash对应的叶子块里，否则优先复用空闲的entry或者entry后面多余的空间，
+  // 所有块都满了时，只有一个块的目录在开启dir_index时转换成有索引的目录，其它目录在末尾追加一个新块
+  fn insert_entry(
+    &mut self,
+    ino: u32,
+    name: &str,
+    file_type: Option<DirEntryFileType>,
+  ) -> Result<(), Error<IO::Error>> {
+    trace!(
+      "Dir::insert_entry ino: {}, name: {}, file_type: {:?}",
+      ino,
+      name,
+      file_type
+    );
+    Self::check_name(name)?;
     let mut new_entry = DirEntryData::new(
       ino,
       name,
       file_type,
       self.fs.super_block.borrow().has_feature_incompat_filetype(),
     );
-    trace!("Dir::add_dir_entry_and_sync new_entry: {:?}", new_entry);
+    trace!("Dir::insert_entry new_entry: {:?}", new_entry);
 
-    // 找到最后一个entry对应的extent
-    let extents = {
-      let mut disk = self.fs.disk.borrow_mut();
-      self.inode.get_extents(&mut *disk)?
-    };
-    let (extent_idx, mut extent_offset, mut entries) = {
-      let mut iter = self.iter();
-      let mut entries = Vec::new();
-      for entry in &mut iter {
-        entries.push(entry.unwrap().data);
+    if self.inode.get_flags().contains(InodeFlags::INDEX_FL) {
+      if self.is_indexed() && self.dx_insert_entry(new_entry)? {
+        return Ok(());
       }
-      let mut extent_offset = iter.extent_offset;
-      let mut extent_idx = iter.extent_idx;
-      let last_entry = entries.last().unwrap();
-      if extent_offset == 0 {
-        extent_idx -= 1;
-        extent_offset = self.fs.super_block.borrow().get_block_size() * extents[extent_idx].len as u64;
-      }
-      extent_offset -= last_entry.get_rec_len() as u64;
-
-      // 检查checksum
-      let tail_entry = iter.tail_entry.unwrap();
-      let csum = tail_entry.get_checksum();
-      let cmp_csum = DirEntryData::compute_dirblock_checksum(
-        &entries,
-        self.fs.super_block.borrow().get_block_size(),
-        &self.fs.super_block.borrow().uuid,
-        self.ino as u32,
-        self.inode.generation,
-      );
-      assert_eq!(csum, cmp_csum);
-      (extent_idx, extent_offset, entries)
-    };
-    let last_entry_idx = entries.len() - 1;
-    trace!("Dir::add_dir_entry_and_sync last_entry: {:?}", entries[last_entry_idx]);
-    trace!(
-      "Dir::add_dir_entry_and_sync extent_idx: {}, extent_offset: {}",
-      extent_idx,
-      extent_offset
-    );
-    // 假定只有一个extent，且这一个extent仅有一个物理块, 这样方便计算checksum
-    // TODO: 对于有多个物理块的extent（也就是dir entry分布在多个物理块），dir entry tail checksum如何计算？
-    assert!(extent_idx == 0);
-    let extent = extents[extent_idx];
-    assert!(extent.len == 1);
+      // 索引不可用，变回普通目录
+      self.dx_drop_index()?;
+    }
 
-    let mut disk = self.fs.disk.borrow_mut();
-    // TODO: 这里不考虑分配新的extent，所以last entry需要足够大
-    let last_entry_real_len = entries[last_entry_idx].get_real_rec_len();
-    assert!(last_entry_real_len + new_entry.get_rec_len() <= entries[last_entry_idx].get_rec_len());
-    // 更新last entry的rec_len并写入
-    let original_rec_len = entries[last_entry_idx].get_rec_len();
-    entries[last_entry_idx].set_rec_len(last_entry_real_len);
-    extent.write_entrydata(
-      self.fs.super_block.borrow().get_block_size(),
-      &mut *disk,
-      extent_offset,
-      &entries[last_entry_idx],
-    )?;
-    // 写入新的entry
-    extent_offset += last_entry_real_len as u64;
-    new_entry.set_rec_len(original_rec_len - last_entry_real_len);
-    extent.write_entrydata(
-      self.fs.super_block.borrow().get_block_size(),
-      &mut *disk,
-      extent_offset,
-      &new_entry,
-    )?;
-    entries.push(new_entry);
-    // 计算checksum并写入tail entry
-    let csum = DirEntryData::compute_dirblock_checksum(
-      &entries,
-      self.fs.super_block.borrow().get_block_size(),
-      &self.fs.super_block.borrow().uuid,
-      self.ino as u32,
-      self.inode.generation,
-    );
-    let tail_entry = DirEntryData::DirEntryTail(DirEntryTail {
-      reserved_zero1: 0,
-      rec_len: 12,
-      reserved_zero2: 0,
-      reserved_ft: 0xDE,
-      checksum: csum,
-    });
-    extent.write_entrydata(
-      self.fs.super_block.borrow().get_block_size(),
-      &mut *disk,
-      extent_offset + new_entry.get_rec_len() as u64,
-      &tail_entry,
-    )?;
+    let blocks = self.get_blocks()?;
+    for &pblock in &blocks {
+      let mut entries = self.read_dirblock(pblock)?;
+      if Self::place_entry(&mut entries, new_entry) {
+        trace!("Dir::insert_entry: insert into block {}", pblock);
+        return self.write_dirblock(pblock, &entries);
+      }
+    }
 
-    // 更新link count
-    if let Some(file_type) = file_type {
-      if file_type == DirEntryFileType::DIR {
-        trace!("Dir::add_dir_entry_and_sync: increment parent dir link count if new entry is a dir");
-        self.inode.links_count += 1;
-        self.inode.compute_and_set_checksum(
-          self.ino as u32,
-          self.fs.super_block.borrow().get_inode_size() as u16,
-          &self.fs.super_block.borrow().uuid,
-        );
-        let pos = self.fs.get_inode_pos(self.ino);
-        disk.seek(SeekFrom::Start(pos))?;
-        self.inode.serialize(&mut *disk)?;
+    if blocks.len() == 1 && self.fs.super_block.borrow().has_feature_compat_dir_index() {
+      self.dx_make_indexed()?;
+      if self.dx_insert_entry(new_entry)? {
+        return Ok(());
       }
+      error!("Dir::insert_entry: failed to insert into new index");
+      return Err(Error::CorruptedFileSystem);
     }
 
-    Ok(())
+    // 所有块都满了，追加一个新块
+    let (_, pblock) = self.append_block()?;
+    new_entry.set_rec_len(self.dirblock_space());
+    self.write_dirblock(pblock, &[new_entry])
+  }
+
+  // 在一个块的entry里找位置放下new_entry：复用空闲的entry，或者拆分后面有多余空间的entry
+  fn place_entry(entries: &mut Vec<DirEntryData>, mut new_entry: DirEntryData) -> bool {
+    let needed = new_entry.get_real_rec_len();
+    for i in 0..entries.len() {
+      let rec_len = entries[i].get_rec_len();
+      if entries[i].get_inode() == 0 {
+        if rec_len >= needed {
+          new_entry.set_rec_len(rec_len);
+          entries[i] = new_entry;
+          return true;
+        }
+        continue;
+      }
+      let real_rec_len = entries[i].get_real_rec_len();
+      if rec_len - real_rec_len >= needed {
+        entries[i].set_rec_len(real_rec_len);
+        new_entry.set_rec_len(rec_len - real_rec_len);
+        entries.insert(i + 1, new_entry);
+        return true;
+      }
+    }
+    false
   }
 }
 
@@ -173,90 +176,114 @@ impl<'a, IO: ReadWriteSeek> Iterator for DirIter<'a, IO> {
   type Item = Result<DirEntry<'a, IO>, Error<IO::Error>>;
 
   fn next(&mut self) -> Option<Self::Item> {
-    let inode = self.dir_inode;
-    assert!(inode.use_extents(), "only support extents");
-
-    let mut disk = self.fs.disk.borrow_mut();
-    // TODO: 每次next都要读所有的extents，可以优化
-    let extents = inode.get_extents(&mut *disk).unwrap();
-    if self.extent_idx >= extents.len() {
-      return None;
-    }
-    let extent = extents[self.extent_idx];
-    let entrydata = extent
-      .read_entrydata(
-        self.fs.super_block.borrow().get_block_size(),
-        self.fs.super_block.borrow().has_feature_incompat_filetype(),
-        &mut *disk,
-        self.extent_offset,
-      )
-      .unwrap();
-    match entrydata {
-      Some(entrydata) => {
-        if let DirEntryData::DirEntryTail(tail_entry) = entrydata {
-          self.tail_entry = Some(DirEntryData::DirEntryTail(tail_entry));
-          None
-        } else {
-          self.extent_offset += entrydata.get_rec_len() as u64;
-          if self.extent_offset >= extent.len as u64 * self.fs.super_block.borrow().get_block_size() {
-            self.extent_offset = 0;
-            self.extent_idx += 1;
-          }
-          Some(Ok(DirEntry {
-            data: entrydata,
-            fs: self.fs,
-          }))
+    let dir = Dir::new(self.dir_ino, self.dir_inode, self.fs);
+    if self.blocks.is_none() {
+      match dir.get_blocks() {
+        Ok(blocks) => self.blocks = Some(blocks),
+        Err(e) => {
+          self.blocks = Some(Vec::new());
+          return Some(Err(e));
+        }
+      }
+    }
+    let blocks = self.blocks.as_ref().unwrap();
+    loop {
+      if let Some(entry) = self.entries.get(self.entry_idx) {
+        self.entry_idx += 1;
+        if entry.get_inode() == 0 {
+          continue;
+        }
+        return Some(Ok(DirEntry {
+          data: *entry,
+          fs: self.fs,
+        }));
+      }
+      let pblock = *blocks.get(self.block_idx)?;
+      self.block_idx += 1;
+      match dir.read_dirblock(pblock) {
+        Ok(entries) => {
+          self.entries = entries;
+          self.entry_idx = 0;
+        }
+        Err(e) => {
+          // 出错之后不再继续遍历
+          self.block_idx = blocks.len();
+          self.entries.clear();
+          return Some(Err(e));
         }
       }
-      None => None,
     }
   }
 }
 
 // 对外提供的接口
 impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
+  // 有htree索引时按hash查找，否则逐块扫描
   pub fn find_entry(&self, name: &str) -> Result<DirEntry<'a, IO>, Error<IO::Error>> {
     trace!("Dir::find_entry name: {}", name);
-    for r in self.iter() {
-      let e = r?;
-      if e.data.get_name_str() == name {
-        return Ok(e);
-      }
-    }
-    Err(Error::NotFound)
+    let (_, entries, idx) = self.find_dirblock_entry(name)?;
+    Ok(DirEntry {
+      data: entries[idx],
+      fs: self.fs,
+    })
   }
 
   pub fn is_exist(&self, name: &str) -> bool {
     trace!("Dir::is_exist name: {}", name);
-    for r in self.iter() {
-      let e = r.unwrap();
-      if e.data.get_name_str() == name {
-        return true;
-      }
+    self.find_entry(name).is_ok()
+  }
+
+  // entry的名字不能为空也不能超过255字节，不能是.和..，也不能包含/
+  fn check_name(name: &str) -> Result<(), Error<IO::Error>> {
+    if name.is_empty() || name.len() > 255 {
+      return Err(Error::InvalidFileNameLength);
     }
-    false
+    if name == "." || name == ".." || name.contains('/') {
+      return Err(Error::InvalidInput);
+    }
+    Ok(())
   }
 
+  // name是合法的名字并且在目录里还不存在，查找出错(比如还不支持的inline data目录)时返回错误
+  // 创建文件时在分配inode之前调用
+  fn check_not_exist(&self, name: &str) -> Result<(), Error<IO::Error>> {
+    Self::check_name(name)?;
+    match self.find_entry(name) {
+      Ok(_) => Err(Error::AlreadyExists),
+      Err(Error::NotFound) => Ok(()),
+      Err(e) => Err(e),
+    }
+  }
+
+  // 打开path对应的目录，路径中的符号链接都会被解析
   pub fn open_dir(&self, path: &str) -> Result<Self, Error<IO::Error>> {
     trace!("Dir::open_dir path: {}", path);
-    let (name, rest_opt) = split_path(path);
-    let e = self.find_entry(name)?;
-    let dir = e.to_dir();
-    match rest_opt {
-      Some(rest) => dir.open_dir(rest),
-      None => Ok(dir),
+    let (ino, inode) = self.lookup(path)?;
+    if !inode.is_dir() {
+      return Err(Error::InvalidInput);
     }
+    Ok(Dir::new(ino, inode, self.fs))
   }
 
+  // 打开path对应的文件，路径中的符号链接都会被解析
   pub fn open_file(&self, path: &str) -> Result<File<'a, IO>, Error<IO::Error>> {
     trace!("Dir::open_file path: {}", path);
-    let (name, rest_opt) = split_path(path);
-    if let Some(rest) = rest_opt {
-      let e = self.find_entry(name)?;
-      return e.to_dir().open_file(rest);
+    let (ino, inode) = self.lookup(path)?;
+    if !inode.is_file() {
+      return Err(Error::InvalidInput);
     }
-    let e = self.find_entry(name)?;
-    Ok(e.to_file())
+    Ok(File::new(ino, inode, self.fs))
+  }
+
+  // 解析path，返回对应的inode号和inode
+  // 以/开头的路径从根目录开始，否则从当前目录开始，路径中的.、..和符号链接都会被解析
+  pub fn lookup(&self, path: &str) -> Result<(u64, Inode), Error<IO::Error>> {
+    self.resolve_path(path, true)
+  }
+
+  // 和lookup一样，但是最后一级是符号链接时不解析，返回符号链接本身
+  pub fn lookup_nofollow(&self, path: &str) -> Result<(u64, Inode), Error<IO::Error>> {
+    self.resolve_path(path, false)
   }
 
   pub fn create_dir(
@@ -274,22 +301,17 @@ impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
       gid,
       file_perm
     );
-    let (name, rest_opt) = split_path(path);
+    // 和mkdir一样允许结尾的/
+    let (parent, name, _) = split_parent(path).ok_or(Error::InvalidFileNameLength)?;
     // 所有父目录都存在
-    if let Some(rest) = rest_opt {
-      return self
-        .find_entry(name)?
-        .to_dir()
-        .create_dir(rest, uid, gid, file_perm, time);
+    if let Some(parent) = parent {
+      return self.open_dir(parent)?.create_dir(name, uid, gid, file_perm, time);
     }
 
-    if self.is_exist(name) {
-      return Err(Error::AlreadyExists);
-    }
+    self.check_not_exist(name)?;
 
-    let new_ino = self.fs.alloc_inode(true)?;
+    let new_ino = self.fs.alloc_inode(self.ino, true)?;
     let new_mode = (InodeFileType::DIR.bits() & Inode::FILETYPE_MASK) | (file_perm.bits() & Inode::FILEPERM_MASK);
-    let new_flags = InodeFlags::EXTENTS_FL;
     let mut new_inode = Inode {
       uid,
       gid,
@@ -301,58 +323,41 @@ impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
       links_count: 2,
       osd1: 1, // TODO: 为什么
       blocks_lo: self.fs.super_block.borrow().get_block_size() as u32 / Inode::INODE_BLOCK_SIZE as u32,
-      extra_isize: self.fs.super_block.borrow().want_extra_isize,
-      flags: new_flags.bits(),
+      extra_isize: self.new_extra_isize(),
       ..Inode::default()
     };
-    new_inode.set_size(self.fs.super_block.borrow().get_block_size() as u64);
-
-    // 分配一个block作为新目录的extent
-    let bgd_id = (new_ino - 1) / (self.fs.super_block.borrow().inodes_per_group as u64);
-    let new_block_start = self.fs.alloc_contiguous_blocks(1, bgd_id as usize)?;
-    let new_extent = Extent::new(0, 1, new_block_start);
-    new_inode.init_extent_tree(vec![new_extent]);
-    new_inode.compute_and_set_checksum(
-      new_ino as u32,
-      self.fs.super_block.borrow().get_inode_size() as u16,
-      &self.fs.super_block.borrow().uuid,
-    );
+    new_inode.set_size(self.fs.super_block.borrow().get_block_size());
+
+    // 中途失败时释放已经分配的块和inode，避免泄漏
+    if let Err(err) = self.init_new_dir(new_ino, &mut new_inode, name) {
+      new_inode.links_count = 0;
+      self.release_inode(new_ino, &mut new_inode, time)?;
+      return Err(err);
+    }
+    Ok(Dir::new(new_ino, new_inode, self.fs))
+  }
+
+  // 给新目录分配第一个目录块，写入inode和"."、".."，再在当前目录里加入它的entry
+  fn init_new_dir(&mut self, new_ino: u64, new_inode: &mut Inode, name: &str) -> Result<(), Error<IO::Error>> {
+    // 分配一个block作为新目录的第一个目录块
+    let new_block_start = self.fs.alloc_block(self.fs.get_inode_goal(new_ino))?;
+    self.init_new_inode_blocks(new_inode, &[Extent::new(0, 1, new_block_start)]);
     // 写入新的inode
     trace!("Dir::create_dir: write new inode to disk");
-    {
-      let pos = self.fs.get_inode_pos(new_ino as u64);
-      let mut disk = self.fs.disk.borrow_mut();
-      disk.seek(SeekFrom::Start(pos))?;
-      new_inode.serialize(&mut *disk)?;
-    }
-
-    // 在新目录的block里写入dir_entry(.., ., tail)
-    trace!("Dir::create_dir: create new dir entries(.., ., tail)");
-    let new_entries = DirEntryData::new_dir_entries(
-      new_ino as u32,
-      self.ino as u32,
-      self.fs.super_block.borrow().has_feature_incompat_filetype(),
-      self.fs.super_block.borrow().get_block_size(),
-      &self.fs.super_block.borrow().uuid,
-      self.inode.generation,
-    );
-    trace!(
-      "Dir::create_dir: write new dir entries(.., ., tail) to disk: {:?}",
-      new_entries
-    );
-    {
-      let mut disk = self.fs.disk.borrow_mut();
-      let mut offset = 0;
-      for entry in new_entries.iter() {
-        new_extent.write_entrydata(self.fs.super_block.borrow().get_block_size(), &mut *disk, offset, entry)?;
-        offset += entry.get_rec_len() as u64;
-      }
-    }
+    self.fs.write_inode(new_ino, new_inode)?;
 
-    // 在当前目录里写入新的entry
-    self.add_dir_entry_and_sync(new_ino as u32, name, Some(DirEntryFileType::DIR))?;
+    // 在新目录的block里写入dir_entry(., .., 开启metadata_csum时还有tail)
+    trace!("Dir::create_dir: create new dir entries");
+    let new_dir = Dir::new(new_ino, *new_inode, self.fs);
+    let filetype = self.fs.super_block.borrow().has_feature_incompat_filetype();
+    let new_entries = new_dir.pack_entries(vec![
+      DirEntryData::new(new_ino as u32, ".", Some(DirEntryFileType::DIR), filetype),
+      DirEntryData::new(self.ino as u32, "..", Some(DirEntryFileType::DIR), filetype),
+    ]);
+    new_dir.write_dirblock(new_block_start, &new_entries)?;
 
-    return Ok(Dir::new(new_ino as u64, new_inode, self.fs));
+    // 在当前目录里写入新的entry
+    self.add_dir_entry_and_sync(new_ino as u32, name, Some(DirEntryFileType::DIR))
   }
 
   pub fn create_file(
@@ -370,22 +375,20 @@ impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
       gid,
       file_perm
     );
-    let (name, rest_opt) = split_path(path);
+    let (parent, name, must_be_dir) = split_parent(path).ok_or(Error::InvalidFileNameLength)?;
+    // 结尾有/的路径只能指向目录
+    if must_be_dir {
+      return Err(Error::InvalidInput);
+    }
     // 所有父目录都存在
-    if let Some(rest) = rest_opt {
-      return self
-        .find_entry(name)?
-        .to_dir()
-        .create_file(rest, uid, gid, file_perm, time);
+    if let Some(parent) = parent {
+      return self.open_dir(parent)?.create_file(name, uid, gid, file_perm, time);
     }
 
-    if self.is_exist(name) {
-      return Err(Error::AlreadyExists);
-    }
+    self.check_not_exist(name)?;
 
-    let new_ino = self.fs.alloc_inode(true)?;
+    let new_ino = self.fs.alloc_inode(self.ino, false)?;
     let new_mode = (InodeFileType::REG.bits() & Inode::FILETYPE_MASK) | (file_perm.bits() & Inode::FILEPERM_MASK);
-    let new_flags = InodeFlags::EXTENTS_FL;
     let mut new_inode = Inode {
       uid,
       gid,
@@ -396,34 +399,1074 @@ impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
       crtime: time,
       links_count: 1,
       osd1: 1, // TODO: 为什么
-      blocks_lo: self.fs.super_block.borrow().get_block_size() as u32 / Inode::INODE_BLOCK_SIZE as u32,
-      extra_isize: self.fs.super_block.borrow().want_extra_isize,
-      flags: new_flags.bits(),
+      extra_isize: self.new_extra_isize(),
       ..Inode::default()
     };
-    new_inode.set_size(self.fs.super_block.borrow().get_block_size() as u64);
-
-    // 分配一个block作为新目录的extent
-    let bgd_id = (new_ino - 1) / (self.fs.super_block.borrow().inodes_per_group as u64);
-    let new_block_start = self.fs.alloc_contiguous_blocks(1, bgd_id as usize)?;
-    let new_extent = Extent::new(0, 1, new_block_start);
-    new_inode.init_extent_tree(vec![new_extent]);
-    new_inode.compute_and_set_checksum(
-      new_ino as u32,
-      self.fs.super_block.borrow().get_inode_size() as u16,
-      &self.fs.super_block.borrow().uuid,
-    );
+    // 新文件是空的，数据块在写入时再分配
+    self.init_new_inode_blocks(&mut new_inode, &[]);
     // 写入新的inode
     trace!("Dir::create_file: write new inode to disk");
-    {
-      let pos = self.fs.get_inode_pos(new_ino as u64);
-      let mut disk = self.fs.disk.borrow_mut();
-      disk.seek(SeekFrom::Start(pos))?;
-      new_inode.serialize(&mut *disk)?;
-    }
+    self.fs.write_inode(new_ino, &mut new_inode)?;
 
     // 在当前目录里写入新的entry
     self.add_dir_entry_and_sync(new_ino as u32, name, Some(DirEntryFileType::REG_FILE))?;
-    return Ok(File::new(new_ino as u64, new_inode, self.fs));
+    Ok(File::new(new_ino, new_inode, self.fs))
+  }
+
+  // 创建一个指向target的符号链接，返回新的inode号
+  // 目标不超过59字节时直接保存在inode.block里，否则保存在一个数据块里
+  pub fn create_symlink(
+    &mut self,
+    path: &str,
+    target: &str,
+    uid: u16,
+    gid: u16,
+    time: u32,
+  ) -> Result<u64, Error<IO::Error>> {
+    trace!("Dir::create_symlink path: {}, target: {}", path, target);
+    let (parent, name, must_be_dir) = split_parent(path).ok_or(Error::InvalidFileNameLength)?;
+    // 结尾有/的路径只能指向目录
+    if must_be_dir {
+      return Err(Error::InvalidInput);
+    }
+    // 所有父目录都存在
+    if let Some(parent) = parent {
+      return self.open_dir(parent)?.create_symlink(name, target, uid, gid, time);
+    }
+
+    self.check_not_exist(name)?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    if target.is_empty() || target.len() >= block_size as usize {
+      return Err(Error::InvalidInput);
+    }
+
+    let new_ino = self.fs.alloc_inode(self.ino, false)?;
+    let new_mode = (InodeFileType::LNK.bits() & Inode::FILETYPE_MASK) | (0o777 & Inode::FILEPERM_MASK);
+    let mut new_inode = Inode {
+      uid,
+      gid,
+      mode: new_mode,
+      atime: time,
+      ctime: time,
+      mtime: time,
+      crtime: time,
+      links_count: 1,
+      osd1: 1, // TODO: 为什么
+      extra_isize: self.new_extra_isize(),
+      ..Inode::default()
+    };
+    new_inode.set_size(target.len() as u64);
+    if target.len() <= Inode::FAST_SYMLINK_MAX_LEN {
+      // 快速符号链接，不使用extents
+      let block_bytes = unsafe { core::slice::from_raw_parts_mut(new_inode.block.as_mut_ptr() as *mut u8, 60) };
+      block_bytes[..target.len()].copy_from_slice(target.as_bytes());
+    } else {
+      let new_block = self.fs.alloc_block(self.fs.get_inode_goal(new_ino))?;
+      let mut data = vec![0u8; block_size as usize];
+      data[..target.len()].copy_from_slice(target.as_bytes());
+      self.fs.write_block(new_block, &data)?;
+      self.init_new_inode_blocks(&mut new_inode, &[Extent::new(0, 1, new_block)]);
+      new_inode.set_blocks_count(block_size / Inode::INODE_BLOCK_SIZE as u64);
+    }
+    trace!("Dir::create_symlink: write new inode to disk");
+    self.fs.write_inode(new_ino, &mut new_inode)?;
+
+    // 在当前目录里写入新的entry
+    self.add_dir_entry_and_sync(new_ino as u32, name, Some(DirEntryFileType::SYMLINK))?;
+    Ok(new_ino)
+  }
+
+  // 创建FIFO、socket或者字符/块设备文件，返回新的inode号
+  // 设备号只对字符设备和块设备有意义，其余类型会忽略major和minor
+  #[allow(clippy::too_many_arguments)]
+  pub fn mknod(
+    &mut self,
+    path: &str,
+    file_type: InodeFileType,
+    major: u32,
+    minor: u32,
+    uid: u16,
+    gid: u16,
+    file_perm: InodeFilePerm,
+    time: u32,
+  ) -> Result<u64, Error<IO::Error>> {
+    trace!(
+      "Dir::mknod path: {}, file_type: {:?}, major: {}, minor: {}",
+      path,
+      file_type,
+      major,
+      minor
+    );
+    let (parent, name, must_be_dir) = split_parent(path).ok_or(Error::InvalidFileNameLength)?;
+    // 结尾有/的路径只能指向目录
+    if must_be_dir {
+      return Err(Error::InvalidInput);
+    }
+    // 所有父目录都存在
+    if let Some(parent) = parent {
+      return self
+        .open_dir(parent)?
+        .mknod(name, file_type, major, minor, uid, gid, file_perm, time);
+    }
+
+    let is_device = file_type == InodeFileType::CHR || file_type == InodeFileType::BLK;
+    if !(is_device || file_type == InodeFileType::FIFO || file_type == InodeFileType::SOCK) {
+      return Err(Error::InvalidInput);
+    }
+    if is_device && (major > Inode::MAX_DEVICE_MAJOR || minor > Inode::MAX_DEVICE_MINOR) {
+      return Err(Error::InvalidInput);
+    }
+    self.check_not_exist(name)?;
+
+    let new_ino = self.fs.alloc_inode(self.ino, false)?;
+    let new_mode = (file_type.bits() & Inode::FILETYPE_MASK) | (file_perm.bits() & Inode::FILEPERM_MASK);
+    // 特殊文件没有数据块，也不使用extents
+    let mut new_inode = Inode {
+      uid,
+      gid,
+      mode: new_mode,
+      atime: time,
+      ctime: time,
+      mtime: time,
+      crtime: time,
+      links_count: 1,
+      osd1: 1, // TODO: 为什么
+      extra_isize: self.new_extra_isize(),
+      ..Inode::default()
+    };
+    if is_device {
+      new_inode.set_device(major, minor);
+    }
+    trace!("Dir::mknod: write new inode to disk");
+    self.fs.write_inode(new_ino, &mut new_inode)?;
+
+    // 在当前目录里写入新的entry
+    self.add_dir_entry_and_sync(new_ino as u32, name, Some(DirEntryFileType::from(file_type)))?;
+    Ok(new_ino)
+  }
+
+  // 读出path对应的符号链接的目标，path的最后一级不会被解析
+  pub fn read_link(&self, path: &str) -> Result<String, Error<IO::Error>> {
+    trace!("Dir::read_link path: {}", path);
+    let (ino, inode) = self.lookup_nofollow(path)?;
+    self.fs.read_symlink(ino, &inode)
+  }
+
+  // 删除path对应的文件或者空目录，链接数减到0时释放inode和它占用的所有块
+  pub fn remove(&mut self, path: &str, time: u32) -> Result<(), Error<IO::Error>> {
+    trace!("Dir::remove path: {}", path);
+    let (parent, name, must_be_dir) = split_parent(path).ok_or(Error::InvalidFileNameLength)?;
+    match parent {
+      Some(parent) => self.open_dir(parent)?.remove_name(name, must_be_dir, time),
+      None => self.remove_name(name, must_be_dir, time),
+    }
+  }
+
+  // 删除当前目录下的name，must_be_dir为true时name必须是目录
+  fn remove_name(&mut self, name: &str, must_be_dir: bool, time: u32) -> Result<(), Error<IO::Error>> {
+    if name == "." || name == ".." {
+      return Err(Error::InvalidInput);
+    }
+
+    let ino = self.find_entry(name)?.data.get_inode() as u64;
+    let mut inode = self.fs.get_inode(ino)?;
+    let is_dir = inode.is_dir();
+    if must_be_dir && !is_dir {
+      return Err(Error::InvalidInput);
+    }
+    if is_dir && !Dir::new(ino, inode, self.fs).is_empty()? {
+      return Err(Error::DirectoryIsNotEmpty);
+    }
+    // 目录的.也指向自己，所以删除目录时链接数直接清零
+    let links_count = if is_dir {
+      0
+    } else {
+      inode.links_count.checked_sub(1).ok_or(Error::CorruptedFileSystem)?
+    };
+    self.remove_entry(name)?;
+
+    // 被删除的目录里的..指向当前目录
+    if is_dir {
+      self.inode.links_count -= 1;
+    }
+    self.inode.mtime = time;
+    self.inode.ctime = time;
+    self.fs.write_inode(self.ino, &mut self.inode)?;
+
+    inode.links_count = links_count;
+    inode.ctime = time;
+    if inode.links_count > 0 {
+      return self.fs.write_inode(ino, &mut inode);
+    }
+    self.release_inode(ino, &mut inode, time)
+  }
+
+  // 把old_path移动到new_dir下并改名为new_name，new_dir可以就是old_path所在的目录
+  // new_name已经存在时按POSIX的规则覆盖：文件只能覆盖文件，目录只能覆盖空目录
+  pub fn rename(
+    &mut self,
+    old_path: &str,
+    new_dir: &mut Dir<'a, IO>,
+    new_name: &str,
+    time: u32,
+  ) -> Result<(), Error<IO::Error>> {
+    trace!(
+      "Dir::rename old_path: {}, new_dir: {}, new_name: {}",
+      old_path,
+      new_dir.ino,
+      new_name
+    );
+    let (parent, name, must_be_dir) = split_parent(old_path).ok_or(Error::InvalidFileNameLength)?;
+    match parent {
+      Some(parent) => self
+        .open_dir(parent)?
+        .rename_name(name, must_be_dir, new_dir, new_name, time),
+      None => self.rename_name(name, must_be_dir, new_dir, new_name, time),
+    }
+  }
+
+  // 把当前目录下的name移动到new_dir下，must_be_dir为true时name必须是目录
+  fn rename_name(
+    &mut self,
+    name: &str,
+    must_be_dir: bool,
+    new_dir: &mut Dir<'a, IO>,
+    new_name: &str,
+    time: u32,
+  ) -> Result<(), Error<IO::Error>> {
+    if [".", ".."].contains(&name) {
+      return Err(Error::InvalidInput);
+    }
+    Self::check_name(new_name)?;
+
+    let src_ino = self.find_entry(name)?.data.get_inode() as u64;
+    let mut src_inode = self.fs.get_inode(src_ino)?;
+    let is_dir = src_inode.is_dir();
+    if must_be_dir && !is_dir {
+      return Err(Error::InvalidInput);
+    }
+    let file_type = Some(DirEntryFileType::from(src_inode.get_file_type()));
+    // 目录不能移动到它自己的子目录里
+    if is_dir && new_dir.is_descendant_of(src_ino)? {
+      return Err(Error::InvalidInput);
+    }
+
+    let target_ino = match new_dir.find_entry(new_name) {
+      Ok(entry) => Some(entry.data.get_inode() as u64),
+      Err(Error::NotFound) => None,
+      Err(e) => return Err(e),
+    };
+    let mut replaced_dir = false;
+    match target_ino {
+      Some(target_ino) if target_ino == src_ino => return Ok(()),
+      Some(target_ino) => {
+        // 让已有的entry指向被移动的inode，原来的inode少一个链接
+        let mut target_inode = self.fs.get_inode(target_ino)?;
+        if target_inode.is_dir() != is_dir {
+          return Err(Error::InvalidInput);
+        }
+        if is_dir && !Dir::new(target_ino, target_inode, self.fs).is_empty()? {
+          return Err(Error::DirectoryIsNotEmpty);
+        }
+        let links_count = if is_dir {
+          0
+        } else {
+          target_inode
+            .links_count
+            .checked_sub(1)
+            .ok_or(Error::CorruptedFileSystem)?
+        };
+        new_dir.set_entry_inode(new_name, src_ino as u32, file_type)?;
+        target_inode.links_count = links_count;
+        target_inode.ctime = time;
+        if target_inode.links_count > 0 {
+          self.fs.write_inode(target_ino, &mut target_inode)?;
+        } else {
+          self.release_inode(target_ino, &mut target_inode, time)?;
+        }
+        replaced_dir = is_dir;
+      }
+      None => {
+        new_dir.insert_entry(src_ino as u32, new_name, file_type)?;
+        // 同一个目录时插入可能追加了块或者建立了索引，self.inode已经过时
+        if self.ino == new_dir.ino {
+          self.inode = self.fs.get_inode(self.ino)?;
+        }
+      }
+    }
+    self.remove_entry(name)?;
+
+    // 目录移动到别的目录下时，..指向新的父目录
+    let moved_dir = is_dir && self.ino != new_dir.ino;
+    if moved_dir {
+      Dir::new(src_ino, src_inode, self.fs).set_entry_inode("..", new_dir.ino as u32, file_type)?;
+    }
+    src_inode.ctime = time;
+    self.fs.write_inode(src_ino, &mut src_inode)?;
+
+    // 两个父目录可能是同一个目录，所以每次都从disk重新读取inode
+    let mut old_parent = self.fs.get_inode(self.ino)?;
+    if moved_dir {
+      old_parent.links_count -= 1;
+    }
+    old_parent.mtime = time;
+    old_parent.ctime = time;
+    self.fs.write_inode(self.ino, &mut old_parent)?;
+    let mut new_parent = self.fs.get_inode(new_dir.ino)?;
+    // 被覆盖的目录里的..不再指向新的父目录
+    new_parent.links_count = new_parent.links_count + moved_dir as u16 - replaced_dir as u16;
+    new_parent.mtime = time;
+    new_parent.ctime = time;
+    self.fs.write_inode(new_dir.ino, &mut new_parent)?;
+
+    self.inode = self.fs.get_inode(self.ino)?;
+    new_dir.inode = self.fs.get_inode(new_dir.ino)?;
+    Ok(())
+  }
+
+  // 在当前目录下创建一个指向existing_path的硬链接new_name，目录不能创建硬链接
+  pub fn link(&mut self, existing_path: &str, new_name: &str, time: u32) -> Result<(), Error<IO::Error>> {
+    trace!("Dir::link existing_path: {}, new_name: {}", existing_path, new_name);
+    self.check_not_exist(new_name)?;
+
+    let (ino, mut inode) = self.lookup_nofollow(existing_path)?;
+    if inode.is_dir() || inode.links_count >= Inode::MAX_LINKS_COUNT {
+      return Err(Error::InvalidInput);
+    }
+    self.add_dir_entry_and_sync(
+      ino as u32,
+      new_name,
+      Some(DirEntryFileType::from(inode.get_file_type())),
+    )?;
+    self.inode.mtime = time;
+    self.inode.ctime = time;
+    self.fs.write_inode(self.ino, &mut self.inode)?;
+
+    inode.links_count += 1;
+    inode.ctime = time;
+    self.fs.write_inode(ino, &mut inode)
+  }
+
+  // 除了.和..之外没有别的entry
+  pub fn is_empty(&self) -> Result<bool, Error<IO::Error>> {
+    for entry in self.iter() {
+      let entry = entry?;
+      let name = entry.data.get_name_str();
+      if entry.data.get_inode() != 0 && name != "." && name != ".." {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  // 沿着..向上查找，判断当前目录是否是ino或者在ino的子树里
+  fn is_descendant_of(&self, ino: u64) -> Result<bool, Error<IO::Error>> {
+    let mut cur = self.ino;
+    loop {
+      if cur == ino {
+        return Ok(true);
+      }
+      if cur == Inode::ROOT_INO {
+        return Ok(false);
+      }
+      let inode = self.fs.get_inode(cur)?;
+      cur = Dir::new(cur, inode, self.fs).find_entry("..")?.data.get_inode() as u64;
+    }
+  }
+}
+
+// 路径解析
+impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
+  // 解析路径时最多跟随的符号链接数，和Linux的MAXSYMLINKS一样
+  pub const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+  fn resolve_path(&self, path: &str, follow: bool) -> Result<(u64, Inode), Error<IO::Error>> {
+    trace!("Dir::resolve_path path: {}, follow: {}", path, follow);
+    if path.is_empty() {
+      return Err(Error::NotFound);
+    }
+    // 还没有解析的路径组件，倒序存放，最后一个元素是下一个要解析的组件
+    let mut pending = Vec::new();
+    Self::push_components(&mut pending, path);
+    // 结尾有/时最后一级的符号链接也要解析，并且最后必须是目录
+    let must_be_dir = path.ends_with('/');
+    let follow = follow || must_be_dir;
+    let (mut ino, mut inode) = if path.starts_with('/') {
+      (Inode::ROOT_INO, self.fs.get_inode(Inode::ROOT_INO)?)
+    } else {
+      (self.ino, self.inode)
+    };
+
+    let mut follows = 0;
+    while let Some(name) = pending.pop() {
+      if !inode.is_dir() {
+        return Err(Error::InvalidInput);
+      }
+      if name == "." {
+        continue;
+      }
+      // 根目录的..指向它自己
+      let entry_ino = Dir::new(ino, inode, self.fs).find_entry(&name)?.data.get_inode() as u64;
+      let entry_inode = self.fs.get_inode(entry_ino)?;
+      if entry_inode.is_symlink() && (follow || !pending.is_empty()) {
+        follows += 1;
+        if follows > Self::MAX_SYMLINK_FOLLOWS {
+          return Err(Error::TooManySymlinks);
+        }
+        let target = self.fs.read_symlink(entry_ino, &entry_inode)?;
+        trace!("Dir::resolve_path: follow symlink {} -> {}", name, target);
+        Self::push_components(&mut pending, &target);
+        // 相对路径相对于符号链接所在的目录
+        if target.starts_with('/') {
+          ino = Inode::ROOT_INO;
+          inode = self.fs.get_inode(Inode::ROOT_INO)?;
+        }
+        continue;
+      }
+      ino = entry_ino;
+      inode = entry_inode;
+    }
+    if must_be_dir && !inode.is_dir() {
+      return Err(Error::InvalidInput);
+    }
+    Ok((ino, inode))
+  }
+
+  // 把path的组件倒序压入pending，让path的第一个组件最先被弹出
+  fn push_components(pending: &mut Vec<String>, path: &str) {
+    pending.extend(path.split('/').filter(|c| !c.is_empty()).rev().map(String::from));
+  }
+}
+
+// htree索引相关
+impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
+  // 文件系统开启了dir_index并且目录有INDEX_FL标志时才使用索引
+  fn is_indexed(&self) -> bool {
+    self.fs.super_block.borrow().has_feature_compat_dir_index() && self.inode.get_flags().contains(InodeFlags::INDEX_FL)
+  }
+
+  // 逻辑块号对应的物理块号，目录里不应该有空洞
+  fn map_dir_block(extents: &[Extent], lblock: u32) -> Result<u64, Error<IO::Error>> {
+    Extent::lookup(extents, lblock as u64)
+      .ok()
+      .and_then(|e| e.map_block(lblock as u64))
+      .ok_or_else(|| {
+        error!("Dir::map_dir_block: hole at lblock {}", lblock);
+        Error::CorruptedFileSystem
+      })
+  }
+
+  // 计算name在这个目录里的hash，hash_version是dx_root里记录的算法，不支持时返回None
+  fn dx_hash_name(&self, name: &[u8], hash_version: u8) -> Option<u32> {
+    let super_block = self.fs.super_block.borrow();
+    let mut hash_version = hash_version;
+    if super_block.is_hash_unsigned() && hash_version <= DxRootInfo::HASH_TEA {
+      hash_version += DxRootInfo::HASH_LEGACY_UNSIGNED;
+    }
+    dx_hash(name, hash_version, &super_block.get_hash_seed()).map(|(hash, _)| hash)
+  }
+
+  // dx_root里"."的rec_len是12，".."占满剩下的空间；dx_node里只有一个占满整个块的空entry
+  fn is_dx_block(block: &[u8]) -> bool {
+    let rec_len = |offset: usize| u16::from_le_bytes([block[offset + 4], block[offset + 5]]) as usize;
+    let is_node = block[0..4] == [0; 4] && rec_len(0) == block.len();
+    let is_root = rec_len(0) == 12 && block[6] == 1 && block[8] == b'.' && rec_len(12) == block.len() - 12;
+    is_node || is_root
+  }
+
+  // 从块里解析出索引节点，开启metadata_csum时检查dx_tail
+  fn dx_parse_node(&self, pblock: u64, block: &[u8], offset: usize) -> Result<DxNode, Error<IO::Error>> {
+    let super_block = self.fs.super_block.borrow();
+    let node = DxNode::parse(block, offset).ok_or_else(|| {
+      error!("Dir::dx_parse_node: invalid count/limit in block {}", pblock);
+      Error::CorruptedFileSystem
+    })?;
+    if super_block.has_feature_ro_compat_metadata_csum() {
+      if node.tail_offset() + core::mem::size_of::<DxTail>() > block.len() {
+        error!("Dir::dx_parse_node: no space for dx_tail in block {}", pblock);
+        return Err(Error::CorruptedFileSystem);
+      }
+      let csum = DxTail::compute_checksum(block, &node, &super_block.uuid, self.ino as u32, self.inode.generation);
+      if DxTail::get_checksum(block, &node) != csum {
+        error!("Dir::dx_parse_node: checksum mismatch in block {}", pblock);
+        return Err(Error::CorruptedFileSystem);
+      }
+    }
+    Ok(node)
+  }
+
+  // 把修改过的索引节点写回块里，重新计算dx_tail
+  fn dx_write_node(&self, pblock: u64, block: &mut [u8], node: &DxNode) -> Result<(), Error<IO::Error>> {
+    if !node.write(block) {
+      error!("Dir::dx_write_node: too many entries for block {}", pblock);
+      return Err(Error::CorruptedFileSystem);
+    }
+    let super_block = self.fs.super_block.borrow();
+    if super_block.has_feature_ro_compat_metadata_csum() {
+      let csum = DxTail::compute_checksum(block, node, &super_block.uuid, self.ino as u32, self.inode.generation);
+      DxTail::set_checksum(block, node, csum);
+    }
+    drop(super_block);
+    self.fs.write_block(pblock, block)
+  }
+
+  // 重新读取frame所在的块，写入修改过的索引节点
+  fn dx_write_frame(&self, frame: &DxFrame) -> Result<(), Error<IO::Error>> {
+    let block_size = self.fs.super_block.borrow().get_block_size() as usize;
+    let mut block = vec![0u8; block_size];
+    self.fs.read_block(frame.pblock, &mut block)?;
+    self.dx_write_node(frame.pblock, &mut block, &frame.node)
+  }
+
+  // 从root开始按name的hash逐层向下，返回root里的散列算法、hash和每一层的索引节点
+  // 最后一层选中的entry指向叶子块，索引不合法或者散列算法不支持时返回None
+  fn dx_probe(&self, extents: &[Extent], name: &[u8]) -> Result<Option<DxPath>, Error<IO::Error>> {
+    let (block_size, max_levels) = {
+      let super_block = self.fs.super_block.borrow();
+      let max_levels = if super_block.has_feature_incompat_largedir() {
+        3
+      } else {
+        2
+      };
+      (super_block.get_block_size() as usize, max_levels)
+    };
+    let mut block = vec![0u8; block_size];
+    let mut pblock = Self::map_dir_block(extents, 0)?;
+    self.fs.read_block(pblock, &mut block)?;
+    let info = DxRootInfo::load_from_u8(&block[DxRootInfo::OFFSET..]);
+    if info.reserved_zero != 0 || info.info_length != DxRootInfo::INFO_LENGTH || info.indirect_levels >= max_levels {
+      error!("Dir::dx_probe: invalid dx_root {:?}", info);
+      return Ok(None);
+    }
+    let Some(hash) = self.dx_hash_name(name, info.hash_version) else {
+      error!("Dir::dx_probe: unsupported hash version {}", info.hash_version);
+      return Ok(None);
+    };
+
+    let mut path = Vec::new();
+    let mut offset = DxRootInfo::NODE_OFFSET;
+    loop {
+      let node = self.dx_parse_node(pblock, &block, offset)?;
+      let idx = node.lookup(hash);
+      let lblock = node.entries[idx].block;
+      path.push(DxFrame { pblock, node, idx });
+      if path.len() > info.indirect_levels as usize {
+        break;
+      }
+      pblock = Self::map_dir_block(extents, lblock)?;
+      self.fs.read_block(pblock, &mut block)?;
+      offset = DxNode::NODE_OFFSET;
+    }
+    Ok(Some((info.hash_version, hash, path)))
+  }
+
+  // 用htree索引查找name，索引不可用时返回None，由调用者退回线性查找
+  fn dx_find_dirblock_entry(&self, name: &str) -> Result<Option<DirBlockEntry>, Error<IO::Error>> {
+    trace!("Dir::dx_find_dirblock_entry name: {}", name);
+    let extents = self.get_dir_extents()?;
+    let Some((_, hash, mut path)) = self.dx_probe(&extents, name.as_bytes())? else {
+      return Ok(None);
+    };
+    loop {
+      let frame = path.last().unwrap();
+      let pblock = Self::map_dir_block(&extents, frame.node.entries[frame.idx].block)?;
+      let entries = self.read_dirblock(pblock)?;
+      if let Some(i) = entries
+        .iter()
+        .position(|e| e.get_inode() != 0 && e.get_name() == name.as_bytes())
+      {
+        return Ok(Some((pblock, entries, i)));
+      }
+      if !self.dx_next_leaf(&extents, &mut path, hash)? {
+        return Err(Error::NotFound);
+      }
+    }
+  }
+
+  // 移动到下一个叶子块，只有下一个叶子块的起始hash和hash相同(hash冲突被拆到了多个块里)时才需要继续查找
+  fn dx_next_leaf(&self, extents: &[Extent], path: &mut [DxFrame], hash: u32) -> Result<bool, Error<IO::Error>> {
+    let Some(level) = path.iter().rposition(|f| f.idx + 1 < f.node.entries.len()) else {
+      return Ok(false);
+    };
+    path[level].idx += 1;
+    let frame = &path[level];
+    if frame.node.entries[frame.idx].hash & !1 != hash {
+      return Ok(false);
+    }
+    // 重新读取更低层的索引节点，从最左边的entry开始
+    let block_size = self.fs.super_block.borrow().get_block_size() as usize;
+    let mut block = vec![0u8; block_size];
+    for l in level + 1..path.len() {
+      let parent = &path[l - 1];
+      let pblock = Self::map_dir_block(extents, parent.node.entries[parent.idx].block)?;
+      self.fs.read_block(pblock, &mut block)?;
+      let node = self.dx_parse_node(pblock, &block, DxNode::NODE_OFFSET)?;
+      path[l] = DxFrame { pblock, node, idx: 0 };
+    }
+    Ok(true)
+  }
+
+  // 插入到hash对应的叶子块里，叶子块满了时按hash分裂成两个块，索引不可用时返回false
+  fn dx_insert_entry(&mut self, new_entry: DirEntryData) -> Result<bool, Error<IO::Error>> {
+    trace!("Dir::dx_insert_entry name: {}", new_entry.get_name_str());
+    let extents = self.get_dir_extents()?;
+    let Some((hash_version, hash, mut path)) = self.dx_probe(&extents, new_entry.get_name())? else {
+      return Ok(false);
+    };
+    let frame = path.last().unwrap();
+    let pblock = Self::map_dir_block(&extents, frame.node.entries[frame.idx].block)?;
+    let mut entries = self.read_dirblock(pblock)?;
+    if Self::place_entry(&mut entries, new_entry) {
+      self.write_dirblock(pblock, &entries)?;
+      return Ok(true);
+    }
+
+    // 叶子块满了，先保证最底层的索引节点里还能放下新叶子块的dx_entry
+    let level = path.len() - 1;
+    self.dx_make_room(&mut path, level)?;
+
+    // 按hash排序，后一半移到新块里
+    let mut map = Vec::new();
+    for entry in entries.into_iter().filter(|e| e.get_inode() != 0) {
+      let entry_hash = self.dx_hash_name(entry.get_name(), hash_version).unwrap();
+      map.push((entry_hash, entry));
+    }
+    map.sort_by_key(|(h, _)| *h);
+    if map.len() < 2 {
+      error!("Dir::dx_insert_entry: cannot split block {}", pblock);
+      return Err(Error::CorruptedFileSystem);
+    }
+    // 从后往前累计，超过半个块之前的entry都移走，和Linux的do_split一样
+    let half = self.fs.super_block.borrow().get_block_size() as usize / 2;
+    let mut size = 0;
+    let mut split = map.len() / 2;
+    for i in (1..map.len()).rev() {
+      let rec_len = map[i].1.get_real_rec_len() as usize;
+      if size + rec_len / 2 > half {
+        split = i + 1;
+        break;
+      }
+      size += rec_len;
+    }
+    // 和前一个entry的hash相同时设置冲突位，查找时会继续检查下一个块
+    let mut hash2 = map[split].0;
+    if hash2 == map[split - 1].0 {
+      hash2 |= 1;
+    }
+    let moved: Vec<DirEntryData> = map.split_off(split).into_iter().map(|(_, e)| e).collect();
+    let kept: Vec<DirEntryData> = map.into_iter().map(|(_, e)| e).collect();
+    let (lblock2, pblock2) = self.append_block()?;
+    trace!(
+      "Dir::dx_insert_entry: split block {} into {}, hash2: {:#x}",
+      pblock,
+      pblock2,
+      hash2
+    );
+
+    let frame = path.last_mut().unwrap();
+    frame.idx += 1;
+    frame.node.entries.insert(
+      frame.idx,
+      DxEntry {
+        hash: hash2,
+        block: lblock2,
+      },
+    );
+    self.dx_write_frame(path.last().unwrap())?;
+
+    let mut kept = self.pack_entries(kept);
+    let mut moved = self.pack_entries(moved);
+    let target = if hash >= hash2 { &mut moved } else { &mut kept };
+    if !Self::place_entry(target, new_entry) {
+      error!("Dir::dx_insert_entry: no space after split");
+      return Err(Error::NotEnoughSpace);
+    }
+    self.write_dirblock(pblock, &kept)?;
+    self.write_dirblock(pblock2, &moved)?;
+    Ok(true)
+  }
+
+  // 保证path[level]的索引节点没有满：满了就把它分裂成两个，root满了时增加一层索引
+  fn dx_make_room(&mut self, path: &mut Vec<DxFrame>, level: usize) -> Result<(), Error<IO::Error>> {
+    if !path[level].node.is_full() {
+      return Ok(());
+    }
+    let (block_size, has_csum, max_levels) = {
+      let super_block = self.fs.super_block.borrow();
+      let max_levels = if super_block.has_feature_incompat_largedir() {
+        3
+      } else {
+        2
+      };
+      (
+        super_block.get_block_size() as usize,
+        super_block.has_feature_ro_compat_metadata_csum(),
+        max_levels,
+      )
+    };
+    let mut block = vec![0u8; block_size];
+
+    if level == 0 {
+      // root满了，把root里的entry全部移到一个新的索引块里，root只指向这个块
+      if path.len() >= max_levels {
+        error!("Dir::dx_make_room: directory index is full");
+        return Err(Error::NotEnoughSpace);
+      }
+      let (lblock, pblock) = self.append_block()?;
+      trace!("Dir::dx_make_room: add index level, new node: {}", pblock);
+      let mut node = DxNode::new(block_size, DxNode::NODE_OFFSET, has_csum);
+      node.entries = core::mem::take(&mut path[0].node.entries);
+      DxNode::init_node_block(&mut block);
+      self.dx_write_node(pblock, &mut block, &node)?;
+      let child = DxFrame {
+        pblock,
+        node,
+        idx: path[0].idx,
+      };
+
+      let root = &mut path[0];
+      root.node.entries.push(DxEntry { hash: 0, block: lblock });
+      root.idx = 0;
+      self.fs.read_block(root.pblock, &mut block)?;
+      DxRootInfo::load_from_u8_mut(&mut block[DxRootInfo::OFFSET..]).indirect_levels = path.len() as u8;
+      self.dx_write_node(path[0].pblock, &mut block, &path[0].node)?;
+      path.insert(1, child);
+      return Ok(());
+    }
+
+    // 上一层可能是root，增加一层索引后这一层在path里的下标跟着后移
+    let len = path.len();
+    self.dx_make_room(path, level - 1)?;
+    let level = level + path.len() - len;
+    // 后一半entry移到新的索引块里，在上一层插入指向新块的entry
+    let (lblock, pblock) = self.append_block()?;
+    trace!(
+      "Dir::dx_make_room: split index node at level {}, new node: {}",
+      level,
+      pblock
+    );
+    let half = path[level].node.entries.len() / 2;
+    let mut node = DxNode::new(block_size, DxNode::NODE_OFFSET, has_csum);
+    node.entries = path[level].node.entries.split_off(half);
+    let hash = node.entries[0].hash;
+    DxNode::init_node_block(&mut block);
+    self.dx_write_node(pblock, &mut block, &node)?;
+    self.dx_write_frame(&path[level])?;
+
+    let parent = &mut path[level - 1];
+    parent
+      .node
+      .entries
+      .insert(parent.idx + 1, DxEntry { hash, block: lblock });
+    if path[level].idx >= half {
+      path[level - 1].idx += 1;
+      let idx = path[level].idx - half;
+      path[level] = DxFrame { pblock, node, idx };
+    }
+    self.dx_write_frame(&path[level - 1])
+  }
+
+  // 把只有一个块的普通目录转换成有索引的目录：
+  // "."和".."之外的entry移到新的叶子块里，第一个块变成只指向这个叶子块的dx_root
+  fn dx_make_indexed(&mut self) -> Result<(), Error<IO::Error>> {
+    trace!("Dir::dx_make_indexed ino: {}", self.ino);
+    let (block_size, has_csum, hash_version) = {
+      let super_block = self.fs.super_block.borrow();
+      (
+        super_block.get_block_size() as usize,
+        super_block.has_feature_ro_compat_metadata_csum(),
+        super_block.get_def_hash_version(),
+      )
+    };
+    let root_pblock = self.get_blocks()?[0];
+    let entries = self.read_dirblock(root_pblock)?;
+    if entries.len() < 2 || entries[0].get_name() != b"." || entries[1].get_name() != b".." {
+      error!("Dir::dx_make_indexed: missing . or .. in block {}", root_pblock);
+      return Err(Error::CorruptedFileSystem);
+    }
+    let others = entries[2..].iter().filter(|e| e.get_inode() != 0).copied().collect();
+    let (lblock, leaf_pblock) = self.append_block()?;
+    let leaf = self.pack_entries(others);
+    self.write_dirblock(leaf_pblock, &leaf)?;
+
+    // ".."占满root块剩下的空间，索引信息在它的填充里
+    let mut block = vec![0u8; block_size];
+    let (mut dot, mut dotdot) = (entries[0], entries[1]);
+    dot.set_rec_len(12);
+    dot.serialize_to_slice(&mut block);
+    dotdot.set_rec_len(block_size as u16 - 12);
+    dotdot.serialize_to_slice(&mut block[12..]);
+    *DxRootInfo::load_from_u8_mut(&mut block[DxRootInfo::OFFSET..]) = DxRootInfo::new(hash_version);
+    let mut root = DxNode::new(block_size, DxRootInfo::NODE_OFFSET, has_csum);
+    root.entries.push(DxEntry { hash: 0, block: lblock });
+    self.dx_write_node(root_pblock, &mut block, &root)?;
+
+    let flags = self.inode.get_flags() | InodeFlags::INDEX_FL;
+    self.inode.set_flags(flags);
+    self.fs.write_inode(self.ino, &mut self.inode)
+  }
+
+  // 修改dx_root里".."指向的inode，不能用write_dirblock，否则会覆盖索引
+  fn dx_set_dotdot(&self, ino: u32, file_type: Option<DirEntryFileType>) -> Result<(), Error<IO::Error>> {
+    let extents = self.get_dir_extents()?;
+    let pblock = Self::map_dir_block(&extents, 0)?;
+    let block_size = self.fs.super_block.borrow().get_block_size() as usize;
+    let mut block = vec![0u8; block_size];
+    self.fs.read_block(pblock, &mut block)?;
+    let node = self.dx_parse_node(pblock, &block, DxRootInfo::NODE_OFFSET)?;
+    block[12..16].copy_from_slice(&ino.to_le_bytes());
+    if let Some(file_type) = file_type {
+      block[19] = file_type.bits();
+    }
+    self.dx_write_node(pblock, &mut block, &node)
+  }
+
+  // 去掉INDEX_FL，索引块里只剩下空entry，开启metadata_csum时给它们补上tail
+  fn dx_drop_index(&mut self) -> Result<(), Error<IO::Error>> {
+    trace!("Dir::dx_drop_index ino: {}", self.ino);
+    let flags = self.inode.get_flags() - InodeFlags::INDEX_FL;
+    self.inode.set_flags(flags);
+    self.fs.write_inode(self.ino, &mut self.inode)?;
+    if !self.fs.super_block.borrow().has_feature_ro_compat_metadata_csum() {
+      return Ok(());
+    }
+    let block_size = self.fs.super_block.borrow().get_block_size() as usize;
+    let mut block = vec![0u8; block_size];
+    for pblock in self.get_blocks()? {
+      self.fs.read_block(pblock, &mut block)?;
+      if DirEntryTail::is_present(&block) {
+        continue;
+      }
+      // dx_root里".."和dx_node里的空entry占满了整个块，索引数据在它们的填充里
+      let mut entries = self.read_dirblock(pblock)?;
+      let last = entries.last_mut().ok_or(Error::CorruptedFileSystem)?;
+      let rec_len = last.get_rec_len() - core::mem::size_of::<DirEntryTail>() as u16;
+      if rec_len < last.get_real_rec_len() {
+        error!("Dir::dx_drop_index: no space for tail in block {}", pblock);
+        return Err(Error::CorruptedFileSystem);
+      }
+      last.set_rec_len(rec_len);
+      self.write_dirblock(pblock, &entries)?;
+    }
+    Ok(())
+  }
+}
+
+// 目录块相关
+impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
+  // 目录的所有extent
+  fn get_dir_extents(&self) -> Result<Vec<Extent>, Error<IO::Error>> {
+    let mut disk = self.fs.disk.borrow_mut();
+    self
+      .inode
+      .get_extents(self.ino, &mut *disk, &self.fs.super_block.borrow())
+  }
+
+  // 目录占用的所有物理块，按逻辑块号排序
+  fn get_blocks(&self) -> Result<Vec<u64>, Error<IO::Error>> {
+    let extents = self.get_dir_extents()?;
+    let blocks = extents
+      .iter()
+      .flat_map(|e| (0..e.get_len() as u64).map(move |i| e.get_block_loc() + i))
+      .collect();
+    Ok(blocks)
+  }
+
+  // 读出一个目录块里除了tail之外的所有entry，开启metadata_csum时检查tail里的校验和
+  fn read_dirblock(&self, pblock: u64) -> Result<Vec<DirEntryData>, Error<IO::Error>> {
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let filetype = self.fs.super_block.borrow().has_feature_incompat_filetype();
+    if self.fs.super_block.borrow().has_feature_ro_compat_metadata_csum() {
+      let mut data = vec![0u8; block_size as usize];
+      self.fs.read_block(pblock, &mut data)?;
+      let super_block = self.fs.super_block.borrow();
+      if !DirEntryTail::is_present(&data) {
+        // dx_root和dx_node的校验和在dx_tail里，由dx_parse_node检查
+        if !Self::is_dx_block(&data) {
+          error!("Dir::read_dirblock: missing tail in block {}", pblock);
+          return Err(Error::CorruptedFileSystem);
+        }
+      } else if DirEntryTail::get_checksum(&data)
+        != DirEntryTail::compute_checksum(&data, &super_block.uuid, self.ino as u32, self.inode.generation)
+      {
+        error!("Dir::read_dirblock: checksum mismatch in block {}", pblock);
+        return Err(Error::CorruptedFileSystem);
+      }
+    }
+    let mut disk = self.fs.disk.borrow_mut();
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < block_size {
+      disk.seek(SeekFrom::Start(pblock * block_size + offset))?;
+      let entry = DirEntryData::deserialize(&mut *disk, filetype, (block_size - offset) as usize)?;
+      if let DirEntryData::DirEntryTail(_) = entry {
+        break;
+      }
+      if entry.get_rec_len() < 8 {
+        error!("Dir::read_dirblock: invalid rec_len in block {}", pblock);
+        return Err(Error::CorruptedFileSystem);
+      }
+      offset += entry.get_rec_len() as u64;
+      entries.push(entry);
+    }
+    Ok(entries)
+  }
+
+  // 一个目录块里可以放entry的空间，开启metadata_csum时末尾要留给tail
+  fn dirblock_space(&self) -> u16 {
+    let super_block = self.fs.super_block.borrow();
+    let block_size = super_block.get_block_size() as u16;
+    if super_block.has_feature_ro_compat_metadata_csum() {
+      block_size - core::mem::size_of::<DirEntryTail>() as u16
+    } else {
+      block_size
+    }
+  }
+
+  // 把entry紧凑地排在一个块里，最后一个entry占满剩下的空间
+  fn pack_entries(&self, mut entries: Vec<DirEntryData>) -> Vec<DirEntryData> {
+    let space = self.dirblock_space();
+    if entries.is_empty() {
+      let mut empty = DirEntryData::new(
+        0,
+        "",
+        None,
+        self.fs.super_block.borrow().has_feature_incompat_filetype(),
+      );
+      empty.set_rec_len(space);
+      return vec![empty];
+    }
+    let mut used = 0;
+    for entry in entries.iter_mut() {
+      entry.set_rec_len(entry.get_real_rec_len());
+      used += entry.get_rec_len();
+    }
+    let last = entries.last_mut().unwrap();
+    last.set_rec_len(last.get_rec_len() + space - used);
+    entries
+  }
+
+  // 在目录末尾追加一个块，更新目录的大小和块数并写回inode，返回新块的逻辑块号和物理块号
+  fn append_block(&mut self) -> Result<(u32, u64), Error<IO::Error>> {
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let lblock = self.inode.get_size() / block_size;
+    if lblock >= u32::MAX as u64 {
+      return Err(Error::NotEnoughSpace);
+    }
+    // 尽量接在目录最后一个块后面
+    let goal = match self.get_dir_extents()?.last() {
+      Some(last) => last.get_block_loc() + last.get_len() as u64,
+      None => self.fs.get_inode_goal(self.ino),
+    };
+    let pblock = self.fs.alloc_block(goal)?;
+    trace!("Dir::append_block: lblock: {}, pblock: {}", lblock, pblock);
+    self
+      .fs
+      .map_blocks(self.ino, &mut self.inode, Extent::new(lblock as u32, 1, pblock))?;
+    let blocks_count = self.inode.get_blocks_count() + block_size / Inode::INODE_BLOCK_SIZE as u64;
+    self.inode.set_blocks_count(blocks_count);
+    self.inode.set_size((lblock + 1) * block_size);
+    self.fs.write_inode(self.ino, &mut self.inode)?;
+    Ok((lblock as u32, pblock))
+  }
+
+  // 找到name对应的entry，返回它所在的物理块、块里的所有entry和它在块里的下标
+  fn find_dirblock_entry(&self, name: &str) -> Result<DirBlockEntry, Error<IO::Error>> {
+    let mut blocks = self.get_blocks()?;
+    // "."和".."只会在第一个块里，索引里也没有它们
+    if name == "." || name == ".." {
+      blocks.truncate(1);
+    } else if self.is_indexed() {
+      if let Some(found) = self.dx_find_dirblock_entry(name)? {
+        return Ok(found);
+      }
+    }
+    for pblock in blocks {
+      let entries = self.read_dirblock(pblock)?;
+      if let Some(idx) = entries
+        .iter()
+        .position(|e| e.get_inode() != 0 && e.get_name_str() == name)
+      {
+        return Ok((pblock, entries, idx));
+      }
+    }
+    Err(Error::NotFound)
+  }
+
+  // 删除name对应的entry，它的空间合并到前一个entry里，块里的第一个entry只把inode清零
+  // 不修改链接数，返回被删除的entry
+  fn remove_entry(&mut self, name: &str) -> Result<DirEntryData, Error<IO::Error>> {
+    trace!("Dir::remove_entry name: {}", name);
+    let (pblock, mut entries, idx) = self.find_dirblock_entry(name)?;
+    let removed = entries[idx];
+    if idx > 0 {
+      let rec_len = entries[idx].get_rec_len();
+      let prev = &mut entries[idx - 1];
+      prev.set_rec_len(prev.get_rec_len() + rec_len);
+      entries.remove(idx);
+    } else {
+      entries[0].set_inode(0);
+    }
+    self.write_dirblock(pblock, &entries)?;
+    Ok(removed)
+  }
+
+  // 让name对应的entry指向另一个inode
+  fn set_entry_inode(
+    &mut self,
+    name: &str,
+    ino: u32,
+    file_type: Option<DirEntryFileType>,
+  ) -> Result<(), Error<IO::Error>> {
+    trace!("Dir::set_entry_inode name: {}, ino: {}", name, ino);
+    if name == ".." && self.is_indexed() {
+      return self.dx_set_dotdot(ino, file_type);
+    }
+    let (pblock, mut entries, idx) = self.find_dirblock_entry(name)?;
+    entries[idx].set_inode(ino);
+    entries[idx].set_file_type(file_type);
+    self.write_dirblock(pblock, &entries)
+  }
+
+  // 把entry写回目录块，开启metadata_csum时在块末尾写入tail
+  fn write_dirblock(&self, pblock: u64, entries: &[DirEntryData]) -> Result<(), Error<IO::Error>> {
+    let super_block = self.fs.super_block.borrow();
+    let block_size = super_block.get_block_size();
+    let mut disk = self.fs.disk.borrow_mut();
+    disk.seek(SeekFrom::Start(pblock * block_size))?;
+    for entry in entries {
+      entry.serialize(&mut *disk)?;
+    }
+    if super_block.has_feature_ro_compat_metadata_csum() {
+      let csum = DirEntryData::compute_dirblock_checksum(
+        entries,
+        block_size,
+        &super_block.uuid,
+        self.ino as u32,
+        self.inode.generation,
+      );
+      let tail_entry = DirEntryData::DirEntryTail(DirEntryTail {
+        reserved_zero1: 0,
+        rec_len: 12,
+        reserved_zero2: 0,
+        reserved_ft: 0xDE,
+        checksum: csum,
+      });
+      tail_entry.serialize(&mut *disk)?;
+    }
+    Ok(())
+  }
+
+  // 链接数为0的inode：释放它占用的块和扩展属性块，记录删除时间，再释放inode本身
+  fn release_inode(&self, ino: u64, inode: &mut Inode, time: u32) -> Result<(), Error<IO::Error>> {
+    trace!("Dir::release_inode ino: {}", ino);
+    if inode.use_extents() || inode.use_block_map() {
+      self.fs.unmap_blocks(ino, inode, 0, u32::MAX as u64 + 1)?;
+    }
+    self.fs.release_xattr_block(inode)?;
+    inode.set_size(0);
+    inode.dtime = time;
+    self.fs.write_inode(ino, inode)?;
+    self.fs.free_inode(ino, inode.is_dir())
   }
 }
@@ -1,6 +1,8 @@
 use crate::dir::Dir;
+use crate::error::Error;
 use crate::file::File;
 use crate::fs::FileSystem;
+use crate::inode::InodeFileType;
 use crate::io::{Read, ReadLeExt, ReadWriteSeek, Seek, Write, WriteLeExt};
 use crate::utils::crc::crc32c;
 use bitflags::bitflags;
@@ -19,6 +21,21 @@ bitflags! {
   }
 }
 
+impl From<InodeFileType> for DirEntryFileType {
+  fn from(file_type: InodeFileType) -> Self {
+    match file_type {
+      InodeFileType::REG => DirEntryFileType::REG_FILE,
+      InodeFileType::DIR => DirEntryFileType::DIR,
+      InodeFileType::CHR => DirEntryFileType::CHRDEV,
+      InodeFileType::BLK => DirEntryFileType::BLKDEV,
+      InodeFileType::FIFO => DirEntryFileType::FIFO,
+      InodeFileType::SOCK => DirEntryFileType::SOCK,
+      InodeFileType::LNK => DirEntryFileType::SYMLINK,
+      _ => DirEntryFileType::UNKNOWN,
+    }
+  }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct DirEntry1 {
@@ -48,6 +65,27 @@ pub struct DirEntryTail {
   pub checksum: u32,
 }
 
+impl DirEntryTail {
+  // 按磁盘上的原始内容计算目录块的校验和，tail之前的所有字节(包括entry之间的填充)都参与计算
+  pub fn compute_checksum(block: &[u8], uuid: &[u8], ino: u32, ino_gen: u32) -> u32 {
+    let len = block.len() - core::mem::size_of::<Self>();
+    let mut csum = crc32c(!0, uuid, uuid.len() as u32);
+    csum = crc32c(csum, &ino.to_le_bytes(), 4);
+    csum = crc32c(csum, &ino_gen.to_le_bytes(), 4);
+    crc32c(csum, &block[..len], len as u32)
+  }
+
+  // 块的末尾是否是一个合法的tail
+  pub fn is_present(block: &[u8]) -> bool {
+    let tail = &block[block.len() - core::mem::size_of::<Self>()..];
+    tail[0..4] == [0; 4] && tail[4..6] == 12u16.to_le_bytes() && tail[6] == 0 && tail[7] == 0xDE
+  }
+
+  pub fn get_checksum(block: &[u8]) -> u32 {
+    u32::from_le_bytes(block[block.len() - 4..].try_into().unwrap())
+  }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum DirEntryData {
   DirEntry1(DirEntry1),
@@ -66,8 +104,8 @@ impl DirEntryData {
     let rec_len = reader.read_u16_le()?;
     assert!(max_size >= rec_len as usize);
 
-    // dir entry tail
-    if inode == 0 && rec_len == 12 {
+    // dir entry tail，只会在块的末尾；块开头被删除的短entry也可能是inode为0、rec_len为12
+    if inode == 0 && rec_len == 12 && max_size == 12 {
       let reserved_zero2 = reader.read_u8()?;
       let reserved_ft = reader.read_u8()?;
       // TODO: use error
@@ -120,7 +158,7 @@ impl DirEntryData {
         writer.write_u16_le(entry.rec_len)?;
         writer.write_u16_le(entry.name_len)?;
         writer.write_all(&entry.name[0..entry.name_len as usize])?;
-        let padding = entry.rec_len - entry.name_len as u16 - 8;
+        let padding = entry.rec_len - entry.name_len - 8;
         writer.write_all(&vec![0u8; padding as usize])?;
       }
       DirEntryData::DirEntry2(entry) => {
@@ -144,10 +182,33 @@ impl DirEntryData {
     Ok(())
   }
 
+  // 和serialize一样，但是写到内存里的buf中
+  pub fn serialize_to_slice(&self, buf: &mut [u8]) {
+    let rec_len = self.get_rec_len() as usize;
+    buf[..rec_len].fill(0);
+    buf[0..4].copy_from_slice(&self.get_inode().to_le_bytes());
+    buf[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    match self {
+      DirEntryData::DirEntry1(entry) => {
+        buf[6..8].copy_from_slice(&entry.name_len.to_le_bytes());
+      }
+      DirEntryData::DirEntry2(entry) => {
+        buf[6] = entry.name_len;
+        buf[7] = entry.file_type;
+      }
+      DirEntryData::DirEntryTail(entry) => {
+        buf[7] = entry.reserved_ft;
+        buf[8..12].copy_from_slice(&entry.checksum.to_le_bytes());
+      }
+    }
+    let name = self.get_name();
+    buf[8..8 + name.len()].copy_from_slice(name);
+  }
+
   pub fn new(ino: u32, name: &str, file_type: Option<DirEntryFileType>, feature_incompat_filetype: bool) -> Self {
     if feature_incompat_filetype {
       let name_len = name.len();
-      let rec_len = (8 + name_len + 3) / 4 * 4;
+      let rec_len = (8 + name_len).div_ceil(4) * 4;
       let mut name_bytes = [0u8; 255];
       name_bytes[..name_len].copy_from_slice(name.as_bytes());
       let entry = DirEntry2 {
@@ -160,7 +221,7 @@ impl DirEntryData {
       DirEntryData::DirEntry2(entry)
     } else {
       let name_len = name.len();
-      let rec_len = (8 + name_len + 3) / 4 * 4;
+      let rec_len = (8 + name_len).div_ceil(4) * 4;
       let mut name_bytes = [0u8; 255];
       name_bytes[..name_len].copy_from_slice(name.as_bytes());
       let entry = DirEntry1 {
@@ -242,6 +303,28 @@ impl DirEntryData {
     }
   }
 
+  pub fn set_inode(&mut self, ino: u32) {
+    match self {
+      DirEntryData::DirEntry1(entry) => entry.inode = ino,
+      DirEntryData::DirEntry2(entry) => entry.inode = ino,
+      DirEntryData::DirEntryTail(_) => unreachable!(),
+    }
+  }
+
+  // 没有开启filetype特性时返回None
+  pub fn get_file_type(&self) -> Option<DirEntryFileType> {
+    match self {
+      DirEntryData::DirEntry2(entry) => Some(DirEntryFileType::from_bits_truncate(entry.file_type)),
+      _ => None,
+    }
+  }
+
+  pub fn set_file_type(&mut self, file_type: Option<DirEntryFileType>) {
+    if let DirEntryData::DirEntry2(entry) = self {
+      entry.file_type = file_type.unwrap_or(DirEntryFileType::UNKNOWN).bits();
+    }
+  }
+
   pub fn get_rec_len(&self) -> u16 {
     match self {
       DirEntryData::DirEntry1(entry) => entry.rec_len,
@@ -265,19 +348,23 @@ impl DirEntryData {
     }
   }
 
-  pub fn get_name_str(&self) -> String {
-    let name = match self {
+  // 磁盘上的原始文件名，计算htree散列时要用它而不是get_name_str
+  pub fn get_name(&self) -> &[u8] {
+    match self {
       DirEntryData::DirEntry1(entry) => &entry.name[0..entry.name_len as usize],
       DirEntryData::DirEntry2(entry) => &entry.name[0..entry.name_len as usize],
       DirEntryData::DirEntryTail(_) => &[],
-    };
-    String::from_utf8_lossy(name).to_string()
+    }
+  }
+
+  pub fn get_name_str(&self) -> String {
+    String::from_utf8_lossy(self.get_name()).to_string()
   }
 
   pub fn get_real_rec_len(&self) -> u16 {
     match self {
-      DirEntryData::DirEntry1(entry) => (entry.name_len as u16 + 8 + 3) / 4 * 4,
-      DirEntryData::DirEntry2(entry) => (entry.name_len as u16 + 8 + 3) / 4 * 4,
+      DirEntryData::DirEntry1(entry) => (entry.name_len + 8).div_ceil(4) * 4,
+      DirEntryData::DirEntry2(entry) => (entry.name_len as u16 + 8).div_ceil(4) * 4,
       DirEntryData::DirEntryTail(entry) => entry.rec_len,
     }
   }
@@ -338,17 +425,23 @@ pub struct DirEntry<'a, IO: ReadWriteSeek> {
 }
 
 impl<'a, IO: ReadWriteSeek> DirEntry<'a, IO> {
-  pub fn to_dir(&self) -> Dir<'a, IO> {
-    let ino = self.data.get_inode();
-    let inode = self.fs.get_inode(ino as u64).unwrap();
-    assert!(inode.is_dir(), "only support dir");
-    Dir::new(ino as u64, inode, self.fs)
+  // entry指向的不是目录时返回InvalidInput
+  pub fn to_dir(&self) -> Result<Dir<'a, IO>, Error<IO::Error>> {
+    let ino = self.data.get_inode() as u64;
+    let inode = self.fs.get_inode(ino)?;
+    if !inode.is_dir() {
+      return Err(Error::InvalidInput);
+    }
+    Ok(Dir::new(ino, inode, self.fs))
   }
 
-  pub fn to_file(&self) -> File<'a, IO> {
-    let ino = self.data.get_inode();
-    let inode = self.fs.get_inode(ino as u64).unwrap();
-    assert!(inode.is_file(), "only support file");
-    File::new(ino as u64, inode, self.fs)
+  // entry指向的不是普通文件时返回InvalidInput
+  pub fn to_file(&self) -> Result<File<'a, IO>, Error<IO::Error>> {
+    let ino = self.data.get_inode() as u64;
+    let inode = self.fs.get_inode(ino)?;
+    if !inode.is_file() {
+      return Err(Error::InvalidInput);
+    }
+    Ok(File::new(ino, inode, self.fs))
   }
 }
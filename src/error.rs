@@ -26,6 +26,10 @@ pub enum Error<T> {
   InvalidFileNameLength,
   /// The provided file name contains an invalid character.
   UnsupportedFileNameCharacter,
+  /// Too many symbolic links were encountered while resolving a path.
+  TooManySymlinks,
+  /// The file system uses a feature that is not supported, or its journal needs recovery.
+  Unsupported,
 }
 
 impl<T: IoError> From<T> for Error<T> {
@@ -44,10 +48,12 @@ impl From<Error<std::io::Error>> for std::io::Error {
       Error::InvalidInput
       | Error::InvalidFileNameLength
       | Error::UnsupportedFileNameCharacter
-      | Error::DirectoryIsNotEmpty => Self::new(std::io::ErrorKind::InvalidInput, error),
+      | Error::DirectoryIsNotEmpty
+      | Error::TooManySymlinks => Self::new(std::io::ErrorKind::InvalidInput, error),
       Error::NotFound => Self::new(std::io::ErrorKind::NotFound, error),
       Error::AlreadyExists => Self::new(std::io::ErrorKind::AlreadyExists, error),
       Error::CorruptedFileSystem => Self::new(std::io::ErrorKind::InvalidData, error),
+      Error::Unsupported => Self::new(std::io::ErrorKind::Unsupported, error),
     }
   }
 }
@@ -66,6 +72,8 @@ impl<T: core::fmt::Display> core::fmt::Display for Error<T> {
       Error::NotFound => write!(f, "No such file or directory"),
       Error::AlreadyExists => write!(f, "File or directory already exists"),
       Error::CorruptedFileSystem => write!(f, "Corrupted file system"),
+      Error::TooManySymlinks => write!(f, "Too many levels of symbolic links"),
+      Error::Unsupported => write!(f, "Unsupported file system feature"),
     }
   }
 }
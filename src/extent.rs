@@ -1,6 +1,12 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
 use crate::dir_entry::DirEntryData;
-use crate::io::{Read, Seek, SeekFrom, Write};
-use crate::utils::combine_u64;
+use crate::error::Error;
+use crate::fs::FileSystem;
+use crate::inode::Inode;
+use crate::io::{Read, ReadWriteSeek, Seek, SeekFrom, Write};
+use crate::utils::{combine_u64, crc::crc32c};
 
 // 12 bytes
 #[repr(C)]
@@ -14,6 +20,10 @@ pub struct ExtentHeader {
 }
 
 impl ExtentHeader {
+  pub const MAGIC: u16 = 0xF30A;
+  // extent tree的最大深度
+  pub const MAX_DEPTH: u16 = 5;
+
   pub fn deserialize<R: Read>(reader: &mut R) -> Result<Self, R::Error> {
     let mut buffer = [0u8; core::mem::size_of::<Self>()];
     reader.read_exact(&mut buffer)?;
@@ -45,7 +55,11 @@ impl ExtentHeader {
   }
 
   pub fn set_magic(&mut self) {
-    self.magic = 0xF30A;
+    self.magic = Self::MAGIC;
+  }
+
+  pub fn is_valid(&self) -> bool {
+    self.magic == Self::MAGIC && self.entries <= self.max && self.depth <= Self::MAX_DEPTH
   }
 }
 
@@ -53,13 +67,21 @@ impl ExtentHeader {
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct ExtentIdx {
-  block: u32,   // 逻辑块号
-  leaf_lo: u32, // 叶子节点低32位
-  leaf_hi: u16, // 叶子节点高16位
-  unused: u16,  // 未使用
+  pub block: u32, // 逻辑块号
+  leaf_lo: u32,   // 叶子节点低32位
+  leaf_hi: u16,   // 叶子节点高16位
+  unused: u16,    // 未使用
 }
 
 impl ExtentIdx {
+  pub fn new(block: u32, leaf: u64) -> Self {
+    Self {
+      block,
+      leaf_lo: (leaf & 0xFFFFFFFF) as u32,
+      leaf_hi: (leaf >> 32 & 0xFFFF) as u16,
+      unused: 0,
+    }
+  }
   pub fn get_extent_idx(&self) -> u64 {
     combine_u64(self.leaf_lo, self.leaf_hi as u32)
   }
@@ -85,12 +107,17 @@ impl ExtentIdx {
 #[derive(Debug, Copy, Clone)]
 pub struct Extent {
   pub block: u32,    // 逻辑块号
-  pub len: u16,      // 逻辑块数
+  pub len: u16,      // 逻辑块数，大于32768时表示unwritten extent，用get_len获取真实长度
   pub start_hi: u16, // 物理块号高16位
   pub start_lo: u32, // 物理块号低32位
 }
 
 impl Extent {
+  // 一个extent最多包含的块数
+  pub const MAX_LEN: u16 = 32768;
+  // 一个unwritten extent最多包含的块数
+  pub const MAX_UNWRITTEN_LEN: u16 = 32767;
+
   pub fn new(block: u32, len: u16, start: u64) -> Self {
     Self {
       block,
@@ -99,9 +126,111 @@ impl Extent {
       start_lo: (start & 0xFFFFFFFF) as u32,
     }
   }
+
+  // 已经分配了物理块但还没有写入数据的extent，读出来都是0
+  pub fn new_unwritten(block: u32, len: u16, start: u64) -> Self {
+    assert!(len <= Self::MAX_UNWRITTEN_LEN);
+    Self::new(block, len + Self::MAX_LEN, start)
+  }
+
   pub fn get_block_loc(&self) -> u64 {
     combine_u64(self.start_lo, self.start_hi as u32)
   }
+
+  pub fn is_unwritten(&self) -> bool {
+    self.len > Self::MAX_LEN
+  }
+
+  pub fn get_len(&self) -> u16 {
+    if self.is_unwritten() {
+      self.len - Self::MAX_LEN
+    } else {
+      self.len
+    }
+  }
+
+  // 保持unwritten状态不变
+  pub fn set_len(&mut self, len: u16) {
+    if self.is_unwritten() {
+      assert!(len <= Self::MAX_UNWRITTEN_LEN);
+      self.len = len + Self::MAX_LEN;
+    } else {
+      assert!(len <= Self::MAX_LEN);
+      self.len = len;
+    }
+  }
+
+  pub fn mark_written(&mut self) {
+    self.len = self.get_len();
+  }
+
+  // 最后一个逻辑块的下一个块
+  pub fn get_end_block(&self) -> u64 {
+    self.block as u64 + self.get_len() as u64
+  }
+
+  // 逻辑块和物理块都紧接在self后面且状态相同时，next可以合并进self
+  pub fn can_merge(&self, next: &Extent) -> bool {
+    let max_len = if self.is_unwritten() {
+      Extent::MAX_UNWRITTEN_LEN
+    } else {
+      Extent::MAX_LEN
+    };
+    self.is_unwritten() == next.is_unwritten()
+      && self.get_end_block() == next.block as u64
+      && self.get_block_loc() + self.get_len() as u64 == next.get_block_loc()
+      && self.get_len() as u32 + next.get_len() as u32 <= max_len as u32
+  }
+
+  // 在按逻辑块号排好序的extents里查找包含lblock的extent
+  // 找不到时lblock位于空洞中，返回下一个extent的起始逻辑块号
+  pub fn lookup(extents: &[Extent], lblock: u64) -> Result<&Extent, Option<u64>> {
+    let pos = extents.partition_point(|e| e.get_end_block() <= lblock);
+    match extents.get(pos) {
+      Some(extent) if extent.block as u64 <= lblock => Ok(extent),
+      Some(extent) => Err(Some(extent.block as u64)),
+      None => Err(None),
+    }
+  }
+
+  // 逻辑块lblock对应的物理块
+  pub fn map_block(&self, lblock: u64) -> Option<u64> {
+    if lblock >= self.block as u64 && lblock < self.get_end_block() {
+      Some(self.get_block_loc() + lblock - self.block as u64)
+    } else {
+      None
+    }
+  }
+
+  // 把[lblock, lblock + len)这一段从extent中切出来，返回切出来的这一段和它前后剩下的部分
+  pub fn split(&self, lblock: u64, len: u64) -> (Option<Extent>, Extent, Option<Extent>) {
+    assert!(lblock >= self.block as u64 && lblock + len <= self.get_end_block());
+    let piece = |start: u64, end: u64| {
+      let mut extent = *self;
+      extent.block = start as u32;
+      extent.set_len((end - start) as u16);
+      let pstart = self.get_block_loc() + start - self.block as u64;
+      extent.start_hi = (pstart >> 32 & 0xFFFF) as u16;
+      extent.start_lo = (pstart & 0xFFFFFFFF) as u32;
+      extent
+    };
+    let before = (lblock > self.block as u64).then(|| piece(self.block as u64, lblock));
+    let after = (lblock + len < self.get_end_block()).then(|| piece(lblock + len, self.get_end_block()));
+    (before, piece(lblock, lblock + len), after)
+  }
+
+  // 合并相邻的可以合并的extent
+  pub fn merge_adjacent(extents: &mut Vec<Extent>) {
+    let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+    for extent in extents.drain(..) {
+      match merged.last_mut() {
+        Some(prev) if prev.can_merge(&extent) => prev.set_len(prev.get_len() + extent.get_len()),
+        _ => merged.push(extent),
+      }
+    }
+    *extents = merged;
+  }
+
   pub fn load_from_u8(data: &[u8]) -> Self {
     unsafe { core::ptr::read(data.as_ptr() as *const _) }
   }
@@ -123,13 +252,13 @@ impl Extent {
     offset: u64,
   ) -> Result<Option<DirEntryData>, R::Error> {
     let pos = self.get_block_loc() * block_size;
-    let size = self.len as u64 * block_size;
+    let size = self.get_len() as u64 * block_size;
     assert!(size >= offset);
     reader.seek(SeekFrom::Start(pos + offset))?;
     // FIXME: 是否可能会出现一个entry跨越两个extent的情况？
     let max_size = size - offset;
     let dir_entry_data = DirEntryData::deserialize(reader, feature_incompat_filetype, max_size as usize).unwrap();
-    return Ok(Some(dir_entry_data));
+    Ok(Some(dir_entry_data))
   }
 
   pub fn read_bytes<R: Read + Seek>(
@@ -164,3 +293,448 @@ impl Extent {
 pub struct ExtentTail {
   checksum: u32, // 校验和
 }
+
+impl ExtentTail {
+  // tail紧跟在节点的最后一个条目(按max计算)之后
+  pub fn offset(header: &ExtentHeader) -> usize {
+    core::mem::size_of::<ExtentHeader>() + header.max as usize * core::mem::size_of::<Extent>()
+  }
+
+  pub fn compute_checksum(block: &[u8], uuid: &[u8], ino: u32, ino_gen: u32) -> u32 {
+    let header = ExtentHeader::load_from_u8(block);
+    let mut csum = crc32c(!0, uuid, uuid.len() as u32);
+    csum = crc32c(csum, &ino.to_le_bytes(), 4);
+    csum = crc32c(csum, &ino_gen.to_le_bytes(), 4);
+    csum = crc32c(csum, block, Self::offset(&header) as u32);
+    csum
+  }
+
+  pub fn get_checksum(block: &[u8]) -> u32 {
+    let offset = Self::offset(&ExtentHeader::load_from_u8(block));
+    u32::from_le_bytes(block[offset..offset + core::mem::size_of::<Self>()].try_into().unwrap())
+  }
+
+  pub fn verify_checksum(block: &[u8], uuid: &[u8], ino: u32, ino_gen: u32) -> bool {
+    Self::get_checksum(block) == Self::compute_checksum(block, uuid, ino, ino_gen)
+  }
+
+  pub fn set_checksum(block: &mut [u8], uuid: &[u8], ino: u32, ino_gen: u32) {
+    let csum = Self::compute_checksum(block, uuid, ino, ino_gen);
+    let offset = Self::offset(&ExtentHeader::load_from_u8(block));
+    block[offset..offset + core::mem::size_of::<Self>()].copy_from_slice(&csum.to_le_bytes());
+  }
+}
+
+// extent tree中的一个节点
+// 根节点保存在inode.block里，其余节点各占一个块
+#[derive(Debug)]
+pub(crate) struct ExtentNode {
+  pub pblock: Option<u64>, // 节点所在的物理块，根节点为None
+  pub depth: u16,
+  pub max: u16,
+  pub generation: u32,         // header里的eh_generation，重写节点时原样写回
+  pub extents: Vec<Extent>,    // 叶子节点的条目
+  pub indexes: Vec<ExtentIdx>, // 索引节点的条目
+}
+
+impl ExtentNode {
+  // data是inode.block(60字节，最多4个条目)或者一个完整的块，max不能超出data能放下的条目数，
+  // 否则按max计算的ExtentTail位置会越界
+  pub fn parse(data: &[u8], pblock: Option<u64>) -> Option<Self> {
+    let header = ExtentHeader::load_from_u8(data);
+    let max_entries = (data.len() - core::mem::size_of::<ExtentHeader>()) / core::mem::size_of::<Extent>();
+    if !header.is_valid() || header.max as usize > max_entries {
+      return None;
+    }
+    let mut node = Self {
+      pblock,
+      depth: header.depth,
+      max: header.max,
+      generation: header.generation,
+      extents: Vec::new(),
+      indexes: Vec::new(),
+    };
+    let mut offset = core::mem::size_of::<ExtentHeader>();
+    for _ in 0..header.entries {
+      if header.is_leaf() {
+        node.extents.push(Extent::load_from_u8(&data[offset..]));
+      } else {
+        node.indexes.push(ExtentIdx::load_from_u8(&data[offset..]));
+      }
+      offset += core::mem::size_of::<Extent>();
+    }
+    Some(node)
+  }
+
+  fn serialize_into(&self, data: &mut [u8]) {
+    let header = ExtentHeader {
+      magic: ExtentHeader::MAGIC,
+      entries: self.len() as u16,
+      max: self.max,
+      depth: self.depth,
+      generation: self.generation,
+    };
+    let mut offset = core::mem::size_of::<ExtentHeader>();
+    unsafe {
+      core::ptr::write_unaligned(data.as_mut_ptr() as *mut ExtentHeader, header);
+      for extent in self.extents.iter() {
+        core::ptr::write_unaligned(data[offset..].as_mut_ptr() as *mut Extent, *extent);
+        offset += core::mem::size_of::<Extent>();
+      }
+      for idx in self.indexes.iter() {
+        core::ptr::write_unaligned(data[offset..].as_mut_ptr() as *mut ExtentIdx, *idx);
+        offset += core::mem::size_of::<ExtentIdx>();
+      }
+    }
+    // 剩余的条目清零
+    let end = core::mem::size_of::<ExtentHeader>() + self.max as usize * core::mem::size_of::<Extent>();
+    data[offset..end].fill(0);
+  }
+
+  fn is_leaf(&self) -> bool {
+    self.depth == 0
+  }
+
+  fn len(&self) -> usize {
+    if self.is_leaf() {
+      self.extents.len()
+    } else {
+      self.indexes.len()
+    }
+  }
+
+  // 节点中第一个条目的逻辑块号
+  fn first_block(&self) -> u32 {
+    if self.is_leaf() {
+      self.extents.first().map_or(0, |e| e.block)
+    } else {
+      self.indexes.first().map_or(0, |idx| idx.block)
+    }
+  }
+
+  // 把从at开始的条目移到新节点
+  fn split_off(&mut self, at: usize, pblock: u64) -> Self {
+    Self {
+      pblock: Some(pblock),
+      depth: self.depth,
+      max: self.max,
+      generation: self.generation,
+      extents: if self.is_leaf() {
+        self.extents.split_off(at)
+      } else {
+        Vec::new()
+      },
+      indexes: if self.is_leaf() {
+        Vec::new()
+      } else {
+        self.indexes.split_off(at)
+      },
+    }
+  }
+}
+
+pub struct ExtentTree<'a, 'b, IO: ReadWriteSeek> {
+  pub fs: &'a FileSystem<IO>,
+  pub ino: u64,
+  pub inode: &'b mut Inode,
+}
+
+impl<'a, 'b, IO: ReadWriteSeek> ExtentTree<'a, 'b, IO> {
+  pub fn new(fs: &'a FileSystem<IO>, ino: u64, inode: &'b mut Inode) -> Self {
+    Self { fs, ino, inode }
+  }
+
+  pub fn get_extents(&self) -> Result<Vec<Extent>, Error<IO::Error>> {
+    let mut disk = self.fs.disk.borrow_mut();
+    self
+      .inode
+      .get_extents(self.ino, &mut *disk, &self.fs.super_block.borrow())
+  }
+
+  // 插入一个extent，能和相邻的extent合并时直接合并
+  // 节点满了就分裂，根节点满了就把根节点的内容移到新分配的块里并增加树的深度
+  // 调用者负责把inode写回disk
+  pub fn insert(&mut self, extent: Extent) -> Result<(), Error<IO::Error>> {
+    trace!("ExtentTree::insert ino: {}, extent: {:?}", self.ino, extent);
+    self.modify_leaf(extent.block as u64, |extents| {
+      let pos = extents.partition_point(|e| e.block < extent.block);
+      extents.insert(pos, extent);
+      Ok(())
+    })
+  }
+
+  // 把unwritten extent中的[lblock, lblock + len)转换为已写入状态，必要时把extent拆成几段
+  // 这一段必须位于同一个extent内
+  pub fn mark_written(&mut self, lblock: u64, len: u64) -> Result<(), Error<IO::Error>> {
+    trace!(
+      "ExtentTree::mark_written ino: {}, lblock: {}, len: {}",
+      self.ino,
+      lblock,
+      len
+    );
+    self.modify_leaf(lblock, |extents| {
+      let pos = extents.partition_point(|e| e.get_end_block() <= lblock);
+      let extent = match extents.get(pos) {
+        Some(extent) if extent.block as u64 <= lblock && lblock + len <= extent.get_end_block() => *extent,
+        _ => return Err(Error::InvalidInput),
+      };
+      if !extent.is_unwritten() {
+        return Ok(());
+      }
+      let (before, mut written, after) = extent.split(lblock, len);
+      written.mark_written();
+      extents.splice(pos..pos + 1, before.into_iter().chain([written]).chain(after));
+      Ok(())
+    })
+  }
+
+  // 删除[lblock, lblock + len)范围内的映射并释放对应的数据块，返回释放的数据块数
+  // 变空的非根节点会被释放，根节点只剩一个放得下的子节点时会降低树的深度
+  // 调用者负责把inode写回disk
+  pub fn remove(&mut self, lblock: u64, len: u64) -> Result<u64, Error<IO::Error>> {
+    trace!("ExtentTree::remove ino: {}, lblock: {}, len: {}", self.ino, lblock, len);
+    let mut root = self.load_root()?;
+    let mut removed = Vec::new();
+    self.remove_node(&mut root, lblock, lblock + len, &mut removed)?;
+    if root.len() > root.max as usize {
+      // 从一个extent中间挖掉一段时条目数会加一
+      self.grow_in_depth(&mut root)?;
+    }
+    self.shrink_in_depth(&mut root)?;
+    self.store_node(&root)?;
+
+    let mut freed = 0;
+    for extent in removed {
+      self.release_blocks(extent.get_block_loc(), extent.get_len() as u64)?;
+      freed += extent.get_len() as u64;
+    }
+    Ok(freed)
+  }
+
+  // 删除node下[start, end)范围内的映射，删掉的部分放进removed，返回分裂出来的右兄弟节点
+  fn remove_node(
+    &mut self,
+    node: &mut ExtentNode,
+    start: u64,
+    end: u64,
+    removed: &mut Vec<Extent>,
+  ) -> Result<Option<ExtentNode>, Error<IO::Error>> {
+    if node.is_leaf() {
+      let mut extents = Vec::with_capacity(node.extents.len() + 1);
+      for extent in node.extents.drain(..) {
+        let from = start.max(extent.block as u64);
+        let to = end.min(extent.get_end_block());
+        if from >= to {
+          extents.push(extent);
+          continue;
+        }
+        let (before, middle, after) = extent.split(from, to - from);
+        extents.extend(before);
+        removed.push(middle);
+        extents.extend(after);
+      }
+      node.extents = extents;
+    } else {
+      let mut i = 0;
+      while i < node.indexes.len() {
+        // 第i个子节点负责[indexes[i].block, indexes[i + 1].block)
+        let next = node.indexes.get(i + 1).map_or(u64::MAX, |idx| idx.block as u64);
+        if i > 0 && node.indexes[i].block as u64 >= end {
+          break;
+        }
+        if next <= start {
+          i += 1;
+          continue;
+        }
+        let pblock = node.indexes[i].get_extent_idx();
+        let mut child = self.load_node(pblock, node.depth - 1)?;
+        let sibling = self.remove_node(&mut child, start, end, removed)?;
+        if child.len() == 0 {
+          // 子节点空了，释放它所在的块
+          self.release_blocks(pblock, 1)?;
+          node.indexes.remove(i);
+          continue;
+        }
+        node.indexes[i].block = child.first_block();
+        self.store_node(&child)?;
+        if let Some(sibling) = sibling {
+          let idx = ExtentIdx::new(sibling.first_block(), sibling.pblock.unwrap());
+          node.indexes.insert(i + 1, idx);
+          i += 1;
+        }
+        i += 1;
+      }
+    }
+    self.split_if_full(node, start)
+  }
+
+  // 找到lblock所在的叶子节点，用f修改它的extent列表，之后合并相邻的extent并按需分裂节点
+  fn modify_leaf<F>(&mut self, lblock: u64, mut f: F) -> Result<(), Error<IO::Error>>
+  where
+    F: FnMut(&mut Vec<Extent>) -> Result<(), Error<IO::Error>>,
+  {
+    let mut root = self.load_root()?;
+    self.modify_node(&mut root, lblock, &mut f)?;
+    if root.len() > root.max as usize {
+      self.grow_in_depth(&mut root)?;
+    }
+    self.store_node(&root)
+  }
+
+  // 返回分裂出来的右兄弟节点
+  fn modify_node<F>(
+    &mut self,
+    node: &mut ExtentNode,
+    lblock: u64,
+    f: &mut F,
+  ) -> Result<Option<ExtentNode>, Error<IO::Error>>
+  where
+    F: FnMut(&mut Vec<Extent>) -> Result<(), Error<IO::Error>>,
+  {
+    if node.is_leaf() {
+      f(&mut node.extents)?;
+      Extent::merge_adjacent(&mut node.extents);
+    } else {
+      let i = node
+        .indexes
+        .partition_point(|idx| idx.block as u64 <= lblock)
+        .saturating_sub(1);
+      let mut child = self.load_node(node.indexes[i].get_extent_idx(), node.depth - 1)?;
+      let sibling = self.modify_node(&mut child, lblock, f)?;
+      node.indexes[i].block = child.first_block();
+      self.store_node(&child)?;
+      if let Some(sibling) = sibling {
+        let idx = ExtentIdx::new(sibling.first_block(), sibling.pblock.unwrap());
+        node.indexes.insert(i + 1, idx);
+      }
+    }
+
+    self.split_if_full(node, lblock)
+  }
+
+  // 非根节点的条目超出上限时分裂出一个新节点并返回它
+  fn split_if_full(&mut self, node: &mut ExtentNode, lblock: u64) -> Result<Option<ExtentNode>, Error<IO::Error>> {
+    if node.pblock.is_none() || node.len() <= node.max as usize {
+      return Ok(None);
+    }
+    // 在末尾追加时只移走最后一个条目，让前面的节点保持满的状态
+    let at = if node.is_leaf() && node.extents.last().unwrap().block as u64 <= lblock {
+      node.len() - 1
+    } else {
+      node.len() / 2
+    };
+    let pblock = self.alloc_node_block()?;
+    let sibling = node.split_off(at, pblock);
+    trace!(
+      "ExtentTree::modify_node: split node {:?} at {}, new node: {}",
+      node.pblock,
+      at,
+      pblock
+    );
+    self.store_node(&sibling)?;
+    Ok(Some(sibling))
+  }
+
+  // 把根节点的内容移到一个新的块里，根节点只保留一个指向它的索引
+  fn grow_in_depth(&mut self, root: &mut ExtentNode) -> Result<(), Error<IO::Error>> {
+    let pblock = self.alloc_node_block()?;
+    let mut child = root.split_off(0, pblock);
+    child.max = self.node_max_entries();
+    trace!("ExtentTree::grow_in_depth: depth {} -> {}", root.depth, root.depth + 1);
+    self.store_node(&child)?;
+    root.extents.clear();
+    root.depth += 1;
+    root.indexes = vec![ExtentIdx::new(child.first_block(), pblock)];
+    Ok(())
+  }
+
+  // 根节点只有一个子节点并且子节点的条目放得进根节点时，把子节点的内容移回根节点
+  fn shrink_in_depth(&mut self, root: &mut ExtentNode) -> Result<(), Error<IO::Error>> {
+    if !root.is_leaf() && root.indexes.is_empty() {
+      // 所有子节点都被删掉了
+      root.depth = 0;
+    }
+    while !root.is_leaf() && root.indexes.len() == 1 {
+      let pblock = root.indexes[0].get_extent_idx();
+      let child = self.load_node(pblock, root.depth - 1)?;
+      if child.len() > root.max as usize {
+        break;
+      }
+      trace!("ExtentTree::shrink_in_depth: depth {} -> {}", root.depth, child.depth);
+      root.depth = child.depth;
+      root.extents = child.extents;
+      root.indexes = child.indexes;
+      self.release_blocks(pblock, 1)?;
+    }
+    Ok(())
+  }
+
+  // 非根节点最多能放的条目数
+  fn node_max_entries(&self) -> u16 {
+    let block_size = self.fs.super_block.borrow().get_block_size() as usize;
+    ((block_size - core::mem::size_of::<ExtentHeader>()) / core::mem::size_of::<Extent>()) as u16
+  }
+
+  fn alloc_node_block(&mut self) -> Result<u64, Error<IO::Error>> {
+    let pblock = self.fs.alloc_block(self.fs.get_inode_goal(self.ino))?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let blocks_count = self.inode.get_blocks_count() + block_size / Inode::INODE_BLOCK_SIZE as u64;
+    self.inode.set_blocks_count(blocks_count);
+    Ok(pblock)
+  }
+
+  // 释放属于这个inode的块并更新inode的块数
+  fn release_blocks(&mut self, start: u64, count: u64) -> Result<(), Error<IO::Error>> {
+    self.fs.free_blocks(start, count)?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let blocks_count = self.inode.get_blocks_count() - count * block_size / Inode::INODE_BLOCK_SIZE as u64;
+    self.inode.set_blocks_count(blocks_count);
+    Ok(())
+  }
+
+  fn load_root(&self) -> Result<ExtentNode, Error<IO::Error>> {
+    let root = unsafe { &*(self.inode.block.as_ptr() as *const [u8; 60]) };
+    ExtentNode::parse(root, None).ok_or(Error::CorruptedFileSystem)
+  }
+
+  fn load_node(&self, pblock: u64, depth: u16) -> Result<ExtentNode, Error<IO::Error>> {
+    let mut data = vec![0u8; self.fs.super_block.borrow().get_block_size() as usize];
+    self.fs.read_block(pblock, &mut data)?;
+    // 先检查header，保证ExtentTail在块内
+    let node = match ExtentNode::parse(&data, Some(pblock)) {
+      Some(node) if node.depth == depth => node,
+      _ => {
+        error!("ExtentTree::load_node: invalid extent node at block {}", pblock);
+        return Err(Error::CorruptedFileSystem);
+      }
+    };
+    let super_block = self.fs.super_block.borrow();
+    if super_block.has_feature_ro_compat_metadata_csum()
+      && !ExtentTail::verify_checksum(&data, &super_block.uuid, self.ino as u32, self.inode.generation)
+    {
+      error!("ExtentTree::load_node: checksum mismatch at block {}", pblock);
+      return Err(Error::CorruptedFileSystem);
+    }
+    Ok(node)
+  }
+
+  fn store_node(&mut self, node: &ExtentNode) -> Result<(), Error<IO::Error>> {
+    match node.pblock {
+      None => {
+        let root = unsafe { &mut *(self.inode.block.as_mut_ptr() as *mut [u8; 60]) };
+        node.serialize_into(root);
+        Ok(())
+      }
+      Some(pblock) => {
+        let mut data = vec![0u8; self.fs.super_block.borrow().get_block_size() as usize];
+        node.serialize_into(&mut data);
+        let super_block = self.fs.super_block.borrow();
+        if super_block.has_feature_ro_compat_metadata_csum() {
+          ExtentTail::set_checksum(&mut data, &super_block.uuid, self.ino as u32, self.inode.generation);
+        }
+        drop(super_block);
+        self.fs.write_block(pblock, &data)
+      }
+    }
+  }
+}
@@ -1,6 +1,12 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::block_map::BlockMap;
+use crate::error::Error;
+use crate::extent::{Extent, ExtentTree};
 use crate::fs::FileSystem;
-use crate::inode::Inode;
-use crate::io::ReadWriteSeek;
+use crate::inode::{Inode, InodeFlags};
+use crate::io::{ReadWriteSeek, SeekFrom};
 
 pub struct File<'a, IO: ReadWriteSeek> {
   pub ino: u64,
@@ -14,8 +20,9 @@ impl<'a, IO: ReadWriteSeek> File<'a, IO> {
     Self { ino, inode, fs }
   }
 
-  pub fn read(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<usize, IO::Error> {
+  pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error<IO::Error>> {
     trace!("File::read offset: {}, buf.len: {}", offset, buf.len());
+    self.check_supported()?;
     if offset >= self.inode.get_size() {
       return Ok(0);
     }
@@ -31,30 +38,367 @@ impl<'a, IO: ReadWriteSeek> File<'a, IO> {
     }
 
     let mut disk = self.fs.disk.borrow_mut();
-    let extents = self.inode.get_extents(&mut *disk).unwrap();
-    let block_size = self.fs.super_block.borrow().get_block_size() as u64;
+    let extents = self
+      .inode
+      .get_extents(self.ino, &mut *disk, &self.fs.super_block.borrow())?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
 
-    let mut left_bytes = read_bytes;
-    for extent in extents {
-      if offset >= extent.len as u64 * block_size {
-        offset -= extent.len as u64 * block_size;
-        continue;
+    let end = offset + read_bytes as u64;
+    let mut pos = offset;
+    while pos < end {
+      let buf_offset = (pos - offset) as usize;
+      match Extent::lookup(&extents, pos / block_size) {
+        Ok(extent) if extent.is_unwritten() => {
+          // unwritten extent还没有写入数据，读出来都是0
+          let extent_end = extent.get_end_block() * block_size;
+          let len = (end.min(extent_end) - pos) as usize;
+          buf[buf_offset..buf_offset + len].fill(0);
+          pos += len as u64;
+        }
+        Ok(extent) => {
+          let extent_start = extent.block as u64 * block_size;
+          let extent_end = extent.get_end_block() * block_size;
+          let len = (end.min(extent_end) - pos) as usize;
+          extent.read_bytes(
+            block_size,
+            &mut *disk,
+            pos - extent_start,
+            &mut buf[buf_offset..buf_offset + len],
+          )?;
+          pos += len as u64;
+        }
+        Err(next_block) => {
+          // 空洞里的数据都是0
+          let hole_end = next_block.map_or(end, |b| end.min(b * block_size));
+          buf[buf_offset..buf_offset + (hole_end - pos) as usize].fill(0);
+          pos = hole_end;
+        }
       }
+    }
 
-      let read_bytes_in_extent =
-        core::cmp::min(left_bytes as u64, (extent.len as u64 * block_size - offset) as u64) as usize;
-
-      extent.read_bytes(block_size, &mut *disk, offset, &mut buf[..read_bytes_in_extent])?;
+    Ok(read_bytes)
+  }
 
-      offset = 0;
-      left_bytes -= read_bytes_in_extent;
-      buf = &mut buf[read_bytes_in_extent..];
+  // 从offset开始找下一段有数据的位置，类似lseek的SEEK_DATA
+  // unwritten extent视为空洞，offset之后全是空洞或者offset超出文件大小时返回None
+  pub fn seek_data(&self, offset: u64) -> Result<Option<u64>, Error<IO::Error>> {
+    trace!("File::seek_data offset: {}", offset);
+    self.check_supported()?;
+    let size = self.inode.get_size();
+    if offset >= size {
+      return Ok(None);
+    }
+    let extents = self.get_extents()?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let mut lblock = offset / block_size;
+    loop {
+      match Extent::lookup(&extents, lblock) {
+        Ok(extent) if extent.is_unwritten() => lblock = extent.get_end_block(),
+        Ok(_) => break,
+        Err(Some(next_block)) => lblock = next_block,
+        Err(None) => return Ok(None),
+      }
+    }
+    let pos = offset.max(lblock * block_size);
+    Ok(Some(pos).filter(|&pos| pos < size))
+  }
 
-      if left_bytes == 0 {
+  // 从offset开始找下一个空洞的位置，类似lseek的SEEK_HOLE
+  // unwritten extent和文件末尾也被视为空洞，offset超出文件大小时返回None
+  pub fn seek_hole(&self, offset: u64) -> Result<Option<u64>, Error<IO::Error>> {
+    trace!("File::seek_hole offset: {}", offset);
+    self.check_supported()?;
+    let size = self.inode.get_size();
+    if offset >= size {
+      return Ok(None);
+    }
+    let extents = self.get_extents()?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let mut pos = offset;
+    while let Ok(extent) = Extent::lookup(&extents, pos / block_size) {
+      if extent.is_unwritten() {
         break;
       }
+      pos = extent.get_end_block() * block_size;
+      if pos >= size {
+        return Ok(Some(size));
+      }
     }
+    Ok(Some(pos))
+  }
 
-    Ok(read_bytes)
+  // 还不支持inline data的文件
+  fn check_supported(&self) -> Result<(), Error<IO::Error>> {
+    if self.inode.get_flags().contains(InodeFlags::INLINE_DATA_FL) {
+      return Err(Error::Unsupported);
+    }
+    Ok(())
+  }
+
+  fn get_extents(&self) -> Result<Vec<Extent>, Error<IO::Error>> {
+    let mut disk = self.fs.disk.borrow_mut();
+    self
+      .inode
+      .get_extents(self.ino, &mut *disk, &self.fs.super_block.borrow())
+  }
+
+  pub fn write(&mut self, offset: u64, buf: &[u8], time: u32) -> Result<usize, Error<IO::Error>> {
+    trace!("File::write offset: {}, buf.len: {}", offset, buf.len());
+    self.check_supported()?;
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let end = offset.checked_add(buf.len() as u64).ok_or(Error::InvalidInput)?;
+    let start_lblock = offset / block_size;
+    let end_lblock = end.div_ceil(block_size);
+    if end_lblock > self.max_lblock() {
+      return Err(Error::InvalidInput);
+    }
+
+    // 给还没有映射的逻辑块分配物理块
+    let new_extents = self.alloc_blocks(start_lblock, end_lblock, false)?;
+    let extents = self.get_extents()?;
+
+    // 逐块写入数据
+    let mut disk = self.fs.disk.borrow_mut();
+    let mut block_buf = vec![0u8; block_size as usize];
+    let mut written = 0;
+    // 写入范围内的unwritten extent，写完数据之后转换为已写入状态
+    let mut unwritten = Vec::new();
+    for lblock in start_lblock..end_lblock {
+      let extent = Extent::lookup(&extents, lblock).map_err(|_| Error::CorruptedFileSystem)?;
+      let pblock = extent.map_block(lblock).unwrap();
+      if extent.is_unwritten() {
+        match unwritten.last_mut() {
+          Some((start, len)) if *start + *len == lblock && extent.map_block(*start).is_some() => *len += 1,
+          _ => unwritten.push((lblock, 1)),
+        }
+      }
+      let block_start = lblock * block_size;
+      let from = offset.max(block_start) - block_start;
+      let to = end.min(block_start + block_size) - block_start;
+      let len = (to - from) as usize;
+      disk.seek(SeekFrom::Start(pblock * block_size))?;
+      if len as u64 == block_size {
+        disk.write_all(&buf[written..written + len])?;
+      } else {
+        // 只写一个块的一部分：新分配的块和unwritten的块其余部分填0，已有的块需要先读出来
+        if extent.is_unwritten() || new_extents.iter().any(|e| e.map_block(lblock).is_some()) {
+          block_buf.fill(0);
+        } else {
+          disk.read_exact(&mut block_buf)?;
+          disk.seek(SeekFrom::Start(pblock * block_size))?;
+        }
+        block_buf[from as usize..to as usize].copy_from_slice(&buf[written..written + len]);
+        disk.write_all(&block_buf)?;
+      }
+      written += len;
+    }
+    drop(disk);
+    for (start, len) in unwritten {
+      ExtentTree::new(self.fs, self.ino, &mut self.inode).mark_written(start, len)?;
+    }
+
+    // 更新inode
+    if end > self.inode.get_size() {
+      self.inode.set_size(end);
+    }
+    self.inode.mtime = time;
+    self.inode.ctime = time;
+    self.fs.write_inode(self.ino, &mut self.inode)?;
+    Ok(written)
+  }
+
+  // 类似fallocate，给[offset, offset + len)中还没有映射的块分配unwritten extent，已有的数据不变
+  // keep_size为false时，超出文件末尾的部分会扩展文件大小
+  pub fn allocate(&mut self, offset: u64, len: u64, keep_size: bool, time: u32) -> Result<(), Error<IO::Error>> {
+    trace!(
+      "File::allocate offset: {}, len: {}, keep_size: {}",
+      offset,
+      len,
+      keep_size
+    );
+    self.check_supported()?;
+    if len == 0 {
+      return Err(Error::InvalidInput);
+    }
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let end = offset.checked_add(len).ok_or(Error::InvalidInput)?;
+    let end_lblock = end.div_ceil(block_size);
+    if end_lblock > self.max_lblock() {
+      return Err(Error::InvalidInput);
+    }
+
+    self.alloc_blocks(offset / block_size, end_lblock, true)?;
+    if !keep_size && end > self.inode.get_size() {
+      self.inode.set_size(end);
+      self.inode.mtime = time;
+    }
+    self.inode.ctime = time;
+    self.fs.write_inode(self.ino, &mut self.inode)
+  }
+
+  // 类似fallocate的PUNCH_HOLE，释放[offset, offset + len)中完整的块，首尾不满一块的部分写0
+  // 文件大小不变
+  pub fn punch_hole(&mut self, offset: u64, len: u64, time: u32) -> Result<(), Error<IO::Error>> {
+    trace!("File::punch_hole offset: {}, len: {}", offset, len);
+    self.check_supported()?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let end = offset.checked_add(len).ok_or(Error::InvalidInput)?;
+    let start_lblock = offset.div_ceil(block_size);
+    let end_lblock = end / block_size;
+
+    if start_lblock > end_lblock {
+      // 整个范围在同一个块里
+      self.zero_block_range(offset, end)?;
+    } else {
+      self.zero_block_range(offset, start_lblock * block_size)?;
+      self.zero_block_range(end_lblock * block_size, end)?;
+      if start_lblock < end_lblock {
+        self
+          .fs
+          .unmap_blocks(self.ino, &mut self.inode, start_lblock, end_lblock - start_lblock)?;
+      }
+    }
+    self.inode.mtime = time;
+    self.inode.ctime = time;
+    self.fs.write_inode(self.ino, &mut self.inode)
+  }
+
+  // 修改文件大小。缩小时释放新末尾之后的所有块(包括预分配的块)，并把最后一个块中末尾之后的部分写0
+  // 扩大时新增的部分是空洞
+  pub fn set_len(&mut self, new_size: u64, time: u32) -> Result<(), Error<IO::Error>> {
+    trace!("File::set_len new_size: {}", new_size);
+    self.check_supported()?;
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let end_lblock = new_size.div_ceil(block_size);
+    if end_lblock > self.max_lblock() {
+      return Err(Error::InvalidInput);
+    }
+
+    let old_size = self.inode.get_size();
+    if new_size < old_size {
+      self.zero_block_range(new_size, end_lblock * block_size)?;
+      let max_lblock = u32::MAX as u64 + 1;
+      self
+        .fs
+        .unmap_blocks(self.ino, &mut self.inode, end_lblock, max_lblock - end_lblock)?;
+    } else {
+      // 原来最后一个块中末尾之后的部分要保证读出来是0
+      let old_block_end = old_size.div_ceil(block_size) * block_size;
+      self.zero_block_range(old_size, new_size.min(old_block_end))?;
+    }
+    self.inode.set_size(new_size);
+    self.inode.mtime = time;
+    self.inode.ctime = time;
+    self.fs.write_inode(self.ino, &mut self.inode)
+  }
+
+  // 把同一个块里的[from, to)写0，空洞和unwritten extent本来就读出0，不需要处理
+  fn zero_block_range(&mut self, from: u64, to: u64) -> Result<(), Error<IO::Error>> {
+    if from >= to {
+      return Ok(());
+    }
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    let lblock = from / block_size;
+    let extents = self.get_extents()?;
+    let pblock = match Extent::lookup(&extents, lblock) {
+      Ok(extent) if !extent.is_unwritten() => extent.map_block(lblock).unwrap(),
+      _ => return Ok(()),
+    };
+    let mut block_buf = vec![0u8; block_size as usize];
+    self.fs.read_block(pblock, &mut block_buf)?;
+    let block_start = lblock * block_size;
+    block_buf[(from - block_start) as usize..(to - block_start) as usize].fill(0);
+    self.fs.write_block(pblock, &block_buf)
+  }
+
+  // 文件最多能有多少个逻辑块：extent的逻辑块号只有32位，块映射受间接块的级数限制
+  fn max_lblock(&self) -> u64 {
+    if self.inode.use_extents() {
+      u32::MAX as u64
+    } else {
+      BlockMap::<IO>::max_blocks(self.fs.super_block.borrow().get_block_size())
+    }
+  }
+
+  // 给[start_lblock, end_lblock)中没有映射的逻辑块分配物理块，返回新加入的extent
+  // unwritten为true时新加入的是unwritten extent
+  // 中途失败时撤销已经加入的extent并释放对应的块，再把inode写回disk，避免这些块泄漏
+  fn alloc_blocks(
+    &mut self,
+    start_lblock: u64,
+    end_lblock: u64,
+    unwritten: bool,
+  ) -> Result<Vec<Extent>, Error<IO::Error>> {
+    let mut new_extents = Vec::new();
+    if let Err(err) = self.map_new_blocks(start_lblock, end_lblock, unwritten, &mut new_extents) {
+      for extent in new_extents.iter().rev() {
+        self
+          .fs
+          .unmap_blocks(self.ino, &mut self.inode, extent.block as u64, extent.get_len() as u64)?;
+      }
+      self.fs.write_inode(self.ino, &mut self.inode)?;
+      return Err(err);
+    }
+    Ok(new_extents)
+  }
+
+  fn map_new_blocks(
+    &mut self,
+    start_lblock: u64,
+    end_lblock: u64,
+    unwritten: bool,
+    new_extents: &mut Vec<Extent>,
+  ) -> Result<(), Error<IO::Error>> {
+    let block_size = self.fs.super_block.borrow().get_block_size();
+    // 块映射没有unwritten状态
+    if unwritten && !self.inode.use_extents() {
+      error!("File::map_new_blocks: block map doesn't support unwritten blocks");
+      return Err(Error::Unsupported);
+    }
+    let extents = self.get_extents()?;
+    // 尽量让新的块接在前一个extent的物理块后面，前面没有extent时放在inode所在的block group里
+    let mut goal = match extents.iter().rev().find(|e| (e.block as u64) < start_lblock) {
+      Some(prev) => prev.get_block_loc() + (start_lblock - prev.block as u64),
+      None => self.fs.get_inode_goal(self.ino),
+    };
+
+    let mut lblock = start_lblock;
+    while lblock < end_lblock {
+      // 空洞一直延续到下一个extent的开头
+      let hole_end = match Extent::lookup(&extents, lblock) {
+        Ok(extent) => {
+          lblock = extent.get_end_block();
+          goal = extent.get_block_loc() + extent.get_len() as u64;
+          continue;
+        }
+        Err(next_block) => next_block.map_or(end_lblock, |b| b.min(end_lblock)),
+      };
+      let max_len = if unwritten {
+        Extent::MAX_UNWRITTEN_LEN
+      } else {
+        Extent::MAX_LEN
+      };
+      // 找不到足够长的连续空间时，分配到的块可能比要求的少
+      let (start, count) = self.fs.alloc_blocks(goal, (hole_end - lblock).min(max_len as u64))?;
+      let extent = if unwritten {
+        Extent::new_unwritten(lblock as u32, count as u16, start)
+      } else {
+        Extent::new(lblock as u32, count as u16, start)
+      };
+      if let Err(err) = self.fs.map_blocks(self.ino, &mut self.inode, extent) {
+        // 这次分配的块还没有映射进inode，直接释放
+        self.fs.free_blocks(start, count)?;
+        return Err(err);
+      }
+      let blocks_count = self.inode.get_blocks_count() + count * block_size / Inode::INODE_BLOCK_SIZE as u64;
+      self.inode.set_blocks_count(blocks_count);
+      new_extents.push(extent);
+      lblock += count;
+      goal = start + count;
+    }
+    Ok(())
   }
 }
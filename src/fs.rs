@@ -5,11 +5,14 @@ use alloc::vec::Vec;
 use crate::error::Error;
 use crate::io::{self, ReadWriteSeek, SeekFrom};
 
-use crate::descriptor::BlockGroupDescriptor;
+use crate::block_map::BlockMap;
+use crate::descriptor::{BGFlags, BlockGroupDescriptor};
 use crate::dir::Dir;
-use crate::inode::Inode;
+use crate::extent::{Extent, ExtentTree};
+use crate::inode::{Inode, InodeFlags};
 use crate::super_block::SuperBlock;
 use crate::utils::bitmap::Bitmap;
+use crate::utils::crc::crc32c;
 
 pub struct FileSystem<IO: ReadWriteSeek> {
   pub disk: RefCell<IO>,
@@ -42,9 +45,19 @@ impl<IO: ReadWriteSeek> FileSystem<IO> {
     // read super block
     let super_block = SuperBlock::deserialize(&mut disk)?;
     trace!("super_block: {:?}", super_block);
+    if !super_block.is_valid() {
+      error!("FileSystem::new: bad magic number");
+      return Err(Error::CorruptedFileSystem);
+    }
+    // ext2、ext3和ext4共用同一套代码，具体行为由特性决定，不认识的特性不能挂载
+    if !super_block.check_features() {
+      return Err(Error::Unsupported);
+    }
     let mut descriptors = Vec::with_capacity(super_block.get_block_group_count() as usize);
-    for _ in 0..super_block.get_block_group_count() {
-      let bgd = BlockGroupDescriptor::deserialize(&mut disk)?;
+    let desc_size = super_block.get_desc_size() as usize;
+    for bgd_id in 0..super_block.get_block_group_count() {
+      disk.seek(SeekFrom::Start(super_block.get_desc_pos(bgd_id)))?;
+      let bgd = BlockGroupDescriptor::deserialize(&mut disk, desc_size)?;
       descriptors.push(bgd);
       trace!("block_group_descriptor: {:?}", bgd);
     }
@@ -71,178 +84,674 @@ impl<IO: ReadWriteSeek> FileSystem<IO> {
     let mut disk = self.disk.borrow_mut();
     disk.seek(SeekFrom::Start(pos))?;
 
-    let inode = Inode::deserialize(&mut *disk)?;
+    let mut inode = Inode::deserialize(&mut *disk)?;
+    inode.clear_beyond_disk_len(self.super_block.borrow().get_inode_size());
     Ok(inode)
   }
 
-  pub fn root_dir(&self) -> Dir<IO> {
+  // 读出磁盘上完整的inode，包括Inode结构体之后的额外空间
+  pub fn read_inode_raw(&self, ino: u64) -> Result<Vec<u8>, Error<IO::Error>> {
+    let mut raw = vec![0u8; self.super_block.borrow().get_inode_size() as usize];
+    let pos = self.get_inode_pos(ino);
+    let mut disk = self.disk.borrow_mut();
+    disk.seek(SeekFrom::Start(pos))?;
+    disk.read_exact(&mut raw)?;
+    Ok(raw)
+  }
+
+  // 读出符号链接的目标，支持快速符号链接、inline data以及保存在数据块里的符号链接
+  pub fn read_symlink(&self, ino: u64, inode: &Inode) -> Result<String, Error<IO::Error>> {
+    trace!("FileSystem::read_symlink ino: {}", ino);
+    if !inode.is_symlink() {
+      return Err(Error::InvalidInput);
+    }
+    let size = inode.get_size() as usize;
+    let block_size = self.super_block.borrow().get_block_size();
+    let block_bytes = unsafe { core::slice::from_raw_parts(inode.block.as_ptr() as *const u8, 60) };
+    let flags = inode.get_flags();
+    // 扩展属性块也计入inode的块数
+    let ea_blocks = if inode.get_file_acl() != 0 {
+      block_size / Inode::INODE_BLOCK_SIZE as u64
+    } else {
+      0
+    };
+
+    let target = if flags.contains(InodeFlags::INLINE_DATA_FL) {
+      // 前60字节在inode.block里，剩下的在system.data扩展属性里
+      let raw = self.read_inode_raw(ino)?;
+      let mut data = block_bytes.to_vec();
+      data.extend(
+        inode
+          .find_inline_xattr(&raw, Inode::XATTR_INDEX_SYSTEM, b"data")
+          .unwrap_or_default(),
+      );
+      data
+    } else if inode.get_blocks_count() == ea_blocks {
+      // 快速符号链接
+      block_bytes.to_vec()
+    } else {
+      let extents = {
+        let mut disk = self.disk.borrow_mut();
+        inode.get_extents(ino, &mut *disk, &self.super_block.borrow())?
+      };
+      let pblock = Extent::lookup(&extents, 0)
+        .ok()
+        .and_then(|e| e.map_block(0))
+        .ok_or(Error::CorruptedFileSystem)?;
+      let mut data = vec![0u8; block_size as usize];
+      self.read_block(pblock, &mut data)?;
+      data
+    };
+    if size > target.len() {
+      error!("FileSystem::read_symlink: symlink {} is longer than its data", ino);
+      return Err(Error::CorruptedFileSystem);
+    }
+    Ok(String::from_utf8_lossy(&target[..size]).to_string())
+  }
+
+  pub fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), Error<IO::Error>> {
+    let mut disk = self.disk.borrow_mut();
+    disk.seek(SeekFrom::Start(block * self.super_block.borrow().get_block_size()))?;
+    disk.read_exact(buf)?;
+    Ok(())
+  }
+
+  pub fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), Error<IO::Error>> {
+    let mut disk = self.disk.borrow_mut();
+    disk.seek(SeekFrom::Start(block * self.super_block.borrow().get_block_size()))?;
+    disk.write_all(buf)?;
+    Ok(())
+  }
+
+  pub fn write_inode(&self, ino: u64, inode: &mut Inode) -> Result<(), Error<IO::Error>> {
+    trace!("FileSystem::write_inode ino: {}", ino);
+    let inode_size = self.super_block.borrow().get_inode_size();
+    if self.super_block.borrow().has_feature_ro_compat_metadata_csum() {
+      // inode内扩展属性不在结构体里，校验和要用磁盘上的原有内容
+      let raw = self.read_inode_raw(ino)?;
+      inode.compute_and_set_checksum(ino as u32, &raw, &self.super_block.borrow().uuid);
+    }
+    let pos = self.get_inode_pos(ino);
+    let mut disk = self.disk.borrow_mut();
+    disk.seek(SeekFrom::Start(pos))?;
+    inode.serialize_to_disk(&mut *disk, inode_size)?;
+    Ok(())
+  }
+
+  // 把extent加入inode的块映射，使用extent tree还是ext2/ext3的间接块由inode决定
+  // 调用者负责数据块的块数以及把inode写回disk
+  pub fn map_blocks(&self, ino: u64, inode: &mut Inode, extent: Extent) -> Result<(), Error<IO::Error>> {
+    if inode.use_extents() {
+      ExtentTree::new(self, ino, inode).insert(extent)
+    } else if inode.use_block_map() {
+      BlockMap::new(self, ino, inode).insert(extent)
+    } else {
+      Err(Error::Unsupported)
+    }
+  }
+
+  // 删除[lblock, lblock + len)范围内的映射并释放对应的数据块，返回释放的数据块数
+  // 调用者负责把inode写回disk
+  pub fn unmap_blocks(&self, ino: u64, inode: &mut Inode, lblock: u64, len: u64) -> Result<u64, Error<IO::Error>> {
+    if inode.use_extents() {
+      ExtentTree::new(self, ino, inode).remove(lblock, len)
+    } else if inode.use_block_map() {
+      BlockMap::new(self, ino, inode).remove(lblock, len)
+    } else {
+      Err(Error::Unsupported)
+    }
+  }
+
+  // 释放inode的扩展属性块：块被多个inode共享时只减少引用计数，最后一个引用释放这个块
+  // 调用者负责把inode写回disk
+  pub fn release_xattr_block(&self, inode: &mut Inode) -> Result<(), Error<IO::Error>> {
+    let block = inode.get_file_acl();
+    if block == 0 {
+      return Ok(());
+    }
+    trace!("FileSystem::release_xattr_block block: {}", block);
+    let block_size = self.super_block.borrow().get_block_size();
+    let mut buf = vec![0u8; block_size as usize];
+    self.read_block(block, &mut buf)?;
+    // 块头：magic、引用计数、块数、hash、checksum
+    if u32::from_le_bytes(buf[0..4].try_into().unwrap()) != Inode::XATTR_MAGIC {
+      error!("FileSystem::release_xattr_block: block {} is not an xattr block", block);
+      return Err(Error::CorruptedFileSystem);
+    }
+    let refcount = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    if refcount > 1 {
+      buf[4..8].copy_from_slice(&(refcount - 1).to_le_bytes());
+      if self.super_block.borrow().has_feature_ro_compat_metadata_csum() {
+        // 校验和覆盖块号和checksum清零之后的整个块
+        buf[16..20].fill(0);
+        let uuid = self.super_block.borrow().uuid;
+        let mut csum = crc32c(!0, &uuid, uuid.len() as u32);
+        csum = crc32c(csum, &block.to_le_bytes(), 8);
+        csum = crc32c(csum, &buf, buf.len() as u32);
+        buf[16..20].copy_from_slice(&csum.to_le_bytes());
+      }
+      self.write_block(block, &buf)?;
+    } else {
+      self.free_blocks(block, 1)?;
+    }
+    let blocks_count = inode
+      .get_blocks_count()
+      .checked_sub(block_size / Inode::INODE_BLOCK_SIZE as u64)
+      .ok_or(Error::CorruptedFileSystem)?;
+    inode.set_blocks_count(blocks_count);
+    inode.set_file_acl(0);
+    Ok(())
+  }
+
+  pub fn root_dir(&self) -> Dir<'_, IO> {
     let inode = self.get_inode(Inode::ROOT_INO).unwrap();
-    Dir::new(Inode::ROOT_INO as u64, inode, self)
+    Dir::new(Inode::ROOT_INO, inode, self)
   }
 }
 
 // alloc
 impl<IO: ReadWriteSeek> FileSystem<IO> {
-  pub fn alloc_contiguous_blocks(&self, count: u64, bgd_id: usize) -> Result<u64, Error<IO::Error>> {
-    trace!(
-      "FileSystem::alloc_contiguous_blocks count: {}, bgd_id: {}",
-      count,
-      bgd_id
-    );
-    let bgd = &mut self.block_group_descriptors.borrow_mut()[bgd_id];
-    let mut free_blocks_count = bgd.get_free_blocks_count();
-    if u64::from(free_blocks_count) < count {
-      // 这一个block group没有足够的空间
-      return Err(Error::NotEnoughSpace);
+  // 以goal为目标分配最多count个连续的块，返回第一个块和实际分配的块数
+  // 先在goal所在的block group里从goal往后找，再按离它由近到远的顺序找其他block group，
+  // 哪里都没有count个连续的空闲块时，退而求其次分配最先找到的最长的一段空闲块
+  pub fn alloc_blocks(&self, goal: u64, count: u64) -> Result<(u64, u64), Error<IO::Error>> {
+    trace!("FileSystem::alloc_blocks goal: {}, count: {}", goal, count);
+    assert!(count > 0);
+    let (group_count, goal_bgd, goal_bit) = {
+      let super_block = self.super_block.borrow();
+      let first_data_block = super_block.first_data_block as u64;
+      let blocks_per_group = super_block.blocks_per_group as u64;
+      let goal = goal.clamp(first_data_block, super_block.get_blocks_count() - 1) - first_data_block;
+      (
+        super_block.get_block_group_count(),
+        (goal / blocks_per_group) as u32,
+        goal % blocks_per_group,
+      )
+    };
+    let mut order = vec![goal_bgd];
+    for distance in 1..group_count {
+      if goal_bgd + distance < group_count {
+        order.push(goal_bgd + distance);
+      }
+      if distance <= goal_bgd {
+        order.push(goal_bgd - distance);
+      }
+    }
+
+    for whole in [true, false] {
+      for &bgd_id in &order {
+        let free_blocks_count = self.block_group_descriptors.borrow()[bgd_id as usize].get_free_blocks_count();
+        if free_blocks_count == 0 || (whole && (free_blocks_count as u64) < count) {
+          continue;
+        }
+        let block_bitmap = self.read_block_bitmap(bgd_id)?;
+        let limit = self.super_block.borrow().get_group_blocks(bgd_id);
+        let found = if whole {
+          let from = if bgd_id == goal_bgd { goal_bit } else { 0 };
+          block_bitmap
+            .find_unused_run(from, count, limit)
+            .or_else(|| block_bitmap.find_unused_run(0, count, limit))
+            .map(|bit| (bit, count))
+        } else {
+          block_bitmap
+            .find_longest_unused_run(limit)
+            .map(|(bit, len)| (bit, len.min(count)))
+        };
+        if let Some((bit, len)) = found {
+          return self.claim_blocks(bgd_id, block_bitmap, bit, len);
+        }
+      }
     }
+    Err(Error::NotEnoughSpace)
+  }
+
+  // 以goal为目标分配一个块
+  pub fn alloc_block(&self, goal: u64) -> Result<u64, Error<IO::Error>> {
+    Ok(self.alloc_blocks(goal, 1)?.0)
+  }
+
+  // 没有更好的参考时，在inode所在的block group里给它分配块
+  pub fn get_inode_goal(&self, ino: u64) -> u64 {
+    let super_block = self.super_block.borrow();
+    let bgd_id = (ino - 1) / super_block.inodes_per_group as u64;
+    super_block.get_group_first_block(bgd_id as u32)
+  }
 
-    let block_bitmap_loc = bgd.get_block_bitmap_loc();
-    let mut block_bitmap = {
+  // 读取block bitmap，BLOCK_UNINIT的block group在磁盘上还没有bitmap，按它的元数据现场构造
+  fn read_block_bitmap(&self, bgd_id: u32) -> Result<Bitmap, Error<IO::Error>> {
+    let bgd = self.block_group_descriptors.borrow()[bgd_id as usize];
+    let super_block = self.super_block.borrow();
+    let size = super_block.blocks_per_group as usize / Bitmap::BITS_PER_ITEM;
+    if !bgd.get_flags().contains(BGFlags::BLOCK_UNINIT) {
       let mut disk = self.disk.borrow_mut();
-      let offset = block_bitmap_loc * self.super_block.borrow().get_block_size();
-      disk.seek(SeekFrom::Start(offset))?;
-      let size = self.super_block.borrow().blocks_per_group as usize / Bitmap::BITS_PER_ITEM as usize;
-      Bitmap::deserialize(&mut *disk, size)?
-    };
+      disk.seek(SeekFrom::Start(
+        bgd.get_block_bitmap_loc() * super_block.get_block_size(),
+      ))?;
+      return Ok(Bitmap::deserialize(&mut *disk, size)?);
+    }
 
-    let start_block = block_bitmap.find_consecutive_unused_bits(count);
-    if start_block.is_none() {
-      // 这一个block group没有足够的连续空间
-      return Err(Error::NotEnoughSpace);
+    let mut block_bitmap = Bitmap::new(size);
+    // superblock和descriptor表的备份
+    block_bitmap.set_bits(0, super_block.get_group_meta_blocks(bgd_id));
+    // 这个block group自己的bitmap和inode table，开启flex_bg时可能不在这个block group里
+    let first_block = super_block.get_group_first_block(bgd_id);
+    let limit = super_block.get_group_blocks(bgd_id);
+    let inode_table_blocks =
+      (super_block.inodes_per_group as u64 * super_block.get_inode_size()).div_ceil(super_block.get_block_size());
+    for (loc, len) in [
+      (bgd.get_block_bitmap_loc(), 1),
+      (bgd.get_inode_bitmap_loc(), 1),
+      (bgd.get_inode_table_loc(), inode_table_blocks),
+    ] {
+      if loc >= first_block && loc + len <= first_block + limit {
+        block_bitmap.set_bits(loc - first_block, len);
+      }
     }
-    let start_block = start_block.unwrap();
-    assert!(block_bitmap.get_bit(start_block) == false);
-    block_bitmap.set_bits(start_block, count);
-    // block bitmap写入disk
-    trace!("FileSystem::alloc_contiguous_blocks: write block_bitmap to disk");
+    // 最后一个block group超出文件系统的部分
+    block_bitmap.set_bits(limit, block_bitmap.size() - limit);
+    Ok(block_bitmap)
+  }
+
+  // 在bgd_id的block bitmap里标记从bit开始的count个块，并更新descriptor和super block
+  fn claim_blocks(
+    &self,
+    bgd_id: u32,
+    mut block_bitmap: Bitmap,
+    bit: u64,
+    count: u64,
+  ) -> Result<(u64, u64), Error<IO::Error>> {
+    block_bitmap.set_bits(bit, count);
+    let bgd = &mut self.block_group_descriptors.borrow_mut()[bgd_id as usize];
+    // block bitmap写入disk，第一次写入时bitmap块里blocks_per_group之后的部分也要置1
+    trace!("FileSystem::claim_blocks: write block_bitmap to disk");
     {
       let mut disk = self.disk.borrow_mut();
-      let offset = block_bitmap_loc * self.super_block.borrow().get_block_size();
-      disk.seek(SeekFrom::Start(offset))?;
-      block_bitmap.serialize(&mut *disk).unwrap();
+      let block_size = self.super_block.borrow().get_block_size();
+      disk.seek(SeekFrom::Start(bgd.get_block_bitmap_loc() * block_size))?;
+      block_bitmap.serialize(&mut *disk)?;
+      if bgd.get_flags().contains(BGFlags::BLOCK_UNINIT) {
+        disk.write_all(&vec![0xFFu8; block_size as usize - block_bitmap.data.len()])?;
+      }
     }
 
-    // FIXME: 这里可能导致只支持一个block group
-    let start_block = start_block + self.super_block.borrow().first_data_block as u64;
-
-    // 更新block group descriptor
+    // 更新block group descriptor，bitmap已经写入disk，不再是BLOCK_UNINIT
+    bgd.set_flags(bgd.get_flags() - BGFlags::BLOCK_UNINIT);
     bgd.set_block_bitmap_csum(&self.super_block.borrow(), &block_bitmap.data);
-    free_blocks_count -= count as u32;
-    bgd.set_free_blocks_count(free_blocks_count);
-    bgd.set_checksum(bgd_id as u32, &self.super_block.borrow());
+    bgd.set_free_blocks_count(bgd.get_free_blocks_count() - count as u32);
+    bgd.set_checksum(bgd_id, &self.super_block.borrow());
 
     // block group descriptor写入disk
-    trace!("FileSystem::alloc_contiguous_blocks: write block group descriptor to disk");
-    {
-      let mut disk = self.disk.borrow_mut();
-      let mut offset = SuperBlock::PADDING_OFFSET + core::mem::size_of::<SuperBlock>();
-      offset += bgd_id * self.super_block.borrow().get_desc_size() as usize;
-      disk.seek(SeekFrom::Start(offset as u64)).unwrap();
-      bgd.serialize(&mut *disk).unwrap();
-    }
+    trace!("FileSystem::claim_blocks: write block group descriptor to disk");
+    self.write_block_group_descriptor(bgd_id as usize, bgd)?;
 
     // 更新super block
-    trace!("FileSystem::alloc_contiguous_blocks: update and write super block to disk");
+    trace!("FileSystem::claim_blocks: update and write super block to disk");
     {
       let mut super_block = self.super_block.borrow_mut();
       let sb_free_blocks_count = super_block.get_free_blocks_count();
       super_block.set_free_blocks_count(sb_free_blocks_count - count);
       super_block.compute_and_set_checksum();
       let mut disk = self.disk.borrow_mut();
-      super_block.serialize(&mut *disk).unwrap();
+      super_block.serialize(&mut *disk)?;
     }
 
-    let start_block = bgd_id as u64 * self.super_block.borrow().blocks_per_group as u64 + start_block;
+    let start_block = self.super_block.borrow().get_group_first_block(bgd_id) + bit;
     trace!(
-      "FileSystem::alloc_contiguous_blocks: start_block: {} count: {}",
+      "FileSystem::claim_blocks: start_block: {} count: {}",
       start_block,
       count
     );
-    return Ok(start_block);
+    Ok((start_block, count))
   }
 
-  pub fn alloc_inode(&self, is_dir: bool) -> Result<u64, Error<IO::Error>> {
-    trace!("FileSystem::alloc_inode is_dir: {}", is_dir);
-    // TODO: 优化，让新的inode跟parent dir在同一个block group?
-    let bgd_len = self.block_group_descriptors.borrow().len();
-    for bgd_id in 0..bgd_len {
-      let bgd = &mut self.block_group_descriptors.borrow_mut()[bgd_id];
-      let mut free_inodes_count = bgd.get_free_inodes_count();
-      if free_inodes_count > 0 {
-        trace!(
-          "FileSystem::alloc_inode: find bgd_id: {}, free_inodes_count: {}",
-          bgd_id,
-          bgd.get_free_inodes_count()
-        );
-        let inode_bitmap_loc = bgd.get_inode_bitmap_loc();
-        let mut inode_bitmap = {
-          let mut disk = self.disk.borrow_mut();
-          disk
-            .seek(SeekFrom::Start(
-              inode_bitmap_loc * self.super_block.borrow().get_block_size(),
-            ))
-            .unwrap();
-          let size = self.super_block.borrow().inodes_per_group as usize / Bitmap::BITS_PER_ITEM as usize;
-          Bitmap::deserialize(&mut *disk, size).unwrap()
-        };
-        trace!("FileSystem::alloc_inode: inode_bitmap: {:?}", inode_bitmap);
-
-        // +1/-1是因为inode从1开始
-        let local_ino = inode_bitmap.find_unused_bit().unwrap();
-        inode_bitmap.set_bit(local_ino);
-        trace!("FileSystem::alloc_inode: new inode_bitmap: {:?}", inode_bitmap);
-
-        // inode bitmap写入disk
-        trace!("FileSystem::alloc_inode: write inode_bitmap to disk");
-        {
-          let mut disk = self.disk.borrow_mut();
-          let offset = inode_bitmap_loc * self.super_block.borrow().get_block_size();
-          disk.seek(SeekFrom::Start(offset)).unwrap();
-          inode_bitmap.serialize(&mut *disk).unwrap();
-        }
+  // 在parent目录下分配一个新的inode，block group由find_inode_group选择
+  pub fn alloc_inode(&self, parent: u64, is_dir: bool) -> Result<u64, Error<IO::Error>> {
+    trace!("FileSystem::alloc_inode parent: {}, is_dir: {}", parent, is_dir);
+    let bgd_id = self.find_inode_group(parent, is_dir).ok_or(Error::NotEnoughSpace)?;
+    let bgd = &mut self.block_group_descriptors.borrow_mut()[bgd_id as usize];
+    trace!(
+      "FileSystem::alloc_inode: find bgd_id: {}, free_inodes_count: {}",
+      bgd_id,
+      bgd.get_free_inodes_count()
+    );
+    let (block_size, inodes_per_group) = {
+      let super_block = self.super_block.borrow();
+      (super_block.get_block_size(), super_block.inodes_per_group as u64)
+    };
+    let inode_bitmap_loc = bgd.get_inode_bitmap_loc();
+    let size = inodes_per_group as usize / Bitmap::BITS_PER_ITEM;
+    // INODE_UNINIT的block group在磁盘上还没有inode bitmap，所有inode都是空闲的
+    let uninit = bgd.get_flags().contains(BGFlags::INODE_UNINIT);
+    let mut inode_bitmap = if uninit {
+      Bitmap::new(size)
+    } else {
+      let mut disk = self.disk.borrow_mut();
+      disk.seek(SeekFrom::Start(inode_bitmap_loc * block_size))?;
+      Bitmap::deserialize(&mut *disk, size)?
+    };
+    trace!("FileSystem::alloc_inode: inode_bitmap: {:?}", inode_bitmap);
+
+    let local_ino = inode_bitmap.find_unused_bit().ok_or_else(|| {
+      error!("FileSystem::alloc_inode: no free inode in bgd_id {}", bgd_id);
+      Error::CorruptedFileSystem
+    })?;
+    inode_bitmap.set_bit(local_ino);
+    trace!("FileSystem::alloc_inode: new inode_bitmap: {:?}", inode_bitmap);
 
-        // 更新block group descriptor
-        trace!("FileSystem::alloc_inode: update block group descriptor");
-        bgd.set_inode_bitmap_csum(&self.super_block.borrow(), &inode_bitmap.data);
-        free_inodes_count -= 1;
-        bgd.set_free_inodes_count(free_inodes_count);
-        if is_dir {
-          let used_dirs_count = bgd.get_used_dirs_count() + 1;
-          bgd.set_used_dirs_count(used_dirs_count);
+    // inode bitmap写入disk，第一次写入时bitmap块里inodes_per_group之后的部分也要置1
+    trace!("FileSystem::alloc_inode: write inode_bitmap to disk");
+    {
+      let mut disk = self.disk.borrow_mut();
+      disk.seek(SeekFrom::Start(inode_bitmap_loc * block_size))?;
+      inode_bitmap.serialize(&mut *disk)?;
+      if uninit {
+        disk.write_all(&vec![0xFFu8; block_size as usize - size])?;
+      }
+    }
+
+    // 更新block group descriptor
+    trace!("FileSystem::alloc_inode: update block group descriptor");
+    bgd.set_flags(bgd.get_flags() - BGFlags::INODE_UNINIT);
+    bgd.set_inode_bitmap_csum(&self.super_block.borrow(), &inode_bitmap.data);
+    bgd.set_free_inodes_count(bgd.get_free_inodes_count() - 1);
+    if is_dir {
+      let used_dirs_count = bgd.get_used_dirs_count() + 1;
+      bgd.set_used_dirs_count(used_dirs_count);
+    }
+    // 只有开启gdt_csum或者metadata_csum时itable_unused才有意义
+    let super_block = self.super_block.borrow();
+    if super_block.has_feature_ro_compat_gdt_csum() || super_block.has_feature_ro_compat_metadata_csum() {
+      let unused = inodes_per_group - local_ino - 1;
+      if unused < bgd.get_itable_unused() as u64 {
+        bgd.set_itable_unused(unused as u32);
+      }
+    }
+    drop(super_block);
+    bgd.set_checksum(bgd_id, &self.super_block.borrow());
+
+    // block group descriptor写入disk
+    trace!("FileSystem::alloc_inode: write block group descriptor to disk");
+    self.write_block_group_descriptor(bgd_id as usize, bgd)?;
+
+    // 更新super block
+    trace!("FileSystem::alloc_inode: update and write super block to disk");
+    {
+      let mut super_block = self.super_block.borrow_mut();
+      let sb_free_inodes_count = super_block.get_free_inodes_count();
+      super_block.set_free_inodes_count(sb_free_inodes_count - 1);
+      super_block.compute_and_set_checksum();
+      let mut disk = self.disk.borrow_mut();
+      super_block.serialize(&mut *disk)?;
+    }
+
+    // +1 是因为inode从1开始
+    let new_ino = bgd_id as u64 * inodes_per_group + local_ino + 1;
+    trace!("FileSystem::alloc_inode: new_ino: {}", new_ino);
+    Ok(new_ino)
+  }
+
+  // 给新的inode选择block group
+  // 根目录下的目录用Orlov算法分散到不同的(flex) group，其他目录和文件尽量放在父目录所在的(flex) group
+  fn find_inode_group(&self, parent: u64, is_dir: bool) -> Option<u32> {
+    let (group_count, flex_size, parent_group) = {
+      let super_block = self.super_block.borrow();
+      let parent_group = (parent - 1) / super_block.inodes_per_group as u64;
+      (
+        super_block.get_block_group_count(),
+        super_block.get_flex_size(),
+        parent_group as u32,
+      )
+    };
+    if is_dir {
+      return self.find_group_orlov(parent_group, parent == Inode::ROOT_INO);
+    }
+
+    let bgds = self.block_group_descriptors.borrow();
+    let has_free_inodes = |bgd_id: u32| bgds[bgd_id as usize].get_free_inodes_count() > 0;
+    if flex_size > 1 {
+      // 父目录所在的flex group里第一个有空闲inode的block group，都满了就换一个flex group
+      let first = parent_group / flex_size * flex_size;
+      if let Some(bgd_id) = (first..(first + flex_size).min(group_count)).find(|&g| has_free_inodes(g)) {
+        return Some(bgd_id);
+      }
+      drop(bgds);
+      return self.find_group_orlov((first + flex_size) % group_count, false);
+    }
+
+    // 先试父目录所在的block group，再按父目录的inode号散列到其他block group，最后线性查找
+    let usable = |bgd_id: u32| has_free_inodes(bgd_id) && bgds[bgd_id as usize].get_free_blocks_count() > 0;
+    if usable(parent_group) {
+      return Some(parent_group);
+    }
+    let mut bgd_id = ((parent_group as u64 + parent) % group_count as u64) as u32;
+    let mut step = 1;
+    while step < group_count {
+      bgd_id = (bgd_id + step) % group_count;
+      if usable(bgd_id) {
+        return Some(bgd_id);
+      }
+      step <<= 1;
+    }
+    (1..=group_count)
+      .map(|i| (parent_group + i) % group_count)
+      .find(|&g| has_free_inodes(g))
+  }
+
+  // Orlov算法：顶层目录放到空闲inode和空闲块都不少于平均值的flex group里目录最少的一个，
+  // 其他目录从父目录所在的flex group开始找目录不太多、空闲inode和空闲块不太少的flex group，
+  // 找到flex group后用其中第一个有空闲inode的block group
+  fn find_group_orlov(&self, parent_group: u32, top_level: bool) -> Option<u32> {
+    let super_block = self.super_block.borrow();
+    let bgds = self.block_group_descriptors.borrow();
+    let group_count = super_block.get_block_group_count();
+    let flex_size = super_block.get_flex_size();
+    let flex_count = group_count.div_ceil(flex_size);
+    let inodes_per_group = super_block.inodes_per_group as u64;
+    let blocks_per_group = super_block.blocks_per_group as u64;
+    let flex_groups = |flex: u32| flex * flex_size..((flex + 1) * flex_size).min(group_count);
+    // flex group的空闲inode数、空闲块数和目录数
+    let stats = |flex: u32| {
+      flex_groups(flex).fold((0u64, 0u64, 0u64), |(inodes, blocks, dirs), g| {
+        let bgd = &bgds[g as usize];
+        (
+          inodes + bgd.get_free_inodes_count() as u64,
+          blocks + bgd.get_free_blocks_count() as u64,
+          dirs + bgd.get_used_dirs_count() as u64,
+        )
+      })
+    };
+    let free_inodes = super_block.get_free_inodes_count() as u64;
+    let avg_free_inodes = free_inodes / flex_count as u64;
+    let avg_free_blocks = super_block.get_free_blocks_count() / flex_count as u64;
+
+    let found = if top_level {
+      let mut best: Option<(u32, u64)> = None;
+      for flex in 0..flex_count {
+        let (inodes, blocks, dirs) = stats(flex);
+        if inodes == 0 || inodes < avg_free_inodes || blocks < avg_free_blocks {
+          continue;
         }
-        bgd.set_itable_unused(bgd.get_itable_unused() - 1);
-        bgd.set_checksum(bgd_id as u32, &self.super_block.borrow());
-
-        // block group descriptor写入disk
-        trace!("FileSystem::alloc_inode: write block group descriptor to disk");
-        {
-          let mut disk = self.disk.borrow_mut();
-          let mut offset = SuperBlock::PADDING_OFFSET + core::mem::size_of::<SuperBlock>();
-          offset += bgd_id * self.super_block.borrow().get_desc_size() as usize;
-          trace!("FileSystem::alloc_inode: offset: {}", offset);
-          disk.seek(SeekFrom::Start(offset as u64)).unwrap();
-          bgd.serialize(&mut *disk).unwrap();
+        if best.is_none_or(|(_, best_dirs)| dirs < best_dirs) {
+          best = Some((flex, dirs));
         }
+      }
+      best.map(|(flex, _)| flex)
+    } else {
+      let total_dirs: u64 = bgds.iter().map(|bgd| bgd.get_used_dirs_count() as u64).sum();
+      let max_dirs = total_dirs / flex_count as u64 + inodes_per_group * flex_size as u64 / 16;
+      let min_inodes = avg_free_inodes
+        .saturating_sub(inodes_per_group * flex_size as u64 / 4)
+        .max(1);
+      let min_blocks = avg_free_blocks.saturating_sub(blocks_per_group * flex_size as u64 / 4);
+      let parent_flex = parent_group / flex_size;
+      (0..flex_count).map(|i| (parent_flex + i) % flex_count).find(|&flex| {
+        let (inodes, blocks, dirs) = stats(flex);
+        dirs < max_dirs && inodes >= min_inodes && blocks >= min_blocks
+      })
+    };
+    let has_free_inodes = |bgd_id: u32| bgds[bgd_id as usize].get_free_inodes_count() > 0;
+    if let Some(bgd_id) = found.and_then(|flex| flex_groups(flex).find(|&g| has_free_inodes(g))) {
+      return Some(bgd_id);
+    }
 
-        // 更新super block
-        trace!("FileSystem::alloc_inode: update and write super block to disk");
-        {
-          let mut super_block = self.super_block.borrow_mut();
-          let sb_free_inodes_count = super_block.get_free_inodes_count();
-          super_block.set_free_inodes_count(sb_free_inodes_count - 1);
-          super_block.compute_and_set_checksum();
-          let mut disk = self.disk.borrow_mut();
-          super_block.serialize(&mut *disk).unwrap();
-        }
+    // 退而求其次：从父目录所在的block group开始，先找空闲inode不少于平均值的，再找任何有空闲inode的
+    let avg_free_inodes = free_inodes / group_count as u64;
+    for min_inodes in [avg_free_inodes.max(1), 1] {
+      let found = (0..group_count)
+        .map(|i| (parent_group + i) % group_count)
+        .find(|&g| bgds[g as usize].get_free_inodes_count() as u64 >= min_inodes);
+      if found.is_some() {
+        return found;
+      }
+    }
+    None
+  }
+
+  // 释放从start开始的count个块，这些块可以跨越多个block group
+  pub fn free_blocks(&self, start: u64, count: u64) -> Result<(), Error<IO::Error>> {
+    trace!("FileSystem::free_blocks start: {}, count: {}", start, count);
+    let (first_data_block, blocks_per_group, block_size) = {
+      let super_block = self.super_block.borrow();
+      (
+        super_block.first_data_block as u64,
+        super_block.blocks_per_group as u64,
+        super_block.get_block_size(),
+      )
+    };
+    match start.checked_add(count) {
+      Some(end) if start >= first_data_block && end <= self.super_block.borrow().get_blocks_count() => {}
+      _ => return Err(Error::InvalidInput),
+    }
+
+    // 先检查整个范围里的块都已经分配，再修改各个block group，避免释放到一半失败
+    let mut groups = Vec::new();
+    let mut block = start;
+    let end = start + count;
+    while block < end {
+      let bgd_id = ((block - first_data_block) / blocks_per_group) as usize;
+      let bit = (block - first_data_block) % blocks_per_group;
+      let len = (blocks_per_group - bit).min(end - block);
 
-        // +1 是因为inode从1开始
-        let new_ino = bgd_id as u64 * self.super_block.borrow().inodes_per_group as u64 + local_ino + 1;
-        trace!("FileSystem::alloc_inode: new_ino: {}", new_ino);
-        assert!((new_ino - 1) / self.super_block.borrow().inodes_per_group as u64 == bgd_id as u64);
-        return Ok(new_ino);
+      let block_bitmap_loc = self.block_group_descriptors.borrow()[bgd_id].get_block_bitmap_loc();
+      let block_bitmap = {
+        let mut disk = self.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(block_bitmap_loc * block_size))?;
+        Bitmap::deserialize(&mut *disk, blocks_per_group as usize / Bitmap::BITS_PER_ITEM)?
+      };
+      if let Some(i) = (bit..bit + len).find(|&i| !block_bitmap.get_bit(i)) {
+        error!("FileSystem::free_blocks: block {} is already free", block + i - bit);
+        return Err(Error::CorruptedFileSystem);
       }
+      groups.push((bgd_id, block_bitmap_loc, block_bitmap, bit, len));
+      block += len;
     }
 
-    Err(Error::NotEnoughSpace)
+    for (bgd_id, block_bitmap_loc, mut block_bitmap, bit, len) in groups {
+      for i in bit..bit + len {
+        block_bitmap.clear_bit(i);
+      }
+      // block bitmap写入disk
+      {
+        let mut disk = self.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(block_bitmap_loc * block_size))?;
+        block_bitmap.serialize(&mut *disk)?;
+      }
+
+      // 更新block group descriptor
+      let bgd = &mut self.block_group_descriptors.borrow_mut()[bgd_id];
+      bgd.set_block_bitmap_csum(&self.super_block.borrow(), &block_bitmap.data);
+      bgd.set_free_blocks_count(bgd.get_free_blocks_count() + len as u32);
+      bgd.set_checksum(bgd_id as u32, &self.super_block.borrow());
+      self.write_block_group_descriptor(bgd_id, bgd)?;
+
+      // 更新super block
+      {
+        let mut super_block = self.super_block.borrow_mut();
+        let sb_free_blocks_count = super_block.get_free_blocks_count();
+        super_block.set_free_blocks_count(sb_free_blocks_count + len);
+        super_block.compute_and_set_checksum();
+        let mut disk = self.disk.borrow_mut();
+        super_block.serialize(&mut *disk)?;
+      }
+    }
+    Ok(())
+  }
+
+  // 释放一个inode，只修改inode bitmap和计数，inode本身的内容由调用者负责清理
+  pub fn free_inode(&self, ino: u64, is_dir: bool) -> Result<(), Error<IO::Error>> {
+    trace!("FileSystem::free_inode ino: {}, is_dir: {}", ino, is_dir);
+    let (inodes_per_group, block_size) = {
+      let super_block = self.super_block.borrow();
+      (super_block.inodes_per_group as u64, super_block.get_block_size())
+    };
+    // 保留的inode不能释放
+    let super_block = self.super_block.borrow();
+    if ino < super_block.get_first_ino() as u64 || ino > super_block.get_inodes_count() as u64 {
+      return Err(Error::InvalidInput);
+    }
+    drop(super_block);
+    // -1是因为inode从1开始
+    let bgd_id = ((ino - 1) / inodes_per_group) as usize;
+    let local_ino = (ino - 1) % inodes_per_group;
+
+    let bgd = &mut self.block_group_descriptors.borrow_mut()[bgd_id];
+    let inode_bitmap_loc = bgd.get_inode_bitmap_loc();
+    let mut inode_bitmap = {
+      let mut disk = self.disk.borrow_mut();
+      disk.seek(SeekFrom::Start(inode_bitmap_loc * block_size))?;
+      Bitmap::deserialize(&mut *disk, inodes_per_group as usize / Bitmap::BITS_PER_ITEM)?
+    };
+    if !inode_bitmap.get_bit(local_ino) {
+      error!("FileSystem::free_inode: inode {} is already free", ino);
+      return Err(Error::CorruptedFileSystem);
+    }
+    if is_dir && bgd.get_used_dirs_count() == 0 {
+      error!(
+        "FileSystem::free_inode: used_dirs_count of group {} is already 0",
+        bgd_id
+      );
+      return Err(Error::CorruptedFileSystem);
+    }
+    inode_bitmap.clear_bit(local_ino);
+    // inode bitmap写入disk
+    {
+      let mut disk = self.disk.borrow_mut();
+      disk.seek(SeekFrom::Start(inode_bitmap_loc * block_size))?;
+      inode_bitmap.serialize(&mut *disk)?;
+    }
+
+    // 更新block group descriptor
+    bgd.set_inode_bitmap_csum(&self.super_block.borrow(), &inode_bitmap.data);
+    bgd.set_free_inodes_count(bgd.get_free_inodes_count() + 1);
+    if is_dir {
+      bgd.set_used_dirs_count(bgd.get_used_dirs_count() - 1);
+    }
+    bgd.set_checksum(bgd_id as u32, &self.super_block.borrow());
+    self.write_block_group_descriptor(bgd_id, bgd)?;
+
+    // 更新super block
+    {
+      let mut super_block = self.super_block.borrow_mut();
+      let sb_free_inodes_count = super_block.get_free_inodes_count();
+      super_block.set_free_inodes_count(sb_free_inodes_count + 1);
+      super_block.compute_and_set_checksum();
+      let mut disk = self.disk.borrow_mut();
+      super_block.serialize(&mut *disk)?;
+    }
+    Ok(())
+  }
+
+  fn write_block_group_descriptor(&self, bgd_id: usize, bgd: &BlockGroupDescriptor) -> Result<(), Error<IO::Error>> {
+    let offset = self.super_block.borrow().get_desc_pos(bgd_id as u32);
+    let desc_size = self.super_block.borrow().get_desc_size() as usize;
+    trace!(
+      "FileSystem::write_block_group_descriptor bgd_id: {}, offset: {}",
+      bgd_id,
+      offset
+    );
+    let mut disk = self.disk.borrow_mut();
+    disk.seek(SeekFrom::Start(offset))?;
+    bgd.serialize(&mut *disk, desc_size)?;
+    Ok(())
   }
 }
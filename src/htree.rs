@@ -0,0 +1,342 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::utils::crc::crc32c;
+
+// dx_root块里"."和".."之后的索引信息，8 bytes
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DxRootInfo {
+  pub reserved_zero: u32,  // 0
+  pub hash_version: u8,    // 散列算法
+  pub info_length: u8,     // 8
+  pub indirect_levels: u8, // root下面dx_node的层数
+  pub unused_flags: u8,
+}
+
+impl DxRootInfo {
+  // 在root块里的偏移，前面是"."和".."两个entry
+  pub const OFFSET: usize = 24;
+  pub const INFO_LENGTH: u8 = 8;
+  // root里DxCountLimit的偏移
+  pub const NODE_OFFSET: usize = Self::OFFSET + Self::INFO_LENGTH as usize;
+
+  // 散列算法，*_UNSIGNED按无符号char处理文件名，由超级块的flags决定
+  pub const HASH_LEGACY: u8 = 0;
+  pub const HASH_HALF_MD4: u8 = 1;
+  pub const HASH_TEA: u8 = 2;
+  pub const HASH_LEGACY_UNSIGNED: u8 = 3;
+  pub const HASH_HALF_MD4_UNSIGNED: u8 = 4;
+  pub const HASH_TEA_UNSIGNED: u8 = 5;
+
+  pub fn new(hash_version: u8) -> Self {
+    Self {
+      reserved_zero: 0,
+      hash_version,
+      info_length: Self::INFO_LENGTH,
+      indirect_levels: 0,
+      unused_flags: 0,
+    }
+  }
+
+  pub fn load_from_u8(data: &[u8]) -> Self {
+    unsafe { core::ptr::read_unaligned(data.as_ptr() as *const _) }
+  }
+
+  pub fn load_from_u8_mut(data: &mut [u8]) -> &mut Self {
+    unsafe { &mut *(data.as_mut_ptr() as *mut _) }
+  }
+}
+
+// 8 bytes，每个索引块里第一个dx_entry的hash字段保存limit和count
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DxCountLimit {
+  pub limit: u16, // 块里最多能放的dx_entry数
+  pub count: u16, // 块里实际的dx_entry数，包括第一个
+}
+
+// 8 bytes
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DxEntry {
+  pub hash: u32,  // 这个entry对应的最小hash，第一个entry隐含为0
+  pub block: u32, // 下一层的逻辑块号
+}
+
+// 8 bytes，开启metadata_csum时放在dx_entry数组的limit之后
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DxTail {
+  pub reserved: u32,
+  pub checksum: u32,
+}
+
+impl DxTail {
+  // 校验块的开头到最后一个有效的dx_entry，再加上tail的reserved字段
+  pub fn compute_checksum(block: &[u8], node: &DxNode, uuid: &[u8], ino: u32, ino_gen: u32) -> u32 {
+    let size = node.offset + node.entries.len() * core::mem::size_of::<DxEntry>();
+    let tail = node.tail_offset();
+    let mut csum = crc32c(!0, uuid, uuid.len() as u32);
+    csum = crc32c(csum, &ino.to_le_bytes(), 4);
+    csum = crc32c(csum, &ino_gen.to_le_bytes(), 4);
+    csum = crc32c(csum, &block[..size], size as u32);
+    csum = crc32c(csum, &block[tail..tail + 4], 4);
+    crc32c(csum, &[0; 4], 4)
+  }
+
+  pub fn get_checksum(block: &[u8], node: &DxNode) -> u32 {
+    let offset = node.tail_offset() + 4;
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+  }
+
+  pub fn set_checksum(block: &mut [u8], node: &DxNode, checksum: u32) {
+    let offset = node.tail_offset();
+    block[offset..offset + 4].fill(0);
+    block[offset + 4..offset + 8].copy_from_slice(&checksum.to_le_bytes());
+  }
+}
+
+// 索引块里的dx_entry数组
+#[derive(Debug, Clone)]
+pub struct DxNode {
+  pub offset: usize, // DxCountLimit在块里的偏移
+  pub limit: u16,
+  pub entries: Vec<DxEntry>,
+}
+
+// 查找路径上的一层索引：索引块的物理块号、块里的dx_entry和选中的下标
+#[derive(Debug, Clone)]
+pub struct DxFrame {
+  pub pblock: u64,
+  pub node: DxNode,
+  pub idx: usize,
+}
+
+impl DxNode {
+  // dx_node块的开头是一个inode为0、rec_len为块大小的空entry
+  pub const NODE_OFFSET: usize = 8;
+
+  // 从offset开始的dx_entry数组，开启metadata_csum时末尾要留给tail
+  pub fn new(block_size: usize, offset: usize, has_csum: bool) -> Self {
+    let mut space = block_size - offset;
+    if has_csum {
+      space -= core::mem::size_of::<DxTail>();
+    }
+    Self {
+      offset,
+      limit: (space / core::mem::size_of::<DxEntry>()) as u16,
+      entries: Vec::new(),
+    }
+  }
+
+  // 初始化一个dx_node块：只有一个占满整个块的空entry
+  pub fn init_node_block(block: &mut [u8]) {
+    let rec_len = block.len() as u16;
+    block.fill(0);
+    block[4..6].copy_from_slice(&rec_len.to_le_bytes());
+  }
+
+  pub fn tail_offset(&self) -> usize {
+    self.offset + self.limit as usize * core::mem::size_of::<DxEntry>()
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.entries.len() >= self.limit as usize
+  }
+
+  // 把limit、count和dx_entry写回块里，entry数超过limit时不写入，返回false
+  pub fn write(&self, block: &mut [u8]) -> bool {
+    let entry_size = core::mem::size_of::<DxEntry>();
+    if self.entries.len() > self.limit as usize || self.tail_offset() > block.len() {
+      return false;
+    }
+    block[self.offset..self.offset + 2].copy_from_slice(&self.limit.to_le_bytes());
+    block[self.offset + 2..self.offset + 4].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+    for (i, entry) in self.entries.iter().enumerate() {
+      let off = self.offset + i * entry_size;
+      if i > 0 {
+        block[off..off + 4].copy_from_slice(&entry.hash.to_le_bytes());
+      }
+      block[off + 4..off + 8].copy_from_slice(&entry.block.to_le_bytes());
+    }
+    true
+  }
+
+  // 从块里解析出dx_entry数组，count或limit不合法时返回None
+  pub fn parse(block: &[u8], offset: usize) -> Option<Self> {
+    let read_u16 = |off: usize| u16::from_le_bytes(block[off..off + 2].try_into().unwrap());
+    let read_u32 = |off: usize| u32::from_le_bytes(block[off..off + 4].try_into().unwrap());
+    let limit = read_u16(offset);
+    let count = read_u16(offset + 2);
+    let entry_size = core::mem::size_of::<DxEntry>();
+    if count == 0 || count > limit || offset + limit as usize * entry_size > block.len() {
+      return None;
+    }
+    let entries = (0..count as usize)
+      .map(|i| DxEntry {
+        hash: if i == 0 { 0 } else { read_u32(offset + i * entry_size) },
+        block: read_u32(offset + i * entry_size + 4),
+      })
+      .collect();
+    Some(Self { offset, limit, entries })
+  }
+
+  // 最后一个hash不大于hash的entry
+  pub fn lookup(&self, hash: u32) -> usize {
+    self.entries[1..].partition_point(|e| e.hash <= hash)
+  }
+}
+
+// 计算文件名的(hash, minor_hash)，和Linux的ext4fs_dirhash一致，不支持的算法返回None
+// seed全为0时使用默认的种子
+pub fn dx_hash(name: &[u8], hash_version: u8, seed: &[u32; 4]) -> Option<(u32, u32)> {
+  let mut buf = if seed.iter().any(|&s| s != 0) {
+    *seed
+  } else {
+    [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476]
+  };
+  let (hash, minor_hash) = match hash_version {
+    DxRootInfo::HASH_LEGACY => (dx_hack_hash(name, true), 0),
+    DxRootInfo::HASH_LEGACY_UNSIGNED => (dx_hack_hash(name, false), 0),
+    DxRootInfo::HASH_HALF_MD4 | DxRootInfo::HASH_HALF_MD4_UNSIGNED => {
+      let signed = hash_version == DxRootInfo::HASH_HALF_MD4;
+      let mut input = [0u32; 8];
+      let mut rest = name;
+      while !rest.is_empty() {
+        str2hashbuf(rest, &mut input, signed);
+        half_md4_transform(&mut buf, &input);
+        rest = &rest[rest.len().min(32)..];
+      }
+      (buf[1], buf[2])
+    }
+    DxRootInfo::HASH_TEA | DxRootInfo::HASH_TEA_UNSIGNED => {
+      let signed = hash_version == DxRootInfo::HASH_TEA;
+      let mut input = [0u32; 4];
+      let mut rest = name;
+      while !rest.is_empty() {
+        str2hashbuf(rest, &mut input, signed);
+        tea_transform(&mut buf, &input);
+        rest = &rest[rest.len().min(16)..];
+      }
+      (buf[0], buf[1])
+    }
+    _ => return None,
+  };
+  // 最低位用来标记hash冲突，0x7fffffff << 1表示目录结束
+  let mut hash = hash & !1;
+  if hash == 0x7fffffff << 1 {
+    hash = (0x7fffffff - 1) << 1;
+  }
+  Some((hash, minor_hash))
+}
+
+// 按有符号或无符号char读取文件名的一个字节
+fn name_byte(c: u8, signed: bool) -> u32 {
+  if signed {
+    c as i8 as i32 as u32
+  } else {
+    c as u32
+  }
+}
+
+// 最早的散列算法
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+  let (mut hash0, mut hash1) = (0x12a3fe2du32, 0x37abe8f9u32);
+  for &c in name {
+    let mut hash = hash1.wrapping_add(hash0 ^ name_byte(c, signed).wrapping_mul(7152373));
+    if hash & 0x80000000 != 0 {
+      hash = hash.wrapping_sub(0x7fffffff);
+    }
+    hash1 = hash0;
+    hash0 = hash;
+  }
+  hash0 << 1
+}
+
+// 把文件名剩下的部分打包成散列算法的输入，不足的部分用剩余长度填充
+fn str2hashbuf(msg: &[u8], buf: &mut [u32], signed: bool) {
+  let len = msg.len();
+  let mut pad = len as u32 | ((len as u32) << 8);
+  pad |= pad << 16;
+  let mut val = pad;
+  let len = len.min(buf.len() * 4);
+  let mut idx = 0;
+  for (i, &c) in msg[..len].iter().enumerate() {
+    val = name_byte(c, signed).wrapping_add(val << 8);
+    if i % 4 == 3 {
+      buf[idx] = val;
+      idx += 1;
+      val = pad;
+    }
+  }
+  if idx < buf.len() {
+    buf[idx] = val;
+    idx += 1;
+  }
+  for b in &mut buf[idx..] {
+    *b = pad;
+  }
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+  const K1: u32 = 0;
+  const K2: u32 = 0x5A827999;
+  const K3: u32 = 0x6ED9EBA1;
+  let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+  let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+  let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+  let round = |func: &dyn Fn(u32, u32, u32) -> u32, a: u32, b: u32, c: u32, d: u32, x: u32, s: u32| {
+    a.wrapping_add(func(b, c, d)).wrapping_add(x).rotate_left(s)
+  };
+  let [mut a, mut b, mut c, mut d] = *buf;
+
+  // Round 1
+  a = round(&f, a, b, c, d, input[0].wrapping_add(K1), 3);
+  d = round(&f, d, a, b, c, input[1].wrapping_add(K1), 7);
+  c = round(&f, c, d, a, b, input[2].wrapping_add(K1), 11);
+  b = round(&f, b, c, d, a, input[3].wrapping_add(K1), 19);
+  a = round(&f, a, b, c, d, input[4].wrapping_add(K1), 3);
+  d = round(&f, d, a, b, c, input[5].wrapping_add(K1), 7);
+  c = round(&f, c, d, a, b, input[6].wrapping_add(K1), 11);
+  b = round(&f, b, c, d, a, input[7].wrapping_add(K1), 19);
+
+  // Round 2
+  a = round(&g, a, b, c, d, input[1].wrapping_add(K2), 3);
+  d = round(&g, d, a, b, c, input[3].wrapping_add(K2), 5);
+  c = round(&g, c, d, a, b, input[5].wrapping_add(K2), 9);
+  b = round(&g, b, c, d, a, input[7].wrapping_add(K2), 13);
+  a = round(&g, a, b, c, d, input[0].wrapping_add(K2), 3);
+  d = round(&g, d, a, b, c, input[2].wrapping_add(K2), 5);
+  c = round(&g, c, d, a, b, input[4].wrapping_add(K2), 9);
+  b = round(&g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+  // Round 3
+  a = round(&h, a, b, c, d, input[3].wrapping_add(K3), 3);
+  d = round(&h, d, a, b, c, input[7].wrapping_add(K3), 9);
+  c = round(&h, c, d, a, b, input[2].wrapping_add(K3), 11);
+  b = round(&h, b, c, d, a, input[6].wrapping_add(K3), 15);
+  a = round(&h, a, b, c, d, input[1].wrapping_add(K3), 3);
+  d = round(&h, d, a, b, c, input[5].wrapping_add(K3), 9);
+  c = round(&h, c, d, a, b, input[0].wrapping_add(K3), 11);
+  b = round(&h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+  buf[0] = buf[0].wrapping_add(a);
+  buf[1] = buf[1].wrapping_add(b);
+  buf[2] = buf[2].wrapping_add(c);
+  buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+  const DELTA: u32 = 0x9E3779B9;
+  let (mut b0, mut b1) = (buf[0], buf[1]);
+  let [a, b, c, d] = *input;
+  let mut sum = 0u32;
+  for _ in 0..16 {
+    sum = sum.wrapping_add(DELTA);
+    b0 = b0.wrapping_add((b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b));
+    b1 = b1.wrapping_add((b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d));
+  }
+  buf[0] = buf[0].wrapping_add(b0);
+  buf[1] = buf[1].wrapping_add(b1);
+}
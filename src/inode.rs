@@ -1,8 +1,11 @@
 use bitflags::bitflags;
 
 extern crate alloc;
-use crate::extent::{Extent, ExtentHeader};
-use crate::io::{Read, Write};
+use crate::block_map::{read_block_map, DIRECT_BLOCKS};
+use crate::error::Error;
+use crate::extent::{Extent, ExtentHeader, ExtentNode, ExtentTail};
+use crate::io::{Read, Seek, SeekFrom, Write};
+use crate::super_block::SuperBlock;
 use crate::utils::{combine_u64, crc::crc32c};
 use alloc::vec::Vec;
 
@@ -145,6 +148,20 @@ impl Inode {
   // FIXME: 为什么
   // FIXME: ref: https://github.com/yuoo655/ext4_rs/blob/7b601d2b5e110737cfccd1570235bd3218cc537e/src/ext4_defs/consts.rs
   pub const INODE_BLOCK_SIZE: usize = 512;
+  pub const GOOD_OLD_INODE_SIZE: u16 = 128; // ext2原始版本的inode大小，也是inode基本部分的大小
+
+  pub const MAX_LINKS_COUNT: u16 = 65000; // 最大链接数
+
+  // 扩展属性
+  pub const XATTR_MAGIC: u32 = 0xEA020000; // inode内扩展属性的魔数
+  pub const XATTR_INDEX_SYSTEM: u8 = 7; // system.*命名空间，inline data保存在system.data里
+
+  // 快速符号链接的目标直接保存在inode.block里，最大长度不含结尾的0
+  pub const FAST_SYMLINK_MAX_LEN: usize = 59;
+
+  // 设备号
+  pub const MAX_DEVICE_MAJOR: u32 = 0xFFF; // 主设备号最大值
+  pub const MAX_DEVICE_MINOR: u32 = 0xFFFFF; // 次设备号最大值
 }
 
 impl Inode {
@@ -165,6 +182,37 @@ impl Inode {
     Ok(())
   }
 
+  // 结构体里实际保存在磁盘上的字节数：128字节的基本部分加上extra_isize描述的扩展部分
+  // 128字节的inode后面紧接着下一个inode，扩展部分之后可能是inode内的扩展属性
+  pub fn get_disk_len(&self, inode_size: u64) -> usize {
+    let base = Self::GOOD_OLD_INODE_SIZE as usize;
+    if inode_size as usize <= base {
+      return base;
+    }
+    (base + self.extra_isize as usize)
+      .min(inode_size as usize)
+      .min(core::mem::size_of::<Self>())
+  }
+
+  // 从磁盘读出inode之后，把结构体里不属于这个inode的部分清零
+  pub fn clear_beyond_disk_len(&mut self, inode_size: u64) {
+    if inode_size as usize <= Self::GOOD_OLD_INODE_SIZE as usize {
+      self.extra_isize = 0;
+    }
+    let len = self.get_disk_len(inode_size);
+    let self_bytes =
+      unsafe { core::slice::from_raw_parts_mut(self as *mut _ as *mut u8, core::mem::size_of::<Self>()) };
+    self_bytes[len..].fill(0);
+  }
+
+  // 只写入保存在磁盘上的部分
+  pub fn serialize_to_disk<W: Write>(&self, writer: &mut W, inode_size: u64) -> Result<(), W::Error> {
+    let self_bytes =
+      unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>()) };
+    writer.write_all(&self_bytes[..self.get_disk_len(inode_size)])?;
+    Ok(())
+  }
+
   pub fn get_size(&self) -> u64 {
     combine_u64(self.size_lo, self.size_hi)
   }
@@ -174,6 +222,26 @@ impl Inode {
     self.size_hi = (size >> 32) as u32;
   }
 
+  // 以512字节为单位
+  pub fn get_blocks_count(&self) -> u64 {
+    combine_u64(self.blocks_lo, self.osd2.blocks_high as u32)
+  }
+
+  pub fn set_blocks_count(&mut self, count: u64) {
+    self.blocks_lo = count as u32;
+    self.osd2.blocks_high = (count >> 32) as u16;
+  }
+
+  // 扩展属性块的块号，0表示没有
+  pub fn get_file_acl(&self) -> u64 {
+    combine_u64(self.file_acl_lo, self.osd2.file_acl_high as u32)
+  }
+
+  pub fn set_file_acl(&mut self, block: u64) {
+    self.file_acl_lo = block as u32;
+    self.osd2.file_acl_high = (block >> 32) as u16;
+  }
+
   pub fn get_file_perm(&self) -> InodeFilePerm {
     InodeFilePerm::from_bits_truncate(self.mode & Inode::FILEPERM_MASK)
   }
@@ -206,54 +274,167 @@ impl Inode {
     self.get_flags().contains(InodeFlags::EXTENTS_FL)
   }
 
-  pub fn get_extents<R: Read>(&self, _reader: &mut R) -> Result<Vec<Extent>, R::Error> {
+  // inode.block里保存的是ext2/ext3的块映射：不使用extent的普通文件、目录和保存在数据块里的符号链接
+  // 设备文件、FIFO、socket、快速符号链接以及inline data的inode.block里是别的内容
+  pub fn use_block_map(&self) -> bool {
+    if self.use_extents() || self.get_flags().contains(InodeFlags::INLINE_DATA_FL) {
+      return false;
+    }
+    self.is_file() || self.is_dir() || (self.is_symlink() && self.get_size() > Inode::FAST_SYMLINK_MAX_LEN as u64)
+  }
+
+  // inode的所有extent，按逻辑块号排序。使用块映射的inode把连续的块合并成extent返回
+  // 还不支持inline data的文件和目录，返回Unsupported
+  pub fn get_extents<R: Read + Seek>(
+    &self,
+    ino: u64,
+    reader: &mut R,
+    super_block: &SuperBlock,
+  ) -> Result<Vec<Extent>, Error<R::Error>> {
+    // inline data保存在inode里，没有数据块
+    if self.get_flags().contains(InodeFlags::INLINE_DATA_FL) {
+      return Err(Error::Unsupported);
+    }
+    if !self.use_extents() {
+      return read_block_map(self, reader, super_block);
+    }
     let mut extents = Vec::new();
-    assert!(self.use_extents());
+    let root = unsafe { &*(self.block.as_ptr() as *const [u8; 60]) };
+    self.collect_extents(ino, root, None, reader, super_block, &mut extents)?;
+    extents.sort_by_key(|a| a.block);
+    Ok(extents)
+  }
 
-    let mut root_node_offset = 0;
-    let root_eh = {
-      let buffer = unsafe { &mut *(self.block.as_ptr() as *mut [u8; 60]) };
-      ExtentHeader::load_from_u8(&buffer[root_node_offset..])
-    };
-    root_node_offset += core::mem::size_of::<ExtentHeader>();
-    if root_eh.is_leaf() {
-      for _ in 0..root_eh.entries {
-        let extent = {
-          let buffer = unsafe { &mut *(self.block.as_ptr() as *mut [u8; 60]) };
-          Extent::load_from_u8(&buffer[root_node_offset..])
-        };
-        root_node_offset += core::mem::size_of::<Extent>();
-        extents.push(extent);
+  // 递归读取一个extent tree节点下的所有extent
+  // 根节点在inode.block里，其余节点各占一个块，块末尾有ExtentTail校验和
+  fn collect_extents<R: Read + Seek>(
+    &self,
+    ino: u64,
+    data: &[u8],
+    expected_depth: Option<u16>,
+    reader: &mut R,
+    super_block: &SuperBlock,
+    extents: &mut Vec<Extent>,
+  ) -> Result<(), Error<R::Error>> {
+    let node = match ExtentNode::parse(data, None) {
+      Some(node) if expected_depth.is_none_or(|d| d == node.depth) => node,
+      _ => {
+        error!(
+          "Inode::collect_extents: invalid extent header {:?}",
+          ExtentHeader::load_from_u8(data)
+        );
+        return Err(Error::CorruptedFileSystem);
       }
-    } else {
-      unimplemented!();
+    };
+    if expected_depth.is_some()
+      && super_block.has_feature_ro_compat_metadata_csum()
+      && !ExtentTail::verify_checksum(data, &super_block.uuid, ino as u32, self.generation)
+    {
+      error!("Inode::collect_extents: extent block checksum mismatch, ino: {}", ino);
+      return Err(Error::CorruptedFileSystem);
     }
 
-    extents.sort_by(|a, b| a.block.cmp(&b.block));
-    Ok(extents)
+    extents.extend_from_slice(&node.extents);
+    let block_size = super_block.get_block_size();
+    for idx in node.indexes.iter() {
+      let mut child = vec![0u8; block_size as usize];
+      reader.seek(SeekFrom::Start(idx.get_extent_idx() * block_size))?;
+      reader.read_exact(&mut child)?;
+      self.collect_extents(ino, &child, Some(node.depth - 1), reader, super_block, extents)?;
+    }
+    Ok(())
   }
 
   pub fn init_extent_tree(&mut self, extents: Vec<Extent>) {
     trace!("Inode::init_extent_tree: extents: {:?}", extents);
+    // 根节点在inode.block里，最多只能放4个extent
+    assert!(extents.len() <= 4);
     let header = self.block.as_mut_ptr() as *mut ExtentHeader;
     unsafe {
       (*header).set_magic();
-      (*header).entries = 1;
+      (*header).entries = extents.len() as u16;
       (*header).max = 4;
       (*header).depth = 0;
       (*header).generation = 0;
     }
-    assert_eq!(extents.len(), 1);
-    let extent = extents[0];
-    unsafe {
-      // let extent_ptr = self.block.as_mut_ptr().add(core::mem::size_of::<ExtentHeader>()) as *mut Extent;
-      let block_ptr = self.block.as_mut_ptr() as *mut u8;
-      let extent_ptr = block_ptr.add(core::mem::size_of::<ExtentHeader>()) as *mut Extent;
-      (*extent_ptr).block = extent.block;
-      (*extent_ptr).len = extent.len;
-      (*extent_ptr).start_hi = extent.start_hi;
-      (*extent_ptr).start_lo = extent.start_lo;
+    for (i, extent) in extents.iter().enumerate() {
+      unsafe {
+        let block_ptr = self.block.as_mut_ptr() as *mut u8;
+        let extent_ptr =
+          block_ptr.add(core::mem::size_of::<ExtentHeader>() + i * core::mem::size_of::<Extent>()) as *mut Extent;
+        (*extent_ptr).block = extent.block;
+        (*extent_ptr).len = extent.len;
+        (*extent_ptr).start_hi = extent.start_hi;
+        (*extent_ptr).start_lo = extent.start_lo;
+      }
+    }
+  }
+
+  // 不开启extents时新inode使用块映射，extent只能落在直接块的范围内
+  pub fn init_block_map(&mut self, extents: &[Extent]) {
+    trace!("Inode::init_block_map: extents: {:?}", extents);
+    self.block = [0; 15];
+    for extent in extents {
+      assert!(extent.get_end_block() <= DIRECT_BLOCKS as u64);
+      for lblock in extent.block as u64..extent.get_end_block() {
+        self.block[lblock as usize] = extent.map_block(lblock).unwrap() as u32;
+      }
+    }
+  }
+
+  pub fn is_device(&self) -> bool {
+    let file_type = self.get_file_type();
+    file_type == InodeFileType::CHR || file_type == InodeFileType::BLK
+  }
+
+  // 设备文件的主次设备号保存在inode.block里，编码方式和Linux一样：
+  // 主次设备号都小于256时用旧的16位编码保存在block[0]，否则用新的32位编码保存在block[1]
+  pub fn get_device(&self) -> Option<(u32, u32)> {
+    if !self.is_device() {
+      return None;
+    }
+    if self.block[0] != 0 {
+      let dev = self.block[0];
+      Some(((dev >> 8) & 0xFF, dev & 0xFF))
+    } else {
+      let dev = self.block[1];
+      Some(((dev & 0xFFF00) >> 8, (dev & 0xFF) | ((dev >> 12) & 0xFFF00)))
+    }
+  }
+
+  pub fn set_device(&mut self, major: u32, minor: u32) {
+    assert!(major <= Self::MAX_DEVICE_MAJOR && minor <= Self::MAX_DEVICE_MINOR);
+    self.block = [0; 15];
+    if major < 256 && minor < 256 {
+      self.block[0] = (major << 8) | minor;
+    } else {
+      self.block[1] = (minor & 0xFF) | (major << 8) | ((minor & !0xFF) << 12);
+    }
+  }
+
+  // 在inode的额外空间(128 + extra_isize之后)里查找扩展属性，raw是磁盘上完整的inode
+  pub fn find_inline_xattr(&self, raw: &[u8], name_index: u8, name: &[u8]) -> Option<Vec<u8>> {
+    let start = 128 + self.extra_isize as usize;
+    if raw.len() < start + 4 || u32::from_le_bytes(raw[start..start + 4].try_into().unwrap()) != Self::XATTR_MAGIC {
+      return None;
+    }
+    // 属性值的偏移相对于第一个条目
+    let entries_start = start + 4;
+    let mut offset = entries_start;
+    // 条目头16字节，之后是名字，整体按4字节对齐
+    while offset + 16 <= raw.len() && raw[offset..offset + 4] != [0u8; 4] {
+      let name_len = raw[offset] as usize;
+      let index = raw[offset + 1];
+      let value_offs = u16::from_le_bytes([raw[offset + 2], raw[offset + 3]]) as usize;
+      let value_size = u32::from_le_bytes(raw[offset + 8..offset + 12].try_into().unwrap()) as usize;
+      let entry_name = raw.get(offset + 16..offset + 16 + name_len)?;
+      if index == name_index && entry_name == name {
+        let value_start = entries_start + value_offs;
+        return raw.get(value_start..value_start + value_size).map(|v| v.to_vec());
+      }
+      offset += (16 + name_len).div_ceil(4) * 4;
     }
+    None
   }
 
   pub fn get_checksum(&self) -> u32 {
@@ -262,7 +443,9 @@ impl Inode {
     csum
   }
 
-  pub fn compute_checksum(&mut self, ino: u32, inode_size: u16, uuid: &[u8]) -> u32 {
+  // 校验和覆盖磁盘上完整的inode_size字节，raw是磁盘上原有的inode
+  // 结构体保存在磁盘上的部分替换raw的开头，之后的inode内扩展属性保持raw里的内容
+  pub fn compute_checksum(&mut self, ino: u32, raw: &[u8], uuid: &[u8]) -> u32 {
     let original_checksum_lo = self.osd2.checksum_lo;
     let original_checksum_hi = self.checksum_hi;
     self.osd2.checksum_lo = 0;
@@ -272,25 +455,26 @@ impl Inode {
     csum = crc32c(csum, &ino.to_le_bytes(), 4);
     csum = crc32c(csum, &self.generation.to_le_bytes(), 4);
 
-    let mut inode_data = vec![0u8; inode_size as usize];
+    let mut inode_data = raw.to_vec();
+    let len = self.get_disk_len(raw.len() as u64);
     unsafe {
       let inode_data_ptr = self as *const Inode as *const u8;
-      core::ptr::copy_nonoverlapping(inode_data_ptr, inode_data.as_mut_ptr(), core::mem::size_of::<Inode>());
+      core::ptr::copy_nonoverlapping(inode_data_ptr, inode_data.as_mut_ptr(), len);
     }
-    csum = crc32c(csum, &inode_data, inode_size as u32);
+    csum = crc32c(csum, &inode_data, inode_data.len() as u32);
 
     self.osd2.checksum_lo = original_checksum_lo;
     self.checksum_hi = original_checksum_hi;
     csum
   }
 
-  pub fn compute_and_set_checksum(&mut self, ino: u32, inode_size: u16, uuid: &[u8]) {
+  pub fn compute_and_set_checksum(&mut self, ino: u32, raw: &[u8], uuid: &[u8]) {
     // 计算checksum
-    let csum = self.compute_checksum(ino, inode_size, uuid);
+    let csum = self.compute_checksum(ino, raw, uuid);
     // 设置checksum
     self.osd2.checksum_lo = (csum & 0xFFFF) as u16;
     // TODO: hard code
-    if inode_size > 128 {
+    if raw.len() > 128 {
       self.checksum_hi = (csum >> 16) as u16;
     }
   }
@@ -8,6 +8,7 @@ extern crate alloc;
 #[macro_use]
 mod log_macros;
 
+pub mod block_map;
 pub mod descriptor;
 pub mod dir;
 pub mod dir_entry;
@@ -15,6 +16,7 @@ pub mod error;
 pub mod extent;
 pub mod file;
 pub mod fs;
+pub mod htree;
 pub mod inode;
 pub mod io;
 pub mod super_block;
@@ -191,6 +191,116 @@ impl SuperBlock {
   pub fn has_feature_ro_compat_metadata_csum(&self) -> bool {
     self.get_feature_ro_compat().contains(FeatureROCompat::METADATA_CSUM)
   }
+
+  pub fn has_feature_incompat_extents(&self) -> bool {
+    self.get_feature_incompat().contains(FeatureIncompat::EXTENTS)
+  }
+
+  pub fn has_feature_incompat_64bit(&self) -> bool {
+    self.get_feature_incompat().contains(FeatureIncompat::_64BIT)
+  }
+
+  pub fn has_feature_incompat_meta_bg(&self) -> bool {
+    self.get_feature_incompat().contains(FeatureIncompat::META_BG)
+  }
+
+  pub fn has_feature_incompat_flex_bg(&self) -> bool {
+    self.get_feature_incompat().contains(FeatureIncompat::FLEX_BG)
+  }
+
+  pub fn has_feature_ro_compat_gdt_csum(&self) -> bool {
+    self.get_feature_ro_compat().contains(FeatureROCompat::GDT_CSUM)
+  }
+
+  pub fn has_feature_compat_dir_index(&self) -> bool {
+    self.get_feature_compat().contains(FeatureCompat::DIR_INDEX)
+  }
+
+  pub fn has_feature_incompat_largedir(&self) -> bool {
+    self.get_feature_incompat().contains(FeatureIncompat::LARGEDIR)
+  }
+}
+
+impl SuperBlock {
+  pub const MAGIC: u16 = 0xEF53;
+  // 原始版本的文件系统没有first_ino和inode_size字段
+  pub const GOOD_OLD_REV: u32 = 0;
+  pub const GOOD_OLD_FIRST_INO: u32 = 11;
+  pub const GOOD_OLD_INODE_SIZE: u16 = 128;
+  // block group descriptor的大小
+  pub const MIN_DESC_SIZE: u16 = 32;
+  pub const MIN_DESC_SIZE_64BIT: u16 = 64;
+
+  // 已经实现的特性，incompat和ro_compat里出现其它特性时不能挂载
+  pub const SUPPORTED_INCOMPAT: FeatureIncompat = FeatureIncompat::FILETYPE
+    .union(FeatureIncompat::META_BG)
+    .union(FeatureIncompat::EXTENTS)
+    .union(FeatureIncompat::_64BIT)
+    .union(FeatureIncompat::FLEX_BG)
+    .union(FeatureIncompat::LARGEDIR)
+    .union(FeatureIncompat::INLINE_DATA);
+  pub const SUPPORTED_RO_COMPAT: FeatureROCompat = FeatureROCompat::SPARSE_SUPER
+    .union(FeatureROCompat::LARGE_FILE)
+    .union(FeatureROCompat::BTREE_DIR)
+    .union(FeatureROCompat::HUGE_FILE)
+    .union(FeatureROCompat::GDT_CSUM)
+    .union(FeatureROCompat::DIR_NLINK)
+    .union(FeatureROCompat::EXTRA_ISIZE)
+    .union(FeatureROCompat::METADATA_CSUM);
+
+  pub fn is_valid(&self) -> bool {
+    if self.magic != Self::MAGIC {
+      return false;
+    }
+    // 开启64bit时desc_size至少是64字节，并且是2的幂
+    let desc_size = self.get_desc_size();
+    !self.has_feature_incompat_64bit()
+      || (desc_size >= Self::MIN_DESC_SIZE_64BIT as u64
+        && desc_size.is_power_of_two()
+        && desc_size <= self.get_block_size())
+  }
+
+  // 检查是否可以读写这个文件系统。compat特性不影响读写，ext3的日志在不需要恢复时可以直接忽略
+  pub fn check_features(&self) -> bool {
+    if self.get_feature_incompat().contains(FeatureIncompat::RECOVER) {
+      error!("SuperBlock::check_features: journal needs recovery");
+      return false;
+    }
+    let incompat = self.feature_incompat & !Self::SUPPORTED_INCOMPAT.bits();
+    let ro_compat = self.feature_ro_compat & !Self::SUPPORTED_RO_COMPAT.bits();
+    if incompat != 0 || ro_compat != 0 {
+      error!(
+        "SuperBlock::check_features: unsupported incompat: {:#x}, ro_compat: {:#x}",
+        incompat, ro_compat
+      );
+      return false;
+    }
+    // 超过64字节的descriptor有保留字段，写回和计算校验和时都需要原始字节，暂不支持
+    if self.has_feature_incompat_64bit() && self.desc_size != Self::MIN_DESC_SIZE_64BIT {
+      error!("SuperBlock::check_features: unsupported desc_size: {}", self.desc_size);
+      return false;
+    }
+    true
+  }
+}
+
+impl SuperBlock {
+  // flags里的目录散列标志
+  pub const FLAGS_SIGNED_HASH: u32 = 0x1;
+  pub const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+  pub fn get_hash_seed(&self) -> [u32; 4] {
+    self.hash_seed
+  }
+
+  pub fn get_def_hash_version(&self) -> u8 {
+    self.def_hash_version
+  }
+
+  // 文件名是否按无符号char计算散列
+  pub fn is_hash_unsigned(&self) -> bool {
+    self.flags & Self::FLAGS_UNSIGNED_HASH != 0
+  }
 }
 
 impl SuperBlock {
@@ -218,19 +328,122 @@ impl SuperBlock {
     1024 << self.log_block_size
   }
 
+  pub fn get_blocks_count(&self) -> u64 {
+    combine_u64(self.blocks_count_lo, self.blocks_count_hi)
+  }
+
   pub fn get_block_group_count(&self) -> u32 {
-    let blocks_count = combine_u64(self.blocks_count_lo, self.blocks_count_hi);
+    // 第一个block group从first_data_block开始
+    let blocks_count = self.get_blocks_count() - self.first_data_block as u64;
     let blocks_per_group = self.blocks_per_group as u64;
-    let block_group_count = (blocks_count + blocks_per_group - 1) / blocks_per_group;
+    let block_group_count = blocks_count.div_ceil(blocks_per_group);
     block_group_count as u32
   }
 
+  // block group的第一个块
+  pub fn get_group_first_block(&self, bgd_id: u32) -> u64 {
+    self.first_data_block as u64 + bgd_id as u64 * self.blocks_per_group as u64
+  }
+
+  // block group里的块数，最后一个block group可能不满
+  pub fn get_group_blocks(&self, bgd_id: u32) -> u64 {
+    (self.get_blocks_count() - self.get_group_first_block(bgd_id)).min(self.blocks_per_group as u64)
+  }
+
+  // 一个flex group里的block group数，没有开启flex_bg时是1
+  pub fn get_flex_size(&self) -> u32 {
+    if self.has_feature_incompat_flex_bg() {
+      1 << self.log_groups_per_flex
+    } else {
+      1
+    }
+  }
+
+  // block group开头被superblock和descriptor表的备份(包括保留给在线增长的块)占用的块数
+  // meta_bg的descriptor块在meta group的第一个、第二个和最后一个block group里各有一份
+  pub fn get_group_meta_blocks(&self, bgd_id: u32) -> u64 {
+    let has_super = self.bg_has_super(bgd_id) as u64;
+    let desc_per_block = (self.get_block_size() / self.get_desc_size()) as u32;
+    let meta_bg = self.has_feature_incompat_meta_bg();
+    if !meta_bg || bgd_id / desc_per_block < self.first_meta_bg {
+      if has_super == 0 {
+        return 0;
+      }
+      let desc_blocks = if meta_bg {
+        self.first_meta_bg
+      } else {
+        self.get_block_group_count().div_ceil(desc_per_block)
+      };
+      return 1 + desc_blocks as u64 + self.reserved_gdt_blocks as u64;
+    }
+    let idx = bgd_id % desc_per_block;
+    has_super + (idx == 0 || idx == 1 || idx == desc_per_block - 1) as u64
+  }
+
+  // block group里是否有superblock(以及descriptor表)的备份
+  // sparse_super时只有0、1和3、5、7的幂次的block group有，sparse_super2时只有backup_bgs记录的两个
+  pub fn bg_has_super(&self, bgd_id: u32) -> bool {
+    if bgd_id == 0 {
+      return true;
+    }
+    if self.get_feature_compat().contains(FeatureCompat::SPARSE_SUPER2) {
+      return self.backup_bgs.contains(&bgd_id);
+    }
+    if !self.get_feature_ro_compat().contains(FeatureROCompat::SPARSE_SUPER) || bgd_id == 1 {
+      return true;
+    }
+    [3u32, 5, 7].iter().any(|&base| {
+      let mut n = bgd_id;
+      while n.is_multiple_of(base) {
+        n /= base;
+      }
+      n == 1
+    })
+  }
+
+  // 第bgd_id个block group descriptor在磁盘上的字节偏移
+  // descriptor表紧接在superblock所在的块之后：1K的块是第2块，更大的块是第1块，可以跨越多个块
+  // 开启meta_bg时，从first_meta_bg开始每个descriptor块只描述一个meta group(一个块能放下的
+  // descriptor个数的block group)，放在meta group第一个block group的开头(superblock备份之后)
+  pub fn get_desc_pos(&self, bgd_id: u32) -> u64 {
+    let desc_size = self.get_desc_size();
+    let desc_per_block = (self.get_block_size() / desc_size) as u32;
+    let nr = bgd_id / desc_per_block;
+    let block = if !self.has_feature_incompat_meta_bg() || nr < self.first_meta_bg {
+      self.first_data_block as u64 + 1 + nr as u64
+    } else {
+      let first_bg = nr * desc_per_block;
+      self.get_group_first_block(first_bg) + self.bg_has_super(first_bg) as u64
+    };
+    block * self.get_block_size() + (bgd_id % desc_per_block) as u64 * desc_size
+  }
+
   pub fn get_inode_size(&self) -> u64 {
+    if self.rev_level == Self::GOOD_OLD_REV {
+      return Self::GOOD_OLD_INODE_SIZE as u64;
+    }
     self.inode_size as u64
   }
 
+  // 没有开启64bit时block group descriptor固定是32字节，desc_size字段可能是0
   pub fn get_desc_size(&self) -> u64 {
-    self.desc_size as u64
+    if self.has_feature_incompat_64bit() {
+      self.desc_size as u64
+    } else {
+      Self::MIN_DESC_SIZE as u64
+    }
+  }
+
+  pub fn get_inodes_count(&self) -> u32 {
+    self.inodes_count
+  }
+
+  // 第一个非保留的inode
+  pub fn get_first_ino(&self) -> u32 {
+    if self.rev_level == Self::GOOD_OLD_REV {
+      return Self::GOOD_OLD_FIRST_INO;
+    }
+    self.first_ino
   }
 
   pub fn get_free_inodes_count(&self) -> u32 {
@@ -256,8 +469,11 @@ impl SuperBlock {
     crc32c(!0, data, data.len() as u32 - core::mem::size_of::<u32>() as u32)
   }
 
+  // 只有开启metadata_csum时才有校验和
   pub fn compute_and_set_checksum(&mut self) {
-    self.checksum = self.compute_checksum();
+    if self.has_feature_ro_compat_metadata_csum() {
+      self.checksum = self.compute_checksum();
+    }
   }
 
   pub fn get_checksum(&self) -> u32 {
@@ -11,6 +11,10 @@ pub struct Bitmap {
 impl Bitmap {
   pub const BITS_PER_ITEM: usize = 8;
 
+  pub fn new(size: usize) -> Self {
+    Self { data: vec![0u8; size] }
+  }
+
   pub fn deserialize<R: Read>(reader: &mut R, size: usize) -> Result<Self, R::Error> {
     let mut buffer = vec![0u8; size];
     reader.read_exact(&mut buffer)?;
@@ -59,26 +63,38 @@ impl Bitmap {
   }
 
   pub fn find_consecutive_unused_bits(&self, count: u64) -> Option<u64> {
-    let mut consecutive = 0;
-    for (byte_idx, byte) in self.data.iter().enumerate() {
-      if *byte != 0xFF {
-        for bit_idx in 0..8 {
-          if (byte & (1 << bit_idx)) == 0 {
-            consecutive += 1;
-            if consecutive == count {
-              return Some((byte_idx * 8 + bit_idx - (count as usize) + 1) as u64);
-            }
-          } else {
-            consecutive = 0;
-          }
-        }
-      } else {
-        consecutive = 0;
+    self.find_unused_run(0, count, self.size())
+  }
+
+  // 在[from, limit)里找count个连续的空闲位，返回第一位
+  pub fn find_unused_run(&self, from: u64, count: u64, limit: u64) -> Option<u64> {
+    let mut start = from;
+    let mut bit = from;
+    while bit < limit {
+      if self.get_bit(bit) {
+        start = bit + 1;
+      } else if bit + 1 - start == count {
+        return Some(start);
       }
+      bit += 1;
     }
     None
   }
 
+  // 在[0, limit)里找最长的一段连续空闲位，返回第一位和长度
+  pub fn find_longest_unused_run(&self, limit: u64) -> Option<(u64, u64)> {
+    let mut best: Option<(u64, u64)> = None;
+    let mut start = 0;
+    for bit in 0..limit {
+      if self.get_bit(bit) {
+        start = bit + 1;
+      } else if best.is_none_or(|(_, len)| bit + 1 - start > len) {
+        best = Some((start, bit + 1 - start));
+      }
+    }
+    best
+  }
+
   pub fn size(&self) -> u64 {
     self.data.len() as u64 * 8
   }
@@ -72,3 +72,17 @@ pub fn crc32(crc: u32, buf: &[u8], size: u32, tab: &[u32]) -> u32 {
 pub fn crc32c(crc: u32, buf: &[u8], size: u32) -> u32 {
   crc32(crc, buf, size, &CRC32C_TAB)
 }
+
+/// 计算CRC16(多项式0x8005，按位反转)校验和，没有metadata_csum时block group descriptor用它校验
+/// 参数 crc 初始值
+/// 参数 buf 缓冲区
+pub fn crc16(crc: u16, buf: &[u8]) -> u16 {
+  let mut crc = crc;
+  for &byte in buf {
+    crc ^= byte as u16;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+    }
+  }
+  crc
+}
@@ -11,9 +11,18 @@ pub fn combine_u32(lo: u16, hi: u16) -> u32 {
   ((hi as u32) << 16) | (lo as u32)
 }
 
-pub fn split_path(path: &str) -> (&str, Option<&str>) {
-  let trimmed_path = path.trim_matches('/');
-  trimmed_path.find('/').map_or((trimmed_path, None), |n| {
-    (&trimmed_path[..n], Some(&trimmed_path[n + 1..]))
-  })
+// 把path分成父目录和最后一级名字，只有一级时没有父目录，"/name"的父目录是根目录
+// 按POSIX的规则，结尾的/表示最后一级必须是目录，这时第三项为true；最后一级为空(""、"/")时返回None
+pub fn split_parent(path: &str) -> Option<(Option<&str>, &str, bool)> {
+  let trimmed_path = path.trim_end_matches('/');
+  let must_be_dir = trimmed_path.len() < path.len();
+  let (parent, name) = match trimmed_path.rfind('/') {
+    Some(0) => (Some("/"), &trimmed_path[1..]),
+    Some(n) => (Some(&trimmed_path[..n]), &trimmed_path[n + 1..]),
+    None => (None, trimmed_path),
+  };
+  if name.is_empty() {
+    return None;
+  }
+  Some((parent, name, must_be_dir))
 }
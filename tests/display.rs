@@ -1,22 +1,93 @@
 use std::fs;
 
+use ext4fs::descriptor::{BGFlags, BlockGroupDescriptor};
 use ext4fs::dir::Dir;
-use ext4fs::dir_entry::DirEntryData;
-use ext4fs::inode::{Inode, InodeFilePerm};
-use ext4fs::io::{ReadWriteSeek, StdIoWrapper};
+use ext4fs::dir_entry::DirEntryFileType;
+use ext4fs::error::Error;
+use ext4fs::extent::{ExtentHeader, ExtentIdx, ExtentTail};
+use ext4fs::htree::{dx_hash, DxRootInfo};
+use ext4fs::inode::{Inode, InodeFilePerm, InodeFileType, InodeFlags};
+use ext4fs::io::{Read, ReadWriteSeek, Seek, SeekFrom, StdIoWrapper, Write};
+use ext4fs::utils::crc::crc32c;
 use fscommon::BufStream;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 const EXT4_1M_IMG: &str = "imgs/ext4_1m.img";
+// 根目录下的fragmented有500个单块的extent，extent tree深度为2
+const EXT4_FRAGMENTED_IMG: &str = "imgs/ext4_fragmented.img";
+// 根目录下的prealloc前2个块是数据('p')，第2到第9块是fallocate出来的unwritten extent，
+// 对应的物理块里填满了0xEE
+const EXT4_PREALLOC_IMG: &str = "imgs/ext4_prealloc.img";
+// 每个block group 1024个块、64个inode，根目录下的big有1500个块，分布在第0、1、3个block group里
+const EXT4_MULTIGROUP_IMG: &str = "imgs/ext4_multigroup.img";
+// 开启了inline_data，根目录下有Linux工具创建的三种符号链接：
+// fast(保存在inode.block里)、inline(100字节，保存在inline data里)、slow(300字节，保存在数据块里)
+const EXT4_SYMLINK_IMG: &str = "imgs/ext4_symlink.img";
+// e2fsck -D建立了htree索引的目录：indexed有2000个硬链接和两层索引，small只有一层索引
+const EXT4_HTREE_IMG: &str = "imgs/ext4_htree.img";
+// 文件都使用ext2/ext3的块映射：small只有直接块，ind用到一级间接块，dind用到二级间接块，
+// sparse在3K、100K和70M处各有一段数据，70M处用到三级间接块；dir是块映射的目录，里面有f1
+const EXT4_BLOCKMAP_IMG: &str = "imgs/ext4_blockmap.img";
+// mke2fs -t ext2/ext3创建的镜像，128字节inode，没有extents和metadata_csum，ext3多了日志
+// 根目录下有data(100K，用到一级间接块)、dir/small和指向dir/small的符号链接link
+const EXT2_IMG: &str = "imgs/ext2.img";
+const EXT3_IMG: &str = "imgs/ext3.img";
+// 32字节的block group descriptor，每个block group 1024个块，共4个block group，
+// 根目录下的big有2500K，跨越前3个block group，dir下有small
+// ext2_multigroup是ext2(desc_size为0)，ext4_32bit是没有开启64bit的ext4(有metadata_csum)
+const EXT2_MULTIGROUP_IMG: &str = "imgs/ext2_multigroup.img";
+const EXT4_32BIT_IMG: &str = "imgs/ext4_32bit.img";
+// 和上面的内容一样：ext4_4k是4K的块，4个block group；ext4_gdt是1K的块，每个block group 256个块，
+// 32个block group的descriptor表占2个块
+const EXT4_4K_IMG: &str = "imgs/ext4_4k.img";
+const EXT4_GDT_IMG: &str = "imgs/ext4_gdt.img";
+// 和ext4_gdt一样的布局，但开启了meta_bg：第16到31个block group的descriptor放在第16个block group的开头
+const EXT4_META_BG_IMG: &str = "imgs/ext4_metabg.img";
+// 空的ext4，没有flex_bg，8个block group，除了第0个都是INODE_UNINIT和BLOCK_UNINIT
+const EXT4_ORLOV_IMG: &str = "imgs/ext4_orlov.img";
+// 空的ext4，1K的块，开启了large_dir，htree最多可以有三层索引
+const EXT4_LARGEDIR_IMG: &str = "imgs/ext4_largedir.img";
+// 开启了inline_data，根目录下的inline(17字节)和inline_dir的数据都保存在inode里
+const EXT4_INLINE_IMG: &str = "imgs/ext4_inline.img";
+// 128字节inode，扩展属性只能放在单独的块里：根目录下的xattr_file和shared_file共用同一个扩展属性块
+const EXT4_XATTR_IMG: &str = "imgs/ext4_xattr.img";
 
 type FileSystem = ext4fs::fs::FileSystem<StdIoWrapper<BufStream<fs::File>>>;
 
+static IMG_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// 每个测试都在镜像的副本上运行，避免测试之间互相影响以及修改仓库里的镜像
+fn copy_img(filename: &str) -> PathBuf {
+  let id = IMG_COUNTER.fetch_add(1, Ordering::SeqCst);
+  let path = std::env::temp_dir().join(format!("ext4fs-{}-{}.img", process::id(), id));
+  fs::copy(filename, &path).unwrap();
+  path
+}
+
+// 如果系统里有e2fsck，用它检查测试结束后的镜像是否一致
+fn check_fsck(path: &Path) {
+  match Command::new("e2fsck").arg("-fn").arg(path).output() {
+    Ok(output) => assert!(
+      output.status.success(),
+      "e2fsck failed:\n{}",
+      String::from_utf8_lossy(&output.stdout)
+    ),
+    Err(_) => println!("e2fsck not found, skip fsck"),
+  }
+}
+
 fn call_with_fs<F: Fn(FileSystem)>(f: F, filename: &str) {
   let _ = env_logger::builder().is_test(true).try_init();
-  let file = fs::OpenOptions::new().read(true).write(true).open(filename).unwrap();
+  let path = copy_img(filename);
+  let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
   let buf_file = BufStream::new(file);
   let fs = FileSystem::new(buf_file).unwrap();
   f(fs);
+  check_fsck(&path);
+  fs::remove_file(&path).unwrap();
 }
 
 fn get_current_time() -> u32 {
@@ -53,27 +124,25 @@ fn display_inode_of_root_dir(fs: FileSystem) {
   println!("{:?}", root_dir.inode.get_file_perm());
   println!("{:?}", root_dir.inode.get_flags());
 
-  let mut disk = fs.disk.borrow_mut();
-  let extents = root_dir.inode.get_extents(&mut *disk).unwrap();
+  let extents = root_dir
+    .inode
+    .get_extents(root_dir.ino, &mut *fs.disk.borrow_mut(), &fs.super_block.borrow())
+    .unwrap();
   println!("{:?}", extents);
 
   let csum = root_dir.inode.get_checksum();
-  let cmp_csum = root_dir.inode.compute_checksum(
-    root_dir.ino as u32,
-    fs.super_block.borrow().get_inode_size() as u16,
-    &fs.super_block.borrow().uuid,
-  );
+  let raw = fs.read_inode_raw(root_dir.ino).unwrap();
+  let cmp_csum = root_dir
+    .inode
+    .compute_checksum(root_dir.ino as u32, &raw, &fs.super_block.borrow().uuid);
   assert_eq!(csum, cmp_csum);
 }
 
 fn check_inode_checksum(ino: u64, fs: &FileSystem) {
   let mut inode = fs.get_inode(ino).unwrap();
   let csum = inode.get_checksum();
-  let cmp_csum = inode.compute_checksum(
-    ino as u32,
-    fs.super_block.borrow().get_inode_size() as u16,
-    &fs.super_block.borrow().uuid,
-  );
+  let raw = fs.read_inode_raw(ino).unwrap();
+  let cmp_csum = inode.compute_checksum(ino as u32, &raw, &fs.super_block.borrow().uuid);
   assert_eq!(csum, cmp_csum);
 }
 
@@ -81,30 +150,45 @@ fn check_inode_checksum_of_root_dir(fs: FileSystem) {
   check_inode_checksum(Inode::ROOT_INO, &fs);
 }
 
+// 每个目录块都有自己的tail，分别按原始内容校验
+// htree的索引块没有dirent tail，校验dx_entry数组后面的dx_tail
 fn check_dirblock_checksum<IO: ReadWriteSeek>(dir: &Dir<IO>) {
   let extents = {
     let mut disk = dir.fs.disk.borrow_mut();
-    dir.inode.get_extents(&mut *disk).unwrap()
+    dir
+      .inode
+      .get_extents(dir.ino, &mut *disk, &dir.fs.super_block.borrow())
+      .unwrap()
   };
-  assert_eq!(extents.len(), 1);
-  let (entries, tail_entry) = {
-    let mut entries = Vec::new();
-    let mut iter = dir.iter();
-    for entry in &mut iter {
-      entries.push(entry.unwrap().data);
+  let block_size = dir.fs.super_block.borrow().get_block_size() as usize;
+  let uuid = dir.fs.super_block.borrow().uuid;
+  let mut block = vec![0u8; block_size];
+  for extent in extents {
+    for i in 0..extent.get_len() as u64 {
+      dir.fs.read_block(extent.get_block_loc() + i, &mut block).unwrap();
+      let mut cmp_csum = crc32c(!0, &uuid, uuid.len() as u32);
+      cmp_csum = crc32c(cmp_csum, &(dir.ino as u32).to_le_bytes(), 4);
+      cmp_csum = crc32c(cmp_csum, &dir.inode.generation.to_le_bytes(), 4);
+      let is_dirent_tail = block[block_size - 12..block_size - 4] == [0, 0, 0, 0, 12, 0, 0, 0xDE];
+      let csum_offset = if is_dirent_tail {
+        cmp_csum = crc32c(cmp_csum, &block[..block_size - 12], block_size as u32 - 12);
+        block_size - 4
+      } else {
+        // dx_root的count/limit在"."、".."和root info之后，dx_node的在空entry之后
+        let offset = if extent.block as u64 + i == 0 { 32 } else { 8 };
+        let limit = u16::from_le_bytes([block[offset], block[offset + 1]]) as usize;
+        let count = u16::from_le_bytes([block[offset + 2], block[offset + 3]]) as usize;
+        let size = offset + count * 8;
+        let tail = offset + limit * 8;
+        cmp_csum = crc32c(cmp_csum, &block[..size], size as u32);
+        cmp_csum = crc32c(cmp_csum, &block[tail..tail + 4], 4);
+        cmp_csum = crc32c(cmp_csum, &[0; 4], 4);
+        tail + 4
+      };
+      let csum = u32::from_le_bytes(block[csum_offset..csum_offset + 4].try_into().unwrap());
+      assert_eq!(csum, cmp_csum);
     }
-    let tail_entry = iter.tail_entry.unwrap();
-    (entries, tail_entry)
-  };
-  let csum = tail_entry.get_checksum();
-  let cmp_csum = DirEntryData::compute_dirblock_checksum(
-    &entries,
-    dir.fs.super_block.borrow().get_block_size(),
-    &dir.fs.super_block.borrow().uuid,
-    dir.ino as u32,
-    dir.inode.generation,
-  );
-  assert_eq!(csum, cmp_csum);
+  }
 }
 
 fn check_dirblock_checksum_of_root_dir(fs: FileSystem) {
@@ -144,7 +228,9 @@ fn inode_of_dir3_1m() {
       println!("{:?}", inode);
 
       let mut disk = fs.disk.borrow_mut();
-      let extent = inode.get_extents(&mut *disk).unwrap();
+      let extent = inode
+        .get_extents(ino as u64, &mut *disk, &fs.super_block.borrow())
+        .unwrap();
       println!("{:?}", extent);
     },
     EXT4_1M_IMG,
@@ -176,7 +262,7 @@ fn read_file() {
       assert_eq!(entry.data.get_name_str(), "test0");
       println!("{:?}", entry.data);
 
-      let file = entry.to_file();
+      let file = entry.to_file().unwrap();
       let mut buf = vec![0u8; 1024];
       let read_bytes = file.read(0, &mut buf).unwrap();
       println!("read_bytes: {}", read_bytes);
@@ -237,3 +323,1875 @@ fn create_file() {
     EXT4_1M_IMG,
   )
 }
+
+#[test]
+fn write_file() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let mut file = root_dir
+        .create_file("written_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      assert_eq!(file.inode.get_size(), 0);
+
+      let data = b"hello, ext4!";
+      assert_eq!(file.write(0, data, time).unwrap(), data.len());
+      // 跨越块边界写入
+      let block_size = fs.super_block.borrow().get_block_size();
+      let big: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+      assert_eq!(file.write(block_size - 100, &big, time).unwrap(), big.len());
+
+      let file = root_dir.open_file("written_file").unwrap();
+      let size = block_size - 100 + big.len() as u64;
+      assert_eq!(file.inode.get_size(), size);
+      assert_eq!(
+        file.inode.get_blocks_count(),
+        size.div_ceil(block_size) * block_size / Inode::INODE_BLOCK_SIZE as u64
+      );
+      let mut buf = vec![0u8; size as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), size as usize);
+      assert_eq!(&buf[..data.len()], data);
+      assert!(buf[data.len()..(block_size - 100) as usize].iter().all(|&b| b == 0));
+      assert_eq!(&buf[(block_size - 100) as usize..], &big[..]);
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+// 没有空闲块时创建目录失败，已经分配的inode要释放，不能泄漏
+#[test]
+fn create_dir_without_space() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let mut file = root_dir
+        .create_file("big_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let mut offset = 0;
+      while file.allocate(offset, block_size, false, time).is_ok() {
+        offset += block_size;
+      }
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), 0);
+
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+      assert!(matches!(
+        root_dir.create_dir("new_dir", 0, 0, InodeFilePerm::default_dir_perm(), time),
+        Err(Error::NotEnoughSpace)
+      ));
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+      assert!(!root_dir.is_exist("new_dir"));
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+// 空间不够时写入失败，已经分配的块要释放，不能泄漏
+#[test]
+fn write_file_without_space() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let mut file = root_dir
+        .create_file("big_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+      let big = vec![0x5a; ((free_blocks_count + 1) * block_size) as usize];
+      assert!(matches!(file.write(0, &big, time), Err(Error::NotEnoughSpace)));
+
+      let file = root_dir.open_file("big_file").unwrap();
+      assert_eq!(file.inode.get_size(), 0);
+      assert_eq!(file.inode.get_blocks_count(), 0);
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count);
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn read_fragmented_file() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let file = root_dir.open_file("fragmented").unwrap();
+      let extents = {
+        let mut disk = fs.disk.borrow_mut();
+        file
+          .inode
+          .get_extents(file.ino, &mut *disk, &fs.super_block.borrow())
+          .unwrap()
+      };
+      assert!(extents.len() > 4);
+      let mut next_block = 0;
+      for extent in extents.iter() {
+        assert_eq!(extent.block, next_block);
+        next_block += extent.get_len() as u32;
+      }
+
+      let size = file.inode.get_size() as usize;
+      let mut buf = vec![0u8; size];
+      assert_eq!(file.read(0, &mut buf).unwrap(), size);
+      for (i, b) in buf.iter().enumerate() {
+        assert_eq!(*b, ((i * 7 + i / 1024) % 251) as u8);
+      }
+    },
+    EXT4_FRAGMENTED_IMG,
+  )
+}
+
+#[test]
+fn write_file_with_many_extents() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let mut file = root_dir
+        .create_file("many_extents", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let block_data = |lblock: u64| vec![(lblock % 251) as u8; block_size as usize];
+      // 倒序写入，物理块号随逻辑块号递减，每个块都是一个单独的extent
+      let count = 600;
+      for lblock in (0..count).rev() {
+        file.write(lblock * block_size, &block_data(lblock), time).unwrap();
+      }
+
+      let file = root_dir.open_file("many_extents").unwrap();
+      let extents = {
+        let mut disk = fs.disk.borrow_mut();
+        file
+          .inode
+          .get_extents(file.ino, &mut *disk, &fs.super_block.borrow())
+          .unwrap()
+      };
+      assert_eq!(extents.len(), count as usize);
+      assert_eq!(ExtentHeader::load_from_u32(&file.inode.block).depth, 2);
+      let mut buf = vec![0u8; (count * block_size) as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), buf.len());
+      for lblock in 0..count {
+        let start = (lblock * block_size) as usize;
+        assert_eq!(&buf[start..start + block_size as usize], &block_data(lblock)[..]);
+      }
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+// 节点的max超出能放下的条目数时返回错误，而不是按max去访问块外面的ExtentTail
+#[test]
+fn reject_extent_node_with_bad_max() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let file = root_dir.open_file("fragmented").unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let get_extents = |inode: &Inode| {
+        let mut disk = fs.disk.borrow_mut();
+        inode.get_extents(file.ino, &mut *disk, &fs.super_block.borrow())
+      };
+
+      // 根节点在inode.block里，最多4个条目
+      let mut inode = file.inode;
+      ExtentHeader::load_from_u32_mut(&mut inode.block).max = 5;
+      assert!(matches!(get_extents(&inode), Err(Error::CorruptedFileSystem)));
+
+      // 索引块里的max
+      let pblock = ExtentIdx::load_from_u32(&file.inode.block[3..]).get_extent_idx();
+      let pos = pblock * block_size + 4;
+      let mut original = [0u8; 2];
+      {
+        let mut disk = fs.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        disk.read_exact(&mut original).unwrap();
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        disk.write_all(&[0xFF; 2]).unwrap();
+      }
+      assert!(matches!(get_extents(&file.inode), Err(Error::CorruptedFileSystem)));
+      // 修改extent tree时加载节点也会检查
+      let mut inode = file.inode;
+      assert!(matches!(
+        fs.unmap_blocks(file.ino, &mut inode, 0, 1),
+        Err(Error::CorruptedFileSystem)
+      ));
+
+      // 恢复原来的内容
+      let mut disk = fs.disk.borrow_mut();
+      disk.seek(SeekFrom::Start(pos)).unwrap();
+      disk.write_all(&original).unwrap();
+    },
+    EXT4_FRAGMENTED_IMG,
+  )
+}
+
+// 修改extent tree时保留各个节点header里原有的generation
+#[test]
+fn keep_extent_node_generation() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let mut file = root_dir.open_file("fragmented").unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let uuid = fs.super_block.borrow().uuid;
+      let time = get_current_time();
+
+      // 根节点、第一个索引块和它下面的第一个叶子块都设置generation
+      ExtentHeader::load_from_u32_mut(&mut file.inode.block).generation = 0x1234;
+      fs.write_inode(file.ino, &mut file.inode).unwrap();
+      let index_block = ExtentIdx::load_from_u32(&file.inode.block[3..]).get_extent_idx();
+      let mut data = vec![0u8; block_size as usize];
+      fs.read_block(index_block, &mut data).unwrap();
+      let leaf_block = ExtentIdx::load_from_u8(&data[12..]).get_extent_idx();
+      for (pblock, generation) in [(index_block, 0x5678), (leaf_block, 0x9abc)] {
+        fs.read_block(pblock, &mut data).unwrap();
+        ExtentHeader::load_from_u8_mut(&mut data).generation = generation;
+        ExtentTail::set_checksum(&mut data, &uuid, file.ino as u32, file.inode.generation);
+        fs.write_block(pblock, &data).unwrap();
+      }
+
+      // 删除第一个块，叶子、索引块和根节点里第一个条目的逻辑块号都会改变
+      file.punch_hole(0, block_size, time).unwrap();
+      let file = root_dir.open_file("fragmented").unwrap();
+      assert_eq!(ExtentHeader::load_from_u32(&file.inode.block).generation, 0x1234);
+      for (pblock, generation) in [(index_block, 0x5678), (leaf_block, 0x9abc)] {
+        fs.read_block(pblock, &mut data).unwrap();
+        assert_eq!(ExtentHeader::load_from_u8(&data).generation, generation);
+      }
+      assert_eq!(file.seek_data(0).unwrap(), Some(block_size));
+    },
+    EXT4_FRAGMENTED_IMG,
+  )
+}
+
+#[test]
+fn verify_extent_block_checksum() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let file = root_dir.open_file("fragmented").unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      // 根节点的第一个索引指向的块
+      let pblock = ExtentIdx::load_from_u32(&file.inode.block[3..]).get_extent_idx();
+
+      // 修改块里的generation字段，结构仍然合法，但校验和不匹配
+      let pos = pblock * block_size + 8;
+      let mut original = [0u8; 4];
+      {
+        let mut disk = fs.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        disk.read_exact(&mut original).unwrap();
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        disk.write_all(&[0xFF; 4]).unwrap();
+      }
+      let result = {
+        let mut disk = fs.disk.borrow_mut();
+        file.inode.get_extents(file.ino, &mut *disk, &fs.super_block.borrow())
+      };
+      assert!(matches!(result, Err(Error::CorruptedFileSystem)));
+
+      // 恢复原来的内容
+      let mut disk = fs.disk.borrow_mut();
+      disk.seek(SeekFrom::Start(pos)).unwrap();
+      disk.write_all(&original).unwrap();
+    },
+    EXT4_FRAGMENTED_IMG,
+  )
+}
+
+#[test]
+fn reject_dirblock_without_tail() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let pblock = {
+        let mut disk = fs.disk.borrow_mut();
+        root_dir
+          .inode
+          .get_extents(root_dir.ino, &mut *disk, &fs.super_block.borrow())
+          .unwrap()[0]
+          .get_block_loc()
+      };
+
+      // 破坏tail里的file_type(0xDE)，这个块就没有tail了，不能跳过校验
+      let pos = pblock * block_size + block_size - 5;
+      let mut original = [0u8; 1];
+      {
+        let mut disk = fs.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        disk.read_exact(&mut original).unwrap();
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        disk.write_all(&[0]).unwrap();
+      }
+      assert!(matches!(root_dir.find_entry("."), Err(Error::CorruptedFileSystem)));
+
+      // 恢复原来的内容
+      let mut disk = fs.disk.borrow_mut();
+      disk.seek(SeekFrom::Start(pos)).unwrap();
+      disk.write_all(&original).unwrap();
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn read_sparse_file() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let mut file = root_dir
+        .create_file("sparse_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      // 第一个extent从逻辑块2开始，第4到第6块是空洞
+      let data = vec![0xAAu8; 2 * block_size as usize];
+      file.write(2 * block_size, &data, time).unwrap();
+      file.write(7 * block_size + 10, &data[..100], time).unwrap();
+      let size = 7 * block_size + 110;
+      assert_eq!(file.inode.get_size(), size);
+
+      let file = root_dir.open_file("sparse_file").unwrap();
+      let mut buf = vec![0xFFu8; size as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), size as usize);
+      let (hole0, rest) = buf.split_at((2 * block_size) as usize);
+      let (data0, rest) = rest.split_at((2 * block_size) as usize);
+      let (hole1, data1) = rest.split_at((3 * block_size + 10) as usize);
+      assert!(hole0.iter().all(|&b| b == 0));
+      assert!(data0.iter().all(|&b| b == 0xAA));
+      assert!(hole1.iter().all(|&b| b == 0));
+      assert!(data1.iter().all(|&b| b == 0xAA));
+
+      assert_eq!(file.seek_data(0).unwrap(), Some(2 * block_size));
+      assert_eq!(file.seek_data(2 * block_size + 1).unwrap(), Some(2 * block_size + 1));
+      assert_eq!(file.seek_data(4 * block_size).unwrap(), Some(7 * block_size));
+      assert_eq!(file.seek_data(size).unwrap(), None);
+      assert_eq!(file.seek_hole(0).unwrap(), Some(0));
+      assert_eq!(file.seek_hole(2 * block_size).unwrap(), Some(4 * block_size));
+      assert_eq!(file.seek_hole(7 * block_size).unwrap(), Some(size));
+      assert_eq!(file.seek_hole(size).unwrap(), None);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn read_and_write_unwritten_extent() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let mut file = root_dir.open_file("prealloc").unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let size = file.inode.get_size();
+      assert_eq!(size, 10 * block_size);
+
+      let mut buf = vec![0xFFu8; size as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), size as usize);
+      assert!(buf[..2 * block_size as usize].iter().all(|&b| b == b'p'));
+      assert!(buf[2 * block_size as usize..].iter().all(|&b| b == 0));
+      assert_eq!(file.seek_hole(0).unwrap(), Some(2 * block_size));
+      assert_eq!(file.seek_data(2 * block_size).unwrap(), None);
+
+      // 写入unwritten extent的中间部分，extent会被拆成三段
+      let time = get_current_time();
+      file.write(5 * block_size + 10, b"written", time).unwrap();
+      let extents = {
+        let mut disk = fs.disk.borrow_mut();
+        file
+          .inode
+          .get_extents(file.ino, &mut *disk, &fs.super_block.borrow())
+          .unwrap()
+      };
+      let unwritten: Vec<bool> = extents.iter().map(|e| e.is_unwritten()).collect();
+      assert_eq!(unwritten, vec![false, true, false, true]);
+
+      let file = root_dir.open_file("prealloc").unwrap();
+      assert_eq!(file.read(0, &mut buf).unwrap(), size as usize);
+      let written_pos = (5 * block_size + 10) as usize;
+      assert!(buf[2 * block_size as usize..written_pos].iter().all(|&b| b == 0));
+      assert_eq!(&buf[written_pos..written_pos + 7], b"written");
+      assert!(buf[written_pos + 7..].iter().all(|&b| b == 0));
+      assert_eq!(file.seek_data(2 * block_size).unwrap(), Some(5 * block_size));
+      assert_eq!(file.seek_hole(5 * block_size).unwrap(), Some(6 * block_size));
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_PREALLOC_IMG,
+  )
+}
+
+#[test]
+fn allocate_and_punch_hole() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let mut file = root_dir
+        .create_file("allocated_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+      let blocks_of = |count: u64| count * block_size / Inode::INODE_BLOCK_SIZE as u64;
+
+      // 预分配的块读出来都是0
+      file.allocate(0, 8 * block_size, false, time).unwrap();
+      assert_eq!(file.inode.get_size(), 8 * block_size);
+      assert_eq!(file.inode.get_blocks_count(), blocks_of(8));
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count - 8);
+      assert_eq!(file.seek_data(0).unwrap(), None);
+      file
+        .write(2 * block_size, &vec![0xABu8; 3 * block_size as usize], time)
+        .unwrap();
+      // keep_size时文件大小不变
+      file.allocate(8 * block_size, 4 * block_size, true, time).unwrap();
+      assert_eq!(file.inode.get_size(), 8 * block_size);
+      assert_eq!(file.inode.get_blocks_count(), blocks_of(12));
+
+      // 释放块2和块3，块4的前100字节写0
+      file.punch_hole(block_size + 100, 3 * block_size, time).unwrap();
+      assert_eq!(file.inode.get_blocks_count(), blocks_of(10));
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count - 10);
+      let file = root_dir.open_file("allocated_file").unwrap();
+      let mut buf = vec![0xFFu8; 8 * block_size as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), buf.len());
+      let data_start = (4 * block_size + 100) as usize;
+      assert!(buf[..data_start].iter().all(|&b| b == 0));
+      assert!(buf[data_start..5 * block_size as usize].iter().all(|&b| b == 0xAB));
+      assert!(buf[5 * block_size as usize..].iter().all(|&b| b == 0));
+      assert_eq!(file.seek_data(0).unwrap(), Some(4 * block_size));
+
+      // 全部释放之后空闲块数恢复原样
+      let mut file = file;
+      file.punch_hole(0, 12 * block_size, time).unwrap();
+      assert_eq!(file.inode.get_size(), 8 * block_size);
+      assert_eq!(file.inode.get_blocks_count(), 0);
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count);
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+// 空间不够时预分配失败，这次分配的块要释放，之前预分配的块保持不变
+#[test]
+fn allocate_without_space() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let mut file = root_dir
+        .create_file("allocated_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      file.allocate(0, 4 * block_size, false, time).unwrap();
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+      assert!(matches!(
+        file.allocate(4 * block_size, (free_blocks_count + 1) * block_size, true, time),
+        Err(Error::NotEnoughSpace)
+      ));
+
+      let file = root_dir.open_file("allocated_file").unwrap();
+      assert_eq!(file.inode.get_size(), 4 * block_size);
+      assert_eq!(
+        file.inode.get_blocks_count(),
+        4 * block_size / Inode::INODE_BLOCK_SIZE as u64
+      );
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count);
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn punch_hole_in_fragmented_file() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let mut file = root_dir.open_file("fragmented").unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let size = file.inode.get_size();
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+      let time = get_current_time();
+
+      // 跨越多个叶子节点挖一个洞
+      file.punch_hole(100 * block_size, 300 * block_size, time).unwrap();
+      let file = root_dir.open_file("fragmented").unwrap();
+      let mut buf = vec![0u8; size as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), size as usize);
+      let hole = (100 * block_size) as usize..(400 * block_size) as usize;
+      for (i, b) in buf.iter().enumerate() {
+        let expected = if hole.contains(&i) {
+          0
+        } else {
+          ((i * 7 + i / 1024) % 251) as u8
+        };
+        assert_eq!(*b, expected);
+      }
+      assert_eq!(file.seek_hole(0).unwrap(), Some(100 * block_size));
+      assert_eq!(file.seek_data(100 * block_size).unwrap(), Some(400 * block_size));
+
+      // 全部释放之后树的深度降为0
+      let mut file = file;
+      let blocks_count = file.inode.get_blocks_count();
+      file.punch_hole(0, size, time).unwrap();
+      assert_eq!(file.inode.get_blocks_count(), 0);
+      assert_eq!(ExtentHeader::load_from_u32(&file.inode.block).depth, 0);
+      assert_eq!(ExtentHeader::load_from_u32(&file.inode.block).entries, 0);
+      let freed = blocks_count * Inode::INODE_BLOCK_SIZE as u64 / block_size;
+      assert!(fs.super_block.borrow().get_free_blocks_count() >= free_blocks_count + freed);
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_FRAGMENTED_IMG,
+  )
+}
+
+#[test]
+fn truncate_file() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let mut file = root_dir.open_file("fragmented").unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let pattern = |i: usize| ((i * 7 + i / 1024) % 251) as u8;
+      let time = get_current_time();
+
+      // 缩小到第3个块的中间，剩下的3个extent放得进根节点
+      let new_size = 2 * block_size + 100;
+      file.set_len(new_size, time).unwrap();
+      assert_eq!(file.inode.get_size(), new_size);
+      assert_eq!(ExtentHeader::load_from_u32(&file.inode.block).depth, 0);
+      assert_eq!(
+        file.inode.get_blocks_count(),
+        3 * block_size / Inode::INODE_BLOCK_SIZE as u64
+      );
+
+      // 再扩大，新增部分读出来都是0
+      let grown_size = 20 * block_size;
+      file.set_len(grown_size, time).unwrap();
+      let file = root_dir.open_file("fragmented").unwrap();
+      assert_eq!(file.inode.get_size(), grown_size);
+      let mut buf = vec![0xFFu8; grown_size as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), buf.len());
+      for (i, b) in buf.iter().enumerate() {
+        let expected = if (i as u64) < new_size { pattern(i) } else { 0 };
+        assert_eq!(*b, expected);
+      }
+      assert_eq!(file.seek_hole(0).unwrap(), Some(3 * block_size));
+      assert_eq!(file.seek_data(3 * block_size).unwrap(), None);
+
+      let mut file = file;
+      file.set_len(0, time).unwrap();
+      assert_eq!(file.inode.get_size(), 0);
+      assert_eq!(file.inode.get_blocks_count(), 0);
+      check_inode_checksum(file.ino, &fs);
+    },
+    EXT4_FRAGMENTED_IMG,
+  )
+}
+
+#[test]
+fn free_blocks_and_inode() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let mut file = root_dir.open_file("big").unwrap();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let free_blocks_of_groups = || -> Vec<u32> {
+        let bgds = fs.block_group_descriptors.borrow();
+        bgds.iter().map(|bgd| bgd.get_free_blocks_count()).collect()
+      };
+      let (first_block, end_block) = {
+        let mut disk = fs.disk.borrow_mut();
+        let extents = file
+          .inode
+          .get_extents(file.ino, &mut *disk, &fs.super_block.borrow())
+          .unwrap();
+        let last = extents.last().unwrap();
+        (extents[0].get_block_loc(), last.get_block_loc() + last.get_len() as u64)
+      };
+      let blocks = file.inode.get_blocks_count() * Inode::INODE_BLOCK_SIZE as u64 / block_size;
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+      let before = free_blocks_of_groups();
+
+      // 范围跨越第2、3个block group，最后一个块是空闲的，整个释放失败，前面的block group也不能被修改
+      let group_start = fs.super_block.borrow().get_group_first_block(3);
+      assert!(end_block > group_start);
+      assert!(matches!(
+        fs.free_blocks(group_start - 10, end_block - group_start + 11),
+        Err(Error::CorruptedFileSystem)
+      ));
+      assert_eq!(free_blocks_of_groups(), before);
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count);
+
+      // 释放的块分布在多个block group里
+      file.set_len(0, get_current_time()).unwrap();
+      let after = free_blocks_of_groups();
+      assert_eq!(
+        fs.super_block.borrow().get_free_blocks_count(),
+        free_blocks_count + blocks
+      );
+      let changed: Vec<u32> = before.iter().zip(after.iter()).map(|(b, a)| a - b).collect();
+      assert_eq!(changed.iter().map(|&c| c as u64).sum::<u64>(), blocks);
+      assert!(changed.iter().filter(|&&c| c > 0).count() >= 3);
+      // 重复释放会报错
+      assert!(matches!(
+        fs.free_blocks(first_block, 1),
+        Err(Error::CorruptedFileSystem)
+      ));
+
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+      let used_dirs_count = fs.block_group_descriptors.borrow()[0].get_used_dirs_count();
+      let ino = fs.alloc_inode(Inode::ROOT_INO, true).unwrap();
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count - 1);
+      fs.free_inode(ino, true).unwrap();
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+      assert_eq!(
+        fs.block_group_descriptors.borrow()[0].get_used_dirs_count(),
+        used_dirs_count
+      );
+      assert!(matches!(fs.free_inode(ino, true), Err(Error::CorruptedFileSystem)));
+      assert!(matches!(fs.free_inode(Inode::ROOT_INO, true), Err(Error::InvalidInput)));
+    },
+    EXT4_MULTIGROUP_IMG,
+  )
+}
+
+#[test]
+fn remove_file_and_dir() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+      let links_count = root_dir.inode.links_count;
+
+      let mut dir = root_dir
+        .create_dir("removed_dir", 0, 0, InodeFilePerm::default_dir_perm(), time)
+        .unwrap();
+      let mut file = dir
+        .create_file("removed_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      file.write(0, &[0x5Au8; 3000], time).unwrap();
+      assert_eq!(root_dir.inode.links_count, links_count + 1);
+
+      // 非空目录不能删除
+      assert!(matches!(
+        root_dir.remove("removed_dir", time),
+        Err(Error::DirectoryIsNotEmpty)
+      ));
+      assert!(matches!(
+        root_dir.remove("removed_dir/.", time),
+        Err(Error::InvalidInput)
+      ));
+      root_dir.remove("removed_dir/removed_file", time).unwrap();
+      assert!(matches!(
+        root_dir.open_file("removed_dir/removed_file"),
+        Err(Error::NotFound)
+      ));
+      root_dir.remove("removed_dir", time).unwrap();
+      assert!(!root_dir.is_exist("removed_dir"));
+      assert!(matches!(root_dir.remove("removed_dir", time), Err(Error::NotFound)));
+
+      // 所有块和inode都被释放，父目录的链接数恢复原样
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count);
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+      assert_eq!(root_dir.inode.links_count, links_count);
+      assert_eq!(fs.root_dir().inode.links_count, links_count);
+      check_inode_checksum(root_dir.ino, &fs);
+      check_dirblock_checksum(&root_dir);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn remove_fragmented_file() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let file = root_dir.open_file("fragmented").unwrap();
+      let blocks = file.inode.get_blocks_count() * Inode::INODE_BLOCK_SIZE as u64 / block_size;
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+
+      root_dir.remove("fragmented", get_current_time()).unwrap();
+      assert!(!root_dir.is_exist("fragmented"));
+      assert_eq!(
+        fs.super_block.borrow().get_free_blocks_count(),
+        free_blocks_count + blocks
+      );
+      let inode = fs.get_inode(file.ino).unwrap();
+      assert_eq!(inode.links_count, 0);
+      assert_ne!(inode.dtime, 0);
+      check_dirblock_checksum(&root_dir);
+    },
+    EXT4_FRAGMENTED_IMG,
+  )
+}
+
+// 删除文件时扩展属性块也要释放，被共享的扩展属性块只减少引用计数
+#[test]
+fn remove_file_with_xattr_block() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let xattr_block = root_dir.open_file("xattr_file").unwrap().inode.get_file_acl();
+      assert_ne!(xattr_block, 0);
+      assert_eq!(
+        root_dir.open_file("shared_file").unwrap().inode.get_file_acl(),
+        xattr_block
+      );
+      let refcount = |fs: &FileSystem| {
+        let mut buf = vec![0u8; block_size as usize];
+        fs.read_block(xattr_block, &mut buf).unwrap();
+        u32::from_le_bytes(buf[4..8].try_into().unwrap())
+      };
+      assert_eq!(refcount(&fs), 2);
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+
+      root_dir.remove("shared_file", time).unwrap();
+      assert_eq!(refcount(&fs), 1);
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count);
+
+      root_dir.remove("xattr_file", time).unwrap();
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count + 1);
+      check_dirblock_checksum(&root_dir);
+    },
+    EXT4_XATTR_IMG,
+  )
+}
+
+#[test]
+fn rename_file_and_dir() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let root_links_count = root_dir.inode.links_count;
+
+      // 在同一个目录里改名
+      let mut same_dir = fs.root_dir();
+      root_dir.rename("test0", &mut same_dir, "test0_renamed", time).unwrap();
+      assert!(!root_dir.is_exist("test0"));
+      let file = root_dir.open_file("test0_renamed").unwrap();
+      let mut buf = vec![0u8; file.inode.get_size() as usize];
+      assert_eq!(file.read(0, &mut buf).unwrap(), buf.len());
+
+      // 把目录移动到另一个目录下，..和两个父目录的链接数都要更新
+      let mut dir0 = root_dir.open_dir("dir0").unwrap();
+      let dir0_links_count = dir0.inode.links_count;
+      root_dir.rename("dir1", &mut dir0, "moved_dir1", time).unwrap();
+      assert!(!root_dir.is_exist("dir1"));
+      assert_eq!(root_dir.inode.links_count, root_links_count - 1);
+      assert_eq!(dir0.inode.links_count, dir0_links_count + 1);
+      let moved = root_dir.open_dir("dir0/moved_dir1").unwrap();
+      assert_eq!(moved.find_entry("..").unwrap().data.get_inode() as u64, dir0.ino);
+      check_dirblock_checksum(&moved);
+
+      // 目录不能移动到自己的子目录里
+      let mut moved = moved;
+      assert!(matches!(
+        root_dir.rename("dir0", &mut moved, "dir0", time),
+        Err(Error::InvalidInput)
+      ));
+
+      // 覆盖已经存在的文件，被覆盖的inode被释放
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+      let mut src = root_dir
+        .create_file("rename_src", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      src.write(0, b"source", time).unwrap();
+      root_dir
+        .create_file("rename_dst", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let mut same_dir = fs.root_dir();
+      root_dir
+        .rename("rename_src", &mut same_dir, "rename_dst", time)
+        .unwrap();
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count - 1);
+      let file = root_dir.open_file("rename_dst").unwrap();
+      assert_eq!(file.ino, src.ino);
+      let mut buf = [0u8; 6];
+      file.read(0, &mut buf).unwrap();
+      assert_eq!(&buf, b"source");
+
+      // 文件不能覆盖目录，目录只能覆盖空目录
+      assert!(matches!(
+        root_dir.rename("rename_dst", &mut same_dir, "dir2", time),
+        Err(Error::InvalidInput)
+      ));
+      assert!(matches!(
+        root_dir.rename("dir2", &mut same_dir, "dir0", time),
+        Err(Error::DirectoryIsNotEmpty)
+      ));
+      let links_count = root_dir.inode.links_count;
+      root_dir.rename("dir2", &mut same_dir, "dir3", time).unwrap();
+      assert_eq!(root_dir.inode.links_count, links_count - 1);
+      check_inode_checksum(root_dir.ino, &fs);
+      check_dirblock_checksum(&root_dir);
+
+      // 同一个目录里改名时新名字放不下，插入时会追加块或者建立索引，之后还要能删除旧名字
+      let (block_size, tail_len) = {
+        let super_block = fs.super_block.borrow();
+        let tail_len = if super_block.has_feature_ro_compat_metadata_csum() {
+          12
+        } else {
+          0
+        };
+        (super_block.get_block_size(), tail_len)
+      };
+      let mut full = root_dir
+        .create_dir("full", 0, 0, InodeFilePerm::default_dir_perm(), time)
+        .unwrap();
+      full
+        .create_file("f", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      // "."、".."和"f"各占12字节，每个链接占60字节
+      let links = (block_size - 36 - tail_len) / 60;
+      for i in 0..links {
+        full.link("f", &format!("{:02}{}", i, "n".repeat(50)), time).unwrap();
+      }
+      assert_eq!(full.inode.get_size(), block_size);
+      let long_name = "r".repeat(200);
+      let mut same_dir = root_dir.open_dir("full").unwrap();
+      full.rename("f", &mut same_dir, &long_name, time).unwrap();
+      assert!(!full.is_exist("f"));
+      let file = full.open_file(&long_name).unwrap();
+      assert_eq!(file.inode.links_count as u64, links + 1);
+      check_dirblock_checksum(&full);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn create_hard_link() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let original = root_dir.open_file("test0").unwrap();
+      let links_count = original.inode.links_count;
+
+      let mut dir0 = root_dir.open_dir("dir0").unwrap();
+      dir0.link("../test0", "linked_test0", time).unwrap();
+      let linked = dir0.open_file("linked_test0").unwrap();
+      assert_eq!(linked.ino, original.ino);
+      assert_eq!(linked.inode.links_count, links_count + 1);
+      check_inode_checksum(linked.ino, &fs);
+      check_dirblock_checksum(&dir0);
+
+      assert!(matches!(
+        dir0.link("../test0", "linked_test0", time),
+        Err(Error::AlreadyExists)
+      ));
+      assert!(matches!(
+        root_dir.link("dir1", "linked_dir1", time),
+        Err(Error::InvalidInput)
+      ));
+
+      // 删除其中一个链接之后，数据仍然可以通过另一个链接访问
+      root_dir.remove("test0", time).unwrap();
+      let linked = dir0.open_file("linked_test0").unwrap();
+      assert_eq!(linked.inode.links_count, links_count);
+      let mut buf = vec![0u8; linked.inode.get_size() as usize];
+      assert_eq!(linked.read(0, &mut buf).unwrap(), buf.len());
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn grow_multi_block_dir() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let mut dir = root_dir
+        .create_dir("big_dir", 0, 0, InodeFilePerm::default_dir_perm(), time)
+        .unwrap();
+      dir
+        .create_file("target", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      // 用硬链接填满多个目录块，不占用额外的inode
+      let name = |i: usize| format!("{:0>60}", i);
+      for i in 0..200 {
+        dir.link("target", &name(i), time).unwrap();
+      }
+      let size = dir.inode.get_size();
+      assert!(size > 10 * block_size);
+      check_dirblock_checksum(&dir);
+      check_inode_checksum(dir.ino, &fs);
+      assert_eq!(dir.iter().count(), 203);
+      for i in 0..200 {
+        assert!(dir.is_exist(&name(i)));
+      }
+
+      // 删除一半之后重新插入，复用已有的空间，目录不再增长
+      for i in (0..200).step_by(2) {
+        dir.remove(&name(i), time).unwrap();
+      }
+      assert_eq!(dir.iter().count(), 103);
+      for i in (0..200).step_by(2) {
+        assert!(!dir.is_exist(&name(i)));
+        dir.link("target", &name(i), time).unwrap();
+      }
+      assert_eq!(dir.inode.get_size(), size);
+      assert_eq!(dir.iter().count(), 203);
+      assert_eq!(dir.open_file("target").unwrap().inode.links_count, 201);
+      check_dirblock_checksum(&dir);
+
+      let dir = root_dir.open_dir("big_dir").unwrap();
+      assert_eq!(dir.inode.get_size(), size);
+      assert_eq!(dir.iter().count(), 203);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn read_symlinks() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      assert_eq!(root_dir.read_link("fast").unwrap(), "dir0/target");
+      assert_eq!(root_dir.read_link("inline").unwrap(), "a".repeat(100));
+      assert_eq!(root_dir.read_link("slow").unwrap(), format!("/{}", "b".repeat(299)));
+      assert!(matches!(root_dir.read_link("lost+found"), Err(Error::InvalidInput)));
+    },
+    EXT4_SYMLINK_IMG,
+  )
+}
+
+// inline符号链接的目标保存在inode内的扩展属性里，修改inode后校验和要覆盖这部分内容
+#[test]
+fn modify_inode_with_inline_xattr() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      root_dir.link("inline", "inline_link", time).unwrap();
+      let ino = root_dir.find_entry("inline_link").unwrap().data.get_inode() as u64;
+      assert_eq!(fs.get_inode(ino).unwrap().links_count, 2);
+      check_inode_checksum(ino, &fs);
+      assert_eq!(root_dir.read_link("inline_link").unwrap(), "a".repeat(100));
+    },
+    EXT4_SYMLINK_IMG,
+  )
+}
+
+#[test]
+fn create_symlink() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let free_blocks_count = fs.super_block.borrow().get_free_blocks_count();
+
+      let fast_target = "dir0/../test0";
+      let fast = root_dir.create_symlink("fast_link", fast_target, 0, 0, time).unwrap();
+      let inode = fs.get_inode(fast).unwrap();
+      assert!(inode.is_symlink());
+      assert_eq!(inode.get_blocks_count(), 0);
+      assert_eq!(root_dir.read_link("fast_link").unwrap(), fast_target);
+
+      // 长的目标保存在数据块里
+      let slow_target = format!("/{}", "long/".repeat(40));
+      let mut dir0 = root_dir.open_dir("dir0").unwrap();
+      let slow = dir0.create_symlink("slow_link", &slow_target, 0, 0, time).unwrap();
+      assert!(fs.get_inode(slow).unwrap().get_blocks_count() > 0);
+      assert_eq!(root_dir.read_link("dir0/slow_link").unwrap(), slow_target);
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count - 1);
+      check_inode_checksum(slow, &fs);
+      check_dirblock_checksum(&dir0);
+
+      assert!(matches!(
+        root_dir.create_symlink("fast_link", "x", 0, 0, time),
+        Err(Error::AlreadyExists)
+      ));
+      // 名字太长或者为空时返回错误，不分配inode和数据块
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+      assert!(matches!(
+        root_dir.create_symlink(&"s".repeat(256), &slow_target, 0, 0, time),
+        Err(Error::InvalidFileNameLength)
+      ));
+      assert!(matches!(
+        root_dir.create_symlink("", fast_target, 0, 0, time),
+        Err(Error::InvalidFileNameLength)
+      ));
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+      root_dir.remove("dir0/slow_link", time).unwrap();
+      assert_eq!(fs.super_block.borrow().get_free_blocks_count(), free_blocks_count);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+// 结尾的/表示最后一级必须是目录，最后一级为空的路径不能用来创建文件
+#[test]
+fn paths_with_trailing_slash() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let perm = InodeFilePerm::default_file_perm();
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+
+      assert!(matches!(
+        root_dir.create_file("new_file/", 0, 0, perm, time),
+        Err(Error::InvalidInput)
+      ));
+      assert!(matches!(
+        root_dir.create_symlink("dir0/new_link/", "test0", 0, 0, time),
+        Err(Error::InvalidInput)
+      ));
+      assert!(matches!(
+        root_dir.mknod("dev/", InodeFileType::FIFO, 0, 0, 0, 0, perm, time),
+        Err(Error::InvalidInput)
+      ));
+      for path in ["", "/", "//"] {
+        assert!(matches!(
+          root_dir.create_dir(path, 0, 0, perm, time),
+          Err(Error::InvalidFileNameLength)
+        ));
+        assert!(matches!(root_dir.remove(path, time), Err(Error::InvalidFileNameLength)));
+      }
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+      check_dirblock_checksum(&root_dir);
+
+      // 和mkdir一样，创建目录时允许结尾的/
+      let new_dir = root_dir.create_dir("dir0/new_dir//", 0, 0, perm, time).unwrap();
+      assert_eq!(root_dir.open_dir("dir0/new_dir/").unwrap().ino, new_dir.ino);
+      assert!(matches!(root_dir.open_file("test0/"), Err(Error::InvalidInput)));
+      assert!(matches!(root_dir.lookup_nofollow("test0/"), Err(Error::InvalidInput)));
+
+      // 文件不能用结尾有/的路径删除或者移动
+      let mut dir1 = root_dir.open_dir("dir1").unwrap();
+      assert!(matches!(root_dir.remove("test0/", time), Err(Error::InvalidInput)));
+      assert!(matches!(
+        root_dir.rename("/test0/", &mut dir1, "moved", time),
+        Err(Error::InvalidInput)
+      ));
+      assert!(root_dir.is_exist("test0"));
+      root_dir.rename("dir0/new_dir/", &mut dir1, "moved_dir", time).unwrap();
+      root_dir.remove("/dir1/moved_dir/", time).unwrap();
+      assert!(!dir1.is_exist("moved_dir"));
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn resolve_path_with_symlinks() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let test0 = root_dir.open_file("test0").unwrap();
+      let dir0 = root_dir.open_dir("dir0").unwrap();
+      let dir1 = root_dir.open_dir("dir1").unwrap();
+
+      root_dir.create_symlink("abs_link", "/dir0", 0, 0, time).unwrap();
+      root_dir
+        .create_symlink("rel_link", "dir0/../test0", 0, 0, time)
+        .unwrap();
+      root_dir.create_symlink("dir0/up", "..", 0, 0, time).unwrap();
+      root_dir.create_symlink("loop_a", "loop_b", 0, 0, time).unwrap();
+      root_dir.create_symlink("loop_b", "/loop_a", 0, 0, time).unwrap();
+
+      assert_eq!(root_dir.open_file("rel_link").unwrap().ino, test0.ino);
+      assert_eq!(root_dir.open_dir("abs_link").unwrap().ino, dir0.ino);
+      assert_eq!(root_dir.open_dir("abs_link/up/dir1").unwrap().ino, dir1.ino);
+      assert_eq!(root_dir.open_dir("./dir0/.//..").unwrap().ino, root_dir.ino);
+      // 绝对路径总是从根目录开始，根目录的..还是根目录
+      assert_eq!(dir1.open_file("/../abs_link/../test0").unwrap().ino, test0.ino);
+
+      // 不解析最后一级的符号链接
+      let (ino, inode) = root_dir.lookup_nofollow("abs_link").unwrap();
+      assert!(inode.is_symlink());
+      assert_ne!(ino, dir0.ino);
+      let up = root_dir.lookup_nofollow("dir0/up").unwrap().0;
+      assert_eq!(root_dir.lookup_nofollow("abs_link/up").unwrap().0, up);
+      assert_eq!(root_dir.lookup("abs_link/up").unwrap().0, root_dir.ino);
+
+      assert!(matches!(root_dir.lookup("loop_a"), Err(Error::TooManySymlinks)));
+      assert!(root_dir.lookup_nofollow("loop_a").unwrap().1.is_symlink());
+      assert!(matches!(root_dir.open_file("test0/x"), Err(Error::InvalidInput)));
+      assert!(matches!(root_dir.open_file("dir0"), Err(Error::InvalidInput)));
+      assert!(matches!(root_dir.open_dir("missing"), Err(Error::NotFound)));
+
+      // 创建、改名和删除时父目录按同样的规则解析
+      assert!(matches!(
+        root_dir.create_file("test0/x", 0, 0, InodeFilePerm::default_file_perm(), time),
+        Err(Error::InvalidInput)
+      ));
+      assert!(matches!(
+        root_dir.find_entry("dir0").unwrap().to_file(),
+        Err(Error::InvalidInput)
+      ));
+      let mut other = root_dir.open_dir("dir1").unwrap();
+      let file = other
+        .create_file("/abs_link/via_link", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      assert_eq!(root_dir.open_file("dir0/via_link").unwrap().ino, file.ino);
+      let mut new_dir = root_dir.open_dir("dir1").unwrap();
+      other
+        .rename("/abs_link/via_link", &mut new_dir, "renamed", time)
+        .unwrap();
+      assert_eq!(root_dir.open_file("dir1/renamed").unwrap().ino, file.ino);
+      other.remove("/abs_link/up/dir1/renamed", time).unwrap();
+      assert!(matches!(root_dir.open_file("dir1/renamed"), Err(Error::NotFound)));
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn create_special_files() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let perm = InodeFilePerm::default_file_perm();
+
+      // 主次设备号都小于256时使用旧的编码
+      let null = root_dir
+        .mknod("null", InodeFileType::CHR, 1, 3, 0, 0, perm, time)
+        .unwrap();
+      let inode = fs.get_inode(null).unwrap();
+      assert_eq!(inode.block[0], 0x0103);
+      assert_eq!(inode.get_device(), Some((1, 3)));
+
+      // 否则使用新的编码
+      let nvme = root_dir
+        .mknod("nvme", InodeFileType::BLK, 259, 0x12345, 0, 0, perm, time)
+        .unwrap();
+      let inode = fs.get_inode(nvme).unwrap();
+      assert_eq!(inode.block[0], 0);
+      assert_eq!(inode.block[1], 0x45 | (259 << 8) | (0x12300 << 12));
+      assert_eq!(inode.get_device(), Some((259, 0x12345)));
+      check_inode_checksum(nvme, &fs);
+
+      let fifo = root_dir
+        .mknod("fifo", InodeFileType::FIFO, 0, 0, 0, 0, perm, time)
+        .unwrap();
+      assert_eq!(fs.get_inode(fifo).unwrap().get_file_type(), InodeFileType::FIFO);
+      assert_eq!(fs.get_inode(fifo).unwrap().get_device(), None);
+      root_dir
+        .mknod("sock", InodeFileType::SOCK, 0, 0, 0, 0, perm, time)
+        .unwrap();
+      let entry = root_dir.find_entry("sock").unwrap();
+      assert_eq!(entry.data.get_file_type(), Some(DirEntryFileType::SOCK));
+
+      assert!(matches!(
+        root_dir.mknod("reg", InodeFileType::REG, 0, 0, 0, 0, perm, time),
+        Err(Error::InvalidInput)
+      ));
+      assert!(matches!(
+        root_dir.mknod("big", InodeFileType::CHR, 0x1000, 0, 0, 0, perm, time),
+        Err(Error::InvalidInput)
+      ));
+      // 名字太长或者为空时不分配inode
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+      assert!(matches!(
+        root_dir.mknod(&"d".repeat(256), InodeFileType::CHR, 1, 3, 0, 0, perm, time),
+        Err(Error::InvalidFileNameLength)
+      ));
+      assert!(matches!(
+        root_dir.mknod("", InodeFileType::FIFO, 0, 0, 0, 0, perm, time),
+        Err(Error::InvalidFileNameLength)
+      ));
+      assert!(matches!(
+        root_dir.mknod("..", InodeFileType::FIFO, 0, 0, 0, 0, perm, time),
+        Err(Error::InvalidInput)
+      ));
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+      root_dir.remove("fifo", time).unwrap();
+      check_dirblock_checksum(&root_dir);
+    },
+    EXT4_1M_IMG,
+  )
+}
+
+#[test]
+fn dir_hash() {
+  // 期望值来自debugfs的dx_hash -h <version> -s 0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0 <name>
+  let seed = [0x3c2d1e0f, 0x78695a4b, 0xb4a59687, 0xf0e1d2c3];
+  let long_name = "file_with_a_rather_long_name_that_spans_more_than_32_bytes";
+  let expected = [
+    (0, "hello", 0x32252546, 0),
+    (0, long_name, 0x6d349a46, 0),
+    (0, "héllo", 0x239928cc, 0),
+    (1, "a", 0x3e64fde2, 0x42280a57),
+    (1, long_name, 0x687864be, 0x621777d4),
+    (1, "héllo", 0x0f9ee264, 0x5364979c),
+    (2, "hello", 0xf9a4dcd0, 0xd0d3e919),
+    (2, long_name, 0x57b10892, 0x5cbf0e28),
+    (2, "héllo", 0x0bd150b0, 0x6c5f0dd5),
+    (3, "héllo", 0x7798acd8, 0),
+    (4, "héllo", 0x55dd3254, 0x0ccdbb86),
+    (5, "héllo", 0x712ca0f4, 0xb8cf1147),
+  ];
+  for (version, name, hash, minor_hash) in expected {
+    assert_eq!(
+      dx_hash(name.as_bytes(), version, &seed),
+      Some((hash, minor_hash)),
+      "version {} name {}",
+      version,
+      name
+    );
+  }
+  assert_eq!(dx_hash(b"hello", 6, &seed), None);
+}
+
+#[test]
+fn lookup_in_htree_dir() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let time = get_current_time();
+      let mut dir = root_dir.open_dir("indexed").unwrap();
+      let target = dir.find_entry("target").unwrap().data.get_inode();
+      // 索引块不会被当成entry遍历出来
+      let names: Vec<String> = dir.iter().map(|e| e.unwrap().data.get_name_str()).collect();
+      assert_eq!(names.len(), 2003);
+      for name in &names {
+        let entry = dir.find_entry(name).unwrap();
+        assert_eq!(&entry.data.get_name_str(), name);
+      }
+      let name = |i: usize| format!("entry_{:04}_padding_to_make_the_name_longer_xxxxxxxx", i);
+      for i in 0..2000 {
+        assert_eq!(dir.find_entry(&name(i)).unwrap().data.get_inode(), target);
+      }
+      assert_eq!(dir.find_entry("..").unwrap().data.get_inode(), Inode::ROOT_INO as u32);
+      assert!(matches!(dir.find_entry("entry_2000"), Err(Error::NotFound)));
+
+      // 删除entry不影响索引
+      dir.remove(&name(1234), time).unwrap();
+      assert!(!dir.is_exist(&name(1234)));
+      assert!(dir.is_exist(&name(1235)));
+
+      // 插入entry之后索引仍然有效
+      let mut small = root_dir.open_dir("small").unwrap();
+      small
+        .create_file("new_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      assert!(small.inode.get_flags().contains(InodeFlags::INDEX_FL));
+      assert!(small.is_exist("new_file"));
+      assert!(small.is_exist("s042"));
+      assert_eq!(small.iter().count(), 104);
+      check_dirblock_checksum(&small);
+    },
+    EXT4_HTREE_IMG,
+  )
+}
+
+// 读出目录第一个块里的dx_root信息
+fn read_dx_root_info<IO: ReadWriteSeek>(dir: &Dir<IO>) -> DxRootInfo {
+  let extents = {
+    let mut disk = dir.fs.disk.borrow_mut();
+    dir
+      .inode
+      .get_extents(dir.ino, &mut *disk, &dir.fs.super_block.borrow())
+      .unwrap()
+  };
+  let mut block = vec![0u8; dir.fs.super_block.borrow().get_block_size() as usize];
+  dir.fs.read_block(extents[0].get_block_loc(), &mut block).unwrap();
+  DxRootInfo::load_from_u8(&block[DxRootInfo::OFFSET..])
+}
+
+#[test]
+fn maintain_htree_index() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      let name = |i: usize| format!("grow_{:05}_with_a_long_name_to_fill_the_leaf_blocks_quickly", i);
+
+      // 普通目录写满一个块之后转换成有索引的目录，root满了之后增加一层索引
+      let mut dir = root_dir
+        .create_dir("grow", 0, 0, InodeFilePerm::default_dir_perm(), time)
+        .unwrap();
+      dir
+        .create_file("target", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      assert!(!dir.inode.get_flags().contains(InodeFlags::INDEX_FL));
+      for i in 0..3000 {
+        dir.link("target", &name(i), time).unwrap();
+        if i == 20 {
+          assert!(dir.inode.get_flags().contains(InodeFlags::INDEX_FL));
+          assert_eq!(read_dx_root_info(&dir).indirect_levels, 0);
+        }
+      }
+      let info = read_dx_root_info(&dir);
+      assert_eq!(info.hash_version, 1);
+      assert_eq!(info.indirect_levels, 1);
+      check_dirblock_checksum(&dir);
+      assert_eq!(dir.iter().count(), 3003);
+      for i in 0..3000 {
+        assert!(dir.is_exist(&name(i)), "{}", name(i));
+      }
+
+      // 删除entry之后其它entry仍然可以通过索引找到
+      for i in (0..3000).step_by(3) {
+        dir.remove(&name(i), time).unwrap();
+      }
+      for i in 0..3000 {
+        assert_eq!(dir.is_exist(&name(i)), i % 3 != 0);
+      }
+      assert_eq!(dir.open_file("target").unwrap().inode.links_count, 2001);
+
+      // 往Linux建立的索引里插入
+      let mut indexed = root_dir.open_dir("indexed").unwrap();
+      for i in 0..500 {
+        indexed.link("target", &name(i), time).unwrap();
+      }
+      for i in 0..500 {
+        assert!(indexed.is_exist(&name(i)));
+      }
+      assert!(indexed.is_exist("entry_1999_padding_to_make_the_name_longer_xxxxxxxx"));
+      check_dirblock_checksum(&indexed);
+
+      // 移动有索引的目录时".."写在dx_root里
+      let mut small = root_dir.open_dir("small").unwrap();
+      root_dir.rename("grow", &mut small, "moved", time).unwrap();
+      let moved = small.open_dir("moved").unwrap();
+      assert_eq!(moved.find_entry("..").unwrap().data.get_inode(), small.ino as u32);
+      assert!(moved.is_exist(&name(1)));
+      check_dirblock_checksum(&moved);
+    },
+    EXT4_HTREE_IMG,
+  )
+}
+
+#[test]
+fn largedir_htree_index() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      // 255字节的文件名，一个叶子块只能放3个entry，索引很快就会增长到两层dx_node
+      let name = |i: usize| format!("{:06}{}", i, "l".repeat(249));
+      let mut dir = root_dir
+        .create_dir("large", 0, 0, InodeFilePerm::default_dir_perm(), time)
+        .unwrap();
+      dir
+        .create_file("target", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      let mut count = 0;
+      while count == 0 || count % 100 != 0 || read_dx_root_info(&dir).indirect_levels < 2 {
+        dir.link("target", &name(count), time).unwrap();
+        count += 1;
+      }
+      // 再插入一些，让第二层的dx_node也发生分裂
+      for i in count..count + 3000 {
+        dir.link("target", &name(i), time).unwrap();
+      }
+      count += 3000;
+      assert_eq!(read_dx_root_info(&dir).indirect_levels, 2);
+      check_dirblock_checksum(&dir);
+      assert_eq!(dir.iter().count(), count + 3);
+      for i in 0..count {
+        assert!(dir.is_exist(&name(i)), "{}", name(i));
+      }
+    },
+    EXT4_LARGEDIR_IMG,
+  )
+}
+
+#[test]
+fn read_block_map_file() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let check = |name: &str, size: usize, pattern: &dyn Fn(usize) -> u8| {
+        let file = root_dir.open_file(name).unwrap();
+        assert!(!file.inode.use_extents());
+        assert_eq!(file.inode.get_size(), size as u64);
+        let mut buf = vec![0u8; size];
+        assert_eq!(file.read(0, &mut buf).unwrap(), size);
+        for (i, b) in buf.iter().enumerate() {
+          assert_eq!(*b, pattern(i), "{} offset {}", name, i);
+        }
+      };
+      check("small", 5000, &|i| ((i * 7) % 251) as u8);
+      check("ind", 200 * 1024, &|i| ((i * 11 + i / 1024) % 251) as u8);
+      check("dind", 600 * 1024, &|i| ((i * 13 + i / 1024) % 251) as u8);
+
+      // 空洞读出来是0
+      let file = root_dir.open_file("sparse").unwrap();
+      let tind_offset = 70 << 20;
+      assert_eq!(file.inode.get_size(), tind_offset + 1500);
+      let mut buf = vec![0xFFu8; 200 * 1024];
+      assert_eq!(file.read(0, &mut buf).unwrap(), buf.len());
+      for (i, b) in buf.iter().enumerate() {
+        let expected = match i {
+          3072..=4095 => b'A',
+          102400..=103423 => b'B',
+          _ => 0,
+        };
+        assert_eq!(*b, expected, "offset {}", i);
+      }
+      let mut buf = vec![0xFFu8; 2048];
+      assert_eq!(file.read(tind_offset - 548, &mut buf).unwrap(), 2048);
+      assert!(buf[..548].iter().all(|&b| b == 0));
+      assert!(buf[548..].iter().all(|&b| b == b'C'));
+      assert_eq!(file.seek_data(5000).unwrap(), Some(102400));
+      assert_eq!(file.seek_hole(102400).unwrap(), Some(103424));
+      assert_eq!(file.seek_data(103424).unwrap(), Some(tind_offset));
+
+      let dir = root_dir.open_dir("dir").unwrap();
+      assert!(!dir.inode.use_extents());
+      let names: Vec<String> = dir.iter().map(|e| e.unwrap().data.get_name_str()).collect();
+      assert_eq!(names, [".", "..", "f1"]);
+      let f1 = dir.open_file("f1").unwrap();
+      let mut buf = vec![0u8; 5000];
+      assert_eq!(f1.read(0, &mut buf).unwrap(), 5000);
+      assert!(buf.iter().enumerate().all(|(i, &b)| b == ((i * 7) % 251) as u8));
+    },
+    EXT4_BLOCKMAP_IMG,
+  )
+}
+
+#[test]
+fn write_block_map_file() {
+  call_with_fs(
+    |fs| {
+      let mut root_dir = fs.root_dir();
+      let block_size = fs.super_block.borrow().get_block_size();
+      let time = get_current_time();
+      let blocks = |n: u64| n * block_size / Inode::INODE_BLOCK_SIZE as u64;
+      let free_blocks = fs.super_block.borrow().get_free_blocks_count();
+
+      // 从直接块一直写到二级间接块，间接块也计入块数
+      let mut file = root_dir.open_file("small").unwrap();
+      let pattern = |i: usize| ((i * 7) % 251) as u8;
+      let data: Vec<u8> = (0..400 * 1024).map(pattern).collect();
+      file.write(0, &data, time).unwrap();
+      assert!(!file.inode.use_extents());
+      assert_eq!(file.inode.get_blocks_count(), blocks(400 + 1 + 1 + 1));
+      // 写到三级间接块的范围
+      let tind_offset = (12 + 256 + 256 * 256) * block_size;
+      file.write(tind_offset, b"tail", time).unwrap();
+      assert_eq!(file.inode.get_blocks_count(), blocks(400 + 3 + 1 + 3));
+
+      let file = root_dir.open_file("small").unwrap();
+      let mut buf = vec![0u8; data.len()];
+      assert_eq!(file.read(0, &mut buf).unwrap(), data.len());
+      assert_eq!(buf, data);
+      let mut buf = [0u8; 4];
+      file.read(tind_offset, &mut buf).unwrap();
+      assert_eq!(&buf, b"tail");
+
+      // 缩小之后空的间接块被释放
+      let mut file = file;
+      file.set_len(100 * block_size, time).unwrap();
+      assert_eq!(file.inode.get_blocks_count(), blocks(100 + 1));
+      file.punch_hole(0, 12 * block_size, time).unwrap();
+      assert_eq!(file.inode.get_blocks_count(), blocks(88 + 1));
+      file.punch_hole(12 * block_size, 88 * block_size, time).unwrap();
+      assert_eq!(file.inode.get_blocks_count(), 0);
+      assert!(file.inode.block.iter().all(|&b| b == 0));
+      check_inode_checksum(file.ino, &fs);
+
+      // 块映射放不下的范围是非法参数，unwritten块是格式本身不支持
+      assert!(matches!(file.set_len(1 << 40, time), Err(Error::InvalidInput)));
+      assert!(matches!(
+        file.allocate(0, block_size, false, time),
+        Err(Error::Unsupported)
+      ));
+
+      // 块映射的目录增长到需要间接块
+      let mut dir = root_dir.open_dir("dir").unwrap();
+      let name = |i: usize| format!("{:0>200}", i);
+      for i in 0..100 {
+        dir.link("f1", &name(i), time).unwrap();
+      }
+      assert!(!dir.inode.use_extents());
+      assert!(dir.inode.block[12] != 0);
+      check_dirblock_checksum(&dir);
+      for i in 0..100 {
+        assert!(dir.is_exist(&name(i)));
+      }
+
+      // 删除文件释放所有的块
+      root_dir.remove("dind", time).unwrap();
+      root_dir.remove("sparse", time).unwrap();
+      root_dir.remove("small", time).unwrap();
+      assert!(fs.super_block.borrow().get_free_blocks_count() > free_blocks + 550);
+    },
+    EXT4_BLOCKMAP_IMG,
+  )
+}
+
+// 在ext2/ext3上读写，新建的inode使用块映射，不写任何校验和
+fn read_and_write_ext2_ext3(fs: FileSystem) {
+  let mut root_dir = fs.root_dir();
+  let time = get_current_time();
+  assert_eq!(fs.super_block.borrow().get_inode_size(), 128);
+
+  let pattern = |i: usize| ((i * 7) % 251) as u8;
+  let data = root_dir.open_file("data").unwrap();
+  assert!(!data.inode.use_extents());
+  let mut buf = vec![0u8; 100 * 1024];
+  assert_eq!(data.read(0, &mut buf).unwrap(), buf.len());
+  assert!(buf.iter().enumerate().all(|(i, &b)| b == pattern(i)));
+  assert_eq!(root_dir.read_link("link").unwrap(), "dir/small");
+  let mut small = root_dir.open_file("link").unwrap();
+  let mut buf = vec![0u8; small.inode.get_size() as usize];
+  small.read(0, &mut buf).unwrap();
+  assert_eq!(buf, b"hello ext2\n");
+  small.write(11, b"more", time).unwrap();
+
+  let mut dir = root_dir
+    .create_dir("new_dir", 0, 0, InodeFilePerm::default_dir_perm(), time)
+    .unwrap();
+  assert!(!dir.inode.use_extents());
+  assert_eq!(dir.inode.checksum_hi, 0);
+  let mut file = dir
+    .create_file("file", 0, 0, InodeFilePerm::default_file_perm(), time)
+    .unwrap();
+  let data: Vec<u8> = (0..50 * 1024).map(pattern).collect();
+  file.write(0, &data, time).unwrap();
+  assert!(!file.inode.use_extents());
+  assert_eq!(file.inode.osd2.checksum_lo, 0);
+  let long_target = "x".repeat(100);
+  dir.create_symlink("slow", &long_target, 0, 0, time).unwrap();
+  assert_eq!(dir.read_link("slow").unwrap(), long_target);
+  for i in 0..50 {
+    dir.link("file", &format!("link_{:0>40}", i), time).unwrap();
+  }
+
+  let file = root_dir.open_file("new_dir/file").unwrap();
+  let mut buf = vec![0u8; data.len()];
+  assert_eq!(file.read(0, &mut buf).unwrap(), data.len());
+  assert_eq!(buf, data);
+  assert_eq!(file.inode.links_count, 51);
+  root_dir.remove("data", time).unwrap();
+  root_dir.rename("dir", &mut dir, "moved", time).unwrap();
+  assert!(dir.open_dir("moved").unwrap().is_exist("small"));
+}
+
+#[test]
+fn mount_ext2() {
+  call_with_fs(read_and_write_ext2_ext3, EXT2_IMG)
+}
+
+#[test]
+fn mount_ext3() {
+  call_with_fs(read_and_write_ext2_ext3, EXT3_IMG)
+}
+
+#[test]
+fn reject_unsupported_features() {
+  // superblock里feature_incompat的偏移是0x60
+  for incompat in [0x4u32, 0x10000] {
+    let path = copy_img(EXT2_IMG);
+    let mut img = fs::read(&path).unwrap();
+    let offset = 1024 + 0x60;
+    let features = u32::from_le_bytes(img[offset..offset + 4].try_into().unwrap()) | incompat;
+    img[offset..offset + 4].copy_from_slice(&features.to_le_bytes());
+    fs::write(&path, &img).unwrap();
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    assert!(matches!(FileSystem::new(BufStream::new(file)), Err(Error::Unsupported)));
+    fs::remove_file(&path).unwrap();
+  }
+}
+
+#[test]
+fn reject_unsupported_desc_size() {
+  // superblock里desc_size的偏移是0xFE，超过64字节的descriptor不支持
+  let path = copy_img(EXT4_1M_IMG);
+  let mut img = fs::read(&path).unwrap();
+  let offset = 1024 + 0xFE;
+  img[offset..offset + 2].copy_from_slice(&128u16.to_le_bytes());
+  fs::write(&path, &img).unwrap();
+  let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+  assert!(matches!(FileSystem::new(BufStream::new(file)), Err(Error::Unsupported)));
+  fs::remove_file(&path).unwrap();
+}
+
+// 读写有多个block group的文件系统，inode_tables是每个block group里inode表的位置
+// 根目录下的big有2500K，dir下有small
+fn read_and_write_multigroup(fs: FileSystem, inode_tables: &[u64]) {
+  let mut root_dir = fs.root_dir();
+  let block_size = fs.super_block.borrow().get_block_size();
+  let time = get_current_time();
+  {
+    let descriptors = fs.block_group_descriptors.borrow();
+    assert_eq!(descriptors.len(), inode_tables.len());
+    for (bgd, &loc) in descriptors.iter().zip(inode_tables.iter()) {
+      assert_eq!(bgd.get_inode_table_loc(), loc);
+    }
+  }
+
+  let pattern = |i: usize| ((i * 7 + i / 1024) % 251) as u8;
+  let mut big = root_dir.open_file("big").unwrap();
+  let mut buf = vec![0u8; 2500 * 1024];
+  assert_eq!(big.read(0, &mut buf).unwrap(), buf.len());
+  assert!(buf.iter().enumerate().all(|(i, &b)| b == pattern(i)));
+
+  // 释放后面几个block group里的块，再在释放出来的空间里写入
+  let free_blocks = fs.super_block.borrow().get_free_blocks_count();
+  big.set_len(4 * block_size, time).unwrap();
+  assert!(fs.super_block.borrow().get_free_blocks_count() >= free_blocks + 2500 * 1024 / block_size - 4);
+  let mut dir = root_dir
+    .create_dir("new_dir", 0, 0, InodeFilePerm::default_dir_perm(), time)
+    .unwrap();
+  let mut file = dir
+    .create_file("file", 0, 0, InodeFilePerm::default_file_perm(), time)
+    .unwrap();
+  let data: Vec<u8> = (0..30 * 1024).map(pattern).collect();
+  file.write(0, &data, time).unwrap();
+  let file = root_dir.open_file("new_dir/file").unwrap();
+  let mut buf = vec![0u8; data.len()];
+  assert_eq!(file.read(0, &mut buf).unwrap(), data.len());
+  assert_eq!(buf, data);
+  assert!(root_dir.open_dir("dir").unwrap().is_exist("small"));
+}
+
+#[test]
+fn multigroup_ext2() {
+  call_with_fs(
+    |fs| {
+      assert_eq!(fs.super_block.borrow().get_desc_size(), 32);
+      read_and_write_multigroup(fs, &[132, 1156, 2051, 3204]);
+    },
+    EXT2_MULTIGROUP_IMG,
+  )
+}
+
+#[test]
+fn multigroup_ext4_32bit() {
+  call_with_fs(
+    |fs| {
+      assert_eq!(fs.super_block.borrow().get_desc_size(), 32);
+      read_and_write_multigroup(fs, &[11, 27, 43, 59]);
+    },
+    EXT4_32BIT_IMG,
+  )
+}
+
+#[test]
+fn multigroup_4k_block() {
+  call_with_fs(
+    |fs| {
+      assert_eq!(fs.super_block.borrow().get_block_size(), 4096);
+      read_and_write_multigroup(fs, &[73, 77, 81, 85]);
+    },
+    EXT4_4K_IMG,
+  )
+}
+
+#[test]
+fn multi_block_descriptor_table() {
+  call_with_fs(
+    |fs| {
+      // 16个block group组成一个flex group，inode表集中放在flex group的第一个block group里
+      let inode_tables: Vec<u64> = (0..32)
+        .map(|i| if i < 16 { 36 + 8 * i } else { 4129 + 8 * (i - 16) })
+        .collect();
+      read_and_write_multigroup(fs, &inode_tables);
+    },
+    EXT4_GDT_IMG,
+  )
+}
+
+#[test]
+fn meta_bg_descriptors() {
+  call_with_fs(
+    |fs| {
+      {
+        let super_block = fs.super_block.borrow();
+        assert_eq!(super_block.get_desc_pos(0), 2 * 1024);
+        assert_eq!(super_block.get_desc_pos(15), 2 * 1024 + 15 * 64);
+        // 第16个block group从4097开始，没有superblock的备份
+        assert!(!super_block.bg_has_super(16));
+        assert_eq!(super_block.get_desc_pos(16), 4097 * 1024);
+        assert_eq!(super_block.get_desc_pos(17), 4097 * 1024 + 64);
+      }
+      // 在第二个meta group里分配再释放，descriptor写到meta group开头
+      let read_free_blocks = |bgd_id: u32| {
+        let pos = fs.super_block.borrow().get_desc_pos(bgd_id);
+        let mut disk = fs.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        BlockGroupDescriptor::deserialize(&mut *disk, 64)
+          .unwrap()
+          .get_free_blocks_count()
+      };
+      let free_blocks = read_free_blocks(16);
+      let goal = fs.super_block.borrow().get_group_first_block(16);
+      let (start, len) = fs.alloc_blocks(goal, 10).unwrap();
+      assert_eq!(len, 10);
+      assert_eq!(read_free_blocks(16), free_blocks - 10);
+      fs.free_blocks(start, 10).unwrap();
+      assert_eq!(read_free_blocks(16), free_blocks);
+      let inode_tables: Vec<u64> = (0..32)
+        .map(|i| if i < 16 { 35 + 8 * i } else { 4130 + 8 * (i - 16) })
+        .collect();
+      read_and_write_multigroup(fs, &inode_tables);
+    },
+    EXT4_META_BG_IMG,
+  )
+}
+
+#[test]
+fn alloc_blocks_across_groups() {
+  call_with_fs(
+    |fs| {
+      let group_of = |block: u64| (block - 1) / 1024;
+      // 第0、1个block group已经满了，从goal往外找到第2个
+      let (start, len) = fs.alloc_blocks(1, 10).unwrap();
+      assert_eq!((group_of(start), len), (2, 10));
+      let (start2, len2) = fs.alloc_blocks(1025, 10).unwrap();
+      assert_eq!((group_of(start2), len2), (2, 10));
+      // 哪里都没有足够长的连续空间时只分配一部分
+      let (start3, len3) = fs.alloc_blocks(3073, 2000).unwrap();
+      assert_eq!(group_of(start3), 3);
+      assert!(len3 > 0 && len3 < 2000);
+      fs.free_blocks(start, len).unwrap();
+      fs.free_blocks(start2, len2).unwrap();
+      fs.free_blocks(start3, len3).unwrap();
+    },
+    EXT4_32BIT_IMG,
+  )
+}
+
+#[test]
+fn alloc_blocks_in_uninit_group() {
+  call_with_fs(
+    |fs| {
+      // 第20个block group没有任何元数据，第25个开头有superblock的备份，
+      // 它们的bitmap和inode table都在第16个block group里
+      for (bgd_id, meta_blocks) in [(20u32, 0u64), (25, 1)] {
+        let (goal, count) = {
+          let super_block = fs.super_block.borrow();
+          let bgd = fs.block_group_descriptors.borrow()[bgd_id as usize];
+          assert!(bgd.get_flags().contains(BGFlags::BLOCK_UNINIT));
+          (
+            super_block.get_group_first_block(bgd_id),
+            bgd.get_free_blocks_count() as u64,
+          )
+        };
+        assert_eq!(count, 256 - meta_blocks);
+        let (start, len) = fs.alloc_blocks(goal, count).unwrap();
+        assert_eq!((start, len), (goal + meta_blocks, count));
+        let bgd = fs.block_group_descriptors.borrow()[bgd_id as usize];
+        assert!(!bgd.get_flags().contains(BGFlags::BLOCK_UNINIT));
+        assert_eq!(bgd.get_free_blocks_count(), 0);
+        fs.free_blocks(start, len).unwrap();
+      }
+    },
+    EXT4_META_BG_IMG,
+  )
+}
+
+#[test]
+fn orlov_inode_placement() {
+  call_with_fs(
+    |fs| {
+      let inodes_per_group = fs.super_block.borrow().inodes_per_group as u64;
+      let group_of = |ino: u64| (ino - 1) / inodes_per_group;
+      let block_group_of = |block: u64| (block - 1) / 1024;
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      // 根目录下的目录分散到不同的block group
+      let groups: Vec<u64> = (0..4)
+        .map(|i| {
+          let dir = root_dir
+            .create_dir(&format!("top{}", i), 0, 0, InodeFilePerm::default_dir_perm(), time)
+            .unwrap();
+          group_of(dir.ino)
+        })
+        .collect();
+      assert_eq!(groups, vec![1, 2, 3, 4]);
+
+      // 文件和子目录跟父目录在同一个block group，数据块也在这个block group里
+      let mut top = root_dir.open_dir("top0").unwrap();
+      let mut file = top
+        .create_file("file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      file.write(0, &[0x5a; 4096], time).unwrap();
+      let sub = top
+        .create_dir("sub", 0, 0, InodeFilePerm::default_dir_perm(), time)
+        .unwrap();
+      assert_eq!(group_of(file.ino), 1);
+      assert_eq!(group_of(sub.ino), 1);
+      let extents = {
+        let mut disk = fs.disk.borrow_mut();
+        file
+          .inode
+          .get_extents(file.ino, &mut *disk, &fs.super_block.borrow())
+          .unwrap()
+      };
+      assert!(extents.iter().all(|e| block_group_of(e.get_block_loc()) == 1));
+      // 根目录下的文件还在第0个block group
+      let root_file = root_dir
+        .create_file("root_file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      assert_eq!(group_of(root_file.ino), 0);
+    },
+    EXT4_ORLOV_IMG,
+  )
+}
+
+#[test]
+fn orlov_flex_bg_placement() {
+  call_with_fs(
+    |fs| {
+      let inodes_per_group = fs.super_block.borrow().inodes_per_group as u64;
+      let flex_of = |ino: u64| (ino - 1) / inodes_per_group / 16;
+      let mut root_dir = fs.root_dir();
+      let time = get_current_time();
+      // 开启flex_bg时按flex group分散，文件放在父目录所在的flex group里
+      let mut dir = root_dir
+        .create_dir("top", 0, 0, InodeFilePerm::default_dir_perm(), time)
+        .unwrap();
+      assert_eq!(flex_of(dir.ino), 1);
+      let file = dir
+        .create_file("file", 0, 0, InodeFilePerm::default_file_perm(), time)
+        .unwrap();
+      assert_eq!(flex_of(file.ino), 1);
+    },
+    EXT4_META_BG_IMG,
+  )
+}
+
+#[test]
+fn reject_inline_data_files() {
+  call_with_fs(
+    |fs| {
+      let root_dir = fs.root_dir();
+      let time = get_current_time();
+      // 还不支持inline data的文件和目录，读写都返回Unsupported，也不会改动inline data
+      let mut file = root_dir.open_file("inline").unwrap();
+      let mut buf = [0u8; 17];
+      assert!(matches!(file.read(0, &mut buf), Err(Error::Unsupported)));
+      assert!(matches!(file.write(0, b"x", time), Err(Error::Unsupported)));
+      assert!(matches!(file.set_len(0, time), Err(Error::Unsupported)));
+      assert!(matches!(file.allocate(0, 4096, false, time), Err(Error::Unsupported)));
+      assert_eq!(root_dir.open_file("inline").unwrap().inode.get_size(), 17);
+
+      let mut dir = root_dir.open_dir("inline_dir").unwrap();
+      assert!(matches!(dir.iter().next(), Some(Err(Error::Unsupported))));
+      let free_inodes_count = fs.super_block.borrow().get_free_inodes_count();
+      assert!(matches!(
+        dir.create_file("new", 0, 0, InodeFilePerm::default_file_perm(), time),
+        Err(Error::Unsupported)
+      ));
+      assert_eq!(fs.super_block.borrow().get_free_inodes_count(), free_inodes_count);
+    },
+    EXT4_INLINE_IMG,
+  )
+}